@@ -0,0 +1,188 @@
+use flate2::read::GzDecoder;
+use sha2::{Digest, Sha512};
+
+use std::fs::{self, File};
+use std::io::{self, BufReader, Read};
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+
+use crate::daemon::KeepAlive;
+use crate::datastore::{DataManager, FileInfo};
+use crate::settings::Settings;
+
+/// between the path of an archive and the path of a file inside it: "/backup/2019.zip!/DCIM/a.jpg"
+pub const SEPARATOR: &str = "!/";
+
+/// What archive members can do besides showing up in duplicate groups. They are never deleted.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ArchivePolicy {
+    /// only report them as copies
+    #[default]
+    Report,
+    /// files on disk that are also in an archive are deleted, all copies of them if need be
+    DeleteFiles,
+    /// an archive whose every file is also on disk is deleted after the scan
+    DeleteArchives,
+}
+
+enum Format {
+    Zip,
+    Tar,
+    TarGz,
+}
+
+fn format(path: &Path) -> Option<Format> {
+    let name = path.file_name()?.to_string_lossy().to_lowercase();
+    if name.ends_with(".zip") {
+        Some(Format::Zip)
+    } else if name.ends_with(".tar") {
+        Some(Format::Tar)
+    } else if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+        Some(Format::TarGz)
+    } else {
+        None
+    }
+}
+
+pub fn is_archive(path: &Path) -> bool {
+    format(path).is_some()
+}
+
+/// Whether `path` is a file inside an archive.
+pub fn is_member(path: &str) -> bool {
+    path.contains(SEPARATOR)
+}
+
+/// The archive file a member is in, `path` itself for other files.
+pub fn container(path: &str) -> &str {
+    path.split_once(SEPARATOR).map_or(path, |(archive, _)| archive)
+}
+
+/// Whether the file of `entry` exists. A file inside an archive only if the archive is still the
+/// one it was read from: its mtime is the one of the member's row, its size and mtime the ones of
+/// its own row. An archive replaced since then may not hold the file anymore.
+pub fn exists(entry: &FileInfo, data_manager: &impl DataManager) -> bool {
+    if !is_member(&entry.full_path) {
+        return Path::new(&entry.full_path).exists();
+    }
+    let archive = container(&entry.full_path);
+    let Ok(meta) = fs::metadata(archive) else {
+        return false;
+    };
+    let modified = meta.modified().ok()
+        .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    modified == entry.last_modified
+        && matches!(data_manager.get_entry_for_path(archive), Ok(Some(row)) if row.size == meta.len() && row.last_modified == modified)
+}
+
+/// Paths of members of the archive at `path` sort between these two.
+pub fn member_range(path: &str) -> (String, String) {
+    // '0' comes right after '/'
+    (format!("{}{}", path, SEPARATOR), format!("{}!0", path))
+}
+
+/// A regular file inside an archive.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Member {
+    /// path inside the archive, without a leading '/'
+    pub name: String,
+    pub size: u64,
+    /// sha512 (hex) of its content, like the hash of a file on disk
+    pub hash: String,
+}
+
+/// Reads every regular file in the zip, tar or tar.gz at `path` and hashes it.
+/// Archives inside archives are not opened.
+pub fn read_members(path: &Path) -> Result<Vec<Member>, String> {
+    let file = File::open(path).map_err(|e| e.to_string())?;
+    match format(path) {
+        Some(Format::Zip) => read_zip(file),
+        Some(Format::Tar) => read_tar(BufReader::new(file)),
+        Some(Format::TarGz) => read_tar(GzDecoder::new(BufReader::new(file))),
+        None => Err(String::from("not an archive")),
+    }
+}
+
+fn hash(reader: &mut impl Read) -> io::Result<String> {
+    let mut hasher = Sha512::new();
+    io::copy(&mut KeepAlive(reader), &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+fn read_zip(file: File) -> Result<Vec<Member>, String> {
+    let mut zip = zip::ZipArchive::new(BufReader::new(file)).map_err(|e| e.to_string())?;
+    let mut members = vec![];
+    for i in 0..zip.len() {
+        let mut entry = zip.by_index(i).map_err(|e| e.to_string())?;
+        if !entry.is_file() {
+            continue;
+        }
+        let name = entry.name().trim_start_matches('/').to_string();
+        let hash = hash(&mut entry).map_err(|e| format!("{}: {}", name, e))?;
+        members.push(Member { name, size: entry.size(), hash });
+    }
+    Ok(members)
+}
+
+fn read_tar(reader: impl Read) -> Result<Vec<Member>, String> {
+    let mut tar = tar::Archive::new(reader);
+    let mut members = vec![];
+    for entry in tar.entries().map_err(|e| e.to_string())? {
+        let mut entry = entry.map_err(|e| e.to_string())?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        let name = entry.path().map_err(|e| e.to_string())?.to_string_lossy().trim_start_matches("./").trim_start_matches('/').to_string();
+        let size = entry.size();
+        let hash = hash(&mut entry).map_err(|e| format!("{}: {}", name, e))?;
+        members.push(Member { name, size, hash });
+    }
+    Ok(members)
+}
+
+/// Stores the members of `info` as rows with paths like "archive.zip!/dir/file.jpg" if it is an
+/// archive that `changed` since it was read (or was never read). Members the filters reject
+/// (size, extension, age of the archive) are left out, so the rows don't tell whether an archive
+/// is all on disk; `read_members` does.
+pub fn update(info: &FileInfo, changed: bool, settings: &Settings, data_manager: &impl DataManager) {
+    let path = Path::new(&info.full_path);
+    if !is_archive(path) {
+        return;
+    }
+    let existing = match data_manager.get_entries_in_archive(&info.full_path) {
+        Ok(existing) => existing,
+        Err(e) => {
+            log::error!("Unable to read files of archive {}: {:?}", info.full_path, e);
+            return;
+        }
+    };
+    if !changed && !existing.is_empty() {
+        return;
+    }
+    let members = match read_members(path) {
+        Ok(members) => members,
+        Err(e) => {
+            log::warn!(path = info.full_path.as_str(); "Unable to read archive: {}", e);
+            return;
+        }
+    };
+    for entry in existing {
+        data_manager.delete_entry_for_path(&entry.full_path).unwrap_or_default();
+    }
+    for member in members {
+        if !settings.file_filter().accepts_values(Path::new(&member.name), member.size, info.last_modified) {
+            continue;
+        }
+        let entry = FileInfo {
+            full_path: format!("{}{}{}", info.full_path, SEPARATOR, member.name),
+            size: member.size,
+            hash: member.hash,
+            last_modified: info.last_modified,
+            content_hash: None,
+        };
+        data_manager.add_entry(&entry).unwrap_or_else(|e| log::error!("Unable to add {}: {:?}", entry.full_path, e));
+    }
+}
@@ -0,0 +1,261 @@
+use rustfft::num_complex::Complex;
+use rustfft::FftPlanner;
+use symphonia::core::audio::SampleBuffer;
+use symphonia::core::codecs::{DecoderOptions, CODEC_TYPE_NULL};
+use symphonia::core::errors::Error;
+use symphonia::core::formats::FormatOptions;
+use symphonia::core::io::MediaSourceStream;
+use symphonia::core::meta::MetadataOptions;
+use symphonia::core::probe::Hint;
+
+use std::collections::HashSet;
+use std::f32::consts::PI;
+use std::fs::File;
+use std::path::Path;
+
+use crate::content_hash;
+use crate::daemon;
+use crate::datastore::{AudioFingerprint, DataManager, FileInfo};
+use crate::image_hash::{group_pairs, retain_existing, sort_groups, SimilarFile, SimilarGroup};
+use crate::settings::Settings;
+
+/// Audio files that get a content hash when audio_content_hash is on.
+pub const EXTENSIONS: [&str; 6] = ["mp3", "flac", "ogg", "oga", "opus", "wav"];
+/// Audio files that can be decoded for a fingerprint (there is no Opus decoder).
+const FINGERPRINT_EXTENSIONS: [&str; 5] = ["mp3", "flac", "ogg", "oga", "wav"];
+pub const DEFAULT_MAX_BIT_ERROR: f64 = 0.25;
+/// only the beginning of a recording is fingerprinted
+const FINGERPRINT_SECS: u64 = 120;
+/// recordings whose lengths differ by more than this are not compared
+const DURATION_TOLERANCE_MS: u64 = 3000;
+/// samples are averaged down to about this rate, nothing above 2 kHz is used
+const TARGET_RATE: u32 = 5512;
+/// fingerprint frames are 0.37 s long and start every 0.046 s
+const FRAME_SECS: f32 = 0.371;
+const HOPS_PER_FRAME: usize = 8;
+/// 33 bands spaced logarithmically between these give 32 bits per frame
+const LOWEST_HZ: f32 = 300.0;
+const HIGHEST_HZ: f32 = 2000.0;
+/// how many frames one recording may be shifted against the other, to skip different silence at the start
+const MAX_SHIFT: usize = 48;
+
+pub fn is_audio(path: &Path) -> bool {
+    path.extension().is_some_and(|e| EXTENSIONS.contains(&e.to_string_lossy().to_lowercase().as_str()))
+}
+
+fn can_fingerprint(path: &Path) -> bool {
+    path.extension().is_some_and(|e| FINGERPRINT_EXTENSIONS.contains(&e.to_string_lossy().to_lowercase().as_str()))
+}
+
+/// Decodes `path` to mono and returns its length in milliseconds, with its first FINGERPRINT_SECS
+/// averaged down to about TARGET_RATE, and the rate they have then.
+fn decode(path: &Path) -> Result<(u64, Vec<f32>, u32), String> {
+    let file = File::open(path).map_err(|e| e.to_string())?;
+    let stream = MediaSourceStream::new(Box::new(file), Default::default());
+    let mut hint = Hint::new();
+    if let Some(extension) = path.extension().and_then(|e| e.to_str()) {
+        hint.with_extension(extension);
+    }
+    let mut format = symphonia::default::get_probe()
+        .format(&hint, stream, &FormatOptions::default(), &MetadataOptions::default())
+        .map_err(|e| e.to_string())?
+        .format;
+    let track = format.tracks().iter().find(|t| t.codec_params.codec != CODEC_TYPE_NULL).ok_or("no audio track")?;
+    let track_id = track.id;
+    let rate = track.codec_params.sample_rate.ok_or("unknown sample rate")?;
+    let frames = track.codec_params.n_frames;
+    let mut decoder = symphonia::default::get_codecs().make(&track.codec_params, &DecoderOptions::default())
+        .map_err(|e| e.to_string())?;
+    let step = (rate as f32 / TARGET_RATE as f32).round().max(1.0) as usize;
+    let wanted = rate as usize * FINGERPRINT_SECS as usize;
+    let mut mono = vec![];
+    let mut decoded_frames = 0u64;
+    loop {
+        let packet = match format.next_packet() {
+            Ok(p) => p,
+            Err(Error::IoError(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e.to_string()),
+        };
+        if packet.track_id() != track_id {
+            continue;
+        }
+        let decoded = match decoder.decode(&packet) {
+            Ok(d) => d,
+            // a damaged packet, the rest may be fine
+            Err(Error::DecodeError(_)) => continue,
+            Err(e) => return Err(e.to_string()),
+        };
+        let spec = *decoded.spec();
+        let channels = spec.channels.count();
+        decoded_frames += decoded.frames() as u64;
+        if mono.len() < wanted {
+            let mut samples = SampleBuffer::<f32>::new(decoded.capacity() as u64, spec);
+            samples.copy_interleaved_ref(decoded);
+            mono.extend(samples.samples().chunks(channels).map(|c| c.iter().sum::<f32>() / channels as f32));
+        } else if frames.is_some() {
+            // the length is known without decoding the rest
+            break;
+        }
+    }
+    let duration_ms = frames.unwrap_or(decoded_frames) * 1000 / rate as u64;
+    mono.truncate(wanted);
+    let reduced = mono.chunks(step).map(|c| c.iter().sum::<f32>() / c.len() as f32).collect();
+    Ok((duration_ms, reduced, rate / step as u32))
+}
+
+/// 32 bits per frame, one for each pair of neighbouring frequency bands: whether the energy
+/// difference between them grew since the previous frame. Stays about the same when the
+/// recording is encoded differently or its volume changes.
+pub fn fingerprint(samples: &[f32], rate: u32) -> Vec<u32> {
+    let frame_length = (rate as f32 * FRAME_SECS) as usize;
+    let hop = frame_length / HOPS_PER_FRAME;
+    if frame_length == 0 || samples.len() < frame_length {
+        return vec![];
+    }
+    let fft = FftPlanner::new().plan_fft_forward(frame_length);
+    let window: Vec<f32> = (0..frame_length).map(|i| 0.5 - 0.5 * (2.0 * PI * i as f32 / frame_length as f32).cos()).collect();
+    let bins: Vec<usize> = (0..=33)
+        .map(|b| LOWEST_HZ * (HIGHEST_HZ / LOWEST_HZ).powf(b as f32 / 33.0))
+        .map(|hz| (hz * frame_length as f32 / rate as f32) as usize)
+        .collect();
+    let mut previous: Option<Vec<f32>> = None;
+    let mut bits = vec![];
+    for start in (0..=samples.len() - frame_length).step_by(hop.max(1)) {
+        let mut buffer: Vec<Complex<f32>> = samples[start..start + frame_length].iter().zip(&window)
+            .map(|(s, w)| Complex::new(s * w, 0.0))
+            .collect();
+        fft.process(&mut buffer);
+        let energies: Vec<f32> = bins.windows(2)
+            .map(|b| buffer[b[0]..b[1].max(b[0] + 1)].iter().map(|c| c.norm_sqr()).sum())
+            .collect();
+        if let Some(previous) = &previous {
+            bits.push((0..32).fold(0u32, |frame, m| {
+                let bit = (energies[m] - energies[m + 1]) - (previous[m] - previous[m + 1]) > 0.0;
+                (frame << 1) | bit as u32
+            }));
+        }
+        previous = Some(energies);
+    }
+    bits
+}
+
+/// Length in milliseconds and fingerprint of the audio file at `path`.
+pub fn fingerprint_file(path: &Path) -> Result<(u64, Vec<u32>), String> {
+    let (duration_ms, samples, rate) = decode(path)?;
+    Ok((duration_ms, fingerprint(&samples, rate)))
+}
+
+/// Share of bits that differ between two fingerprints at the best of a few shifts against each
+/// other. At least half of the shorter one has to overlap, 1.0 if they can't be compared.
+pub fn bit_error_rate(a: &[u32], b: &[u32]) -> f64 {
+    let min_overlap = (a.len().min(b.len()) / 2).max(1);
+    let mut best = 1.0;
+    for shift in -(MAX_SHIFT as isize)..=MAX_SHIFT as isize {
+        let (a, b) = if shift < 0 { (&a[((-shift) as usize).min(a.len())..], b) } else { (a, &b[(shift as usize).min(b.len())..]) };
+        let overlap = a.len().min(b.len());
+        if overlap < min_overlap {
+            continue;
+        }
+        let errors: u32 = a.iter().zip(b).map(|(x, y)| (x ^ y).count_ones()).sum();
+        best = f64::min(best, errors as f64 / (overlap * 32) as f64);
+    }
+    best
+}
+
+/// Stores the fingerprint of `info` if it is an audio file whose fingerprint is missing or
+/// older than the file.
+pub fn update(info: &FileInfo, data_manager: &impl DataManager) {
+    let path = Path::new(&info.full_path);
+    if !can_fingerprint(path) {
+        return;
+    }
+    match data_manager.get_audio_fingerprint(&info.full_path) {
+        Ok(Some(existing)) if existing.last_modified >= info.last_modified => return,
+        Ok(_) => (),
+        Err(e) => {
+            log::error!("Unable to read audio fingerprint of {}: {:?}", info.full_path, e);
+            return;
+        }
+    }
+    let (duration_ms, fingerprint) = match fingerprint_file(path) {
+        Ok(f) => f,
+        Err(e) => {
+            log::warn!(path = info.full_path.as_str(); "Unable to decode audio: {}", e);
+            return;
+        }
+    };
+    let entry = AudioFingerprint { path: info.full_path.clone(), duration_ms, fingerprint, last_modified: info.last_modified };
+    data_manager.add_audio_fingerprint(&entry).unwrap_or_else(|e| log::error!("Unable to add audio fingerprint of {}: {:?}", info.full_path, e));
+}
+
+/// Other files with the recording `info` is: about as long and with fingerprints that differ
+/// in at most audio_max_bit_error of their bits. Sorted by path, `info` itself included if
+/// there are any. Rows of files that are gone are removed on the way. Only used as duplicates
+/// with audio_fingerprint_delete, see `similar_groups` otherwise.
+pub fn matching(info: &FileInfo, settings: &Settings, data_manager: &impl DataManager) -> Vec<FileInfo> {
+    let max_bit_error = settings.audio_max_bit_error.unwrap_or(DEFAULT_MAX_BIT_ERROR);
+    let Ok(Some(own)) = data_manager.get_audio_fingerprint(&info.full_path) else {
+        return vec![];
+    };
+    // silence would match any other silence
+    if own.fingerprint.iter().all(|f| *f == 0) {
+        return vec![];
+    }
+    let mut candidates = data_manager.get_audio_fingerprints_by_duration(own.duration_ms.saturating_sub(DURATION_TOLERANCE_MS), own.duration_ms + DURATION_TOLERANCE_MS)
+        .unwrap_or_else(|e| {
+            log::error!("Unable to read audio fingerprints: {:?}", e);
+            vec![]
+        });
+    retain_existing(&mut candidates, |c| &c.path, data_manager);
+    let mut matches: Vec<FileInfo> = candidates.into_iter()
+        .filter(|c| c.path == own.path || bit_error_rate(&own.fingerprint, &c.fingerprint) <= max_bit_error)
+        .filter_map(|c| data_manager.get_entry_for_path(&c.path).ok().flatten())
+        .collect();
+    if matches.len() <= 1 {
+        return vec![];
+    }
+    matches.sort_by(|a, b| a.full_path.cmp(&b.full_path));
+    matches
+}
+
+/// Groups of the same recording among the fingerprints in the database, biggest groups first,
+/// like `matching` finds them. The distance is the share of differing bits in percent. Groups of
+/// files with the same content (or the same audio, see audio_content_hash) are left out, those are plain duplicates. Rows of files that are
+/// gone are removed on the way.
+pub fn similar_groups(settings: &Settings, data_manager: &impl DataManager) -> Vec<SimilarGroup> {
+    let max_bit_error = settings.audio_max_bit_error.unwrap_or(DEFAULT_MAX_BIT_ERROR);
+    let mut recordings = data_manager.get_audio_fingerprints_by_duration(0, i64::MAX as u64).expect("Unable to read audio fingerprints");
+    retain_existing(&mut recordings, |r| &r.path, data_manager);
+    // silence would match any other silence
+    recordings.retain(|r| r.fingerprint.iter().any(|f| *f != 0));
+    recordings.sort_by(|a, b| a.duration_ms.cmp(&b.duration_ms).then_with(|| a.path.cmp(&b.path)));
+    let mut pairs = vec![];
+    for (i, a) in recordings.iter().enumerate() {
+        // comparing the fingerprints of a big library takes a while
+        daemon::keepalive();
+        for (j, b) in recordings.iter().enumerate().skip(i + 1).take_while(|(_, b)| b.duration_ms - a.duration_ms <= DURATION_TOLERANCE_MS) {
+            if bit_error_rate(&a.fingerprint, &b.fingerprint) <= max_bit_error {
+                pairs.push((i, j));
+            }
+        }
+    }
+    let mut groups: Vec<SimilarGroup> = group_pairs(recordings.len(), pairs).into_iter().filter_map(|mut members| {
+        let contents: HashSet<String> = members.iter()
+            .filter_map(|i| data_manager.get_entry_for_path(&recordings[*i].path).ok().flatten())
+            .map(|e| content_hash::of(&e, settings).unwrap_or(&e.hash).to_string())
+            .collect();
+        if contents.len() <= 1 {
+            return None;
+        }
+        members.sort_by(|a, b| recordings[*a].path.cmp(&recordings[*b].path));
+        let first = &recordings[members[0]].fingerprint;
+        Some(SimilarGroup {
+            files: members.iter().map(|i| SimilarFile {
+                path: recordings[*i].path.clone(),
+                distance: (bit_error_rate(first, &recordings[*i].fingerprint) * 100.0).round() as u32,
+            }).collect(),
+        })
+    }).collect();
+    sort_groups(&mut groups);
+    groups
+}
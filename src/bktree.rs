@@ -0,0 +1,70 @@
+use std::collections::HashMap;
+
+/// Number of bits that differ between two hashes.
+pub fn hamming_distance(a: u64, b: u64) -> u32 {
+    (a ^ b).count_ones()
+}
+
+/// Index of 64-bit hashes (a BK-tree) that finds all hashes within a Hamming distance of
+/// another one without comparing it to every hash in the tree.
+#[derive(Debug, Default)]
+pub struct BkTree {
+    nodes: Vec<Node>,
+}
+
+#[derive(Debug)]
+struct Node {
+    hash: u64,
+    /// everything inserted with exactly this hash
+    ids: Vec<usize>,
+    /// child nodes by their distance to this one
+    children: HashMap<u32, usize>,
+}
+
+impl BkTree {
+    pub fn new() -> Self {
+        BkTree::default()
+    }
+
+    pub fn insert(&mut self, hash: u64, id: usize) {
+        if self.nodes.is_empty() {
+            self.nodes.push(Node { hash, ids: vec![id], children: HashMap::new() });
+            return;
+        }
+        let mut current = 0;
+        loop {
+            let distance = hamming_distance(self.nodes[current].hash, hash);
+            if distance == 0 {
+                self.nodes[current].ids.push(id);
+                return;
+            }
+            match self.nodes[current].children.get(&distance) {
+                Some(&child) => current = child,
+                None => {
+                    let child = self.nodes.len();
+                    self.nodes.push(Node { hash, ids: vec![id], children: HashMap::new() });
+                    self.nodes[current].children.insert(distance, child);
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Ids of all hashes at most `max_distance` bits away from `hash`, with their distance.
+    pub fn find(&self, hash: u64, max_distance: u32) -> Vec<(usize, u32)> {
+        let mut found = vec![];
+        let mut pending = if self.nodes.is_empty() { vec![] } else { vec![0] };
+        while let Some(n) = pending.pop() {
+            let node = &self.nodes[n];
+            let distance = hamming_distance(node.hash, hash);
+            if distance <= max_distance {
+                found.extend(node.ids.iter().map(|id| (*id, distance)));
+            }
+            // triangle inequality: nothing below a child further away than this can match
+            pending.extend(node.children.iter()
+                .filter(|(d, _)| d.abs_diff(distance) <= max_distance)
+                .map(|(_, child)| *child));
+        }
+        found
+    }
+}
@@ -0,0 +1,85 @@
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::file_manager::{HandleFiles, WalkOptions};
+
+/// What happens with zero-byte files and empty directories that were there before a scan.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EmptyPolicy {
+    /// only list them
+    Report,
+    /// delete them with action D, list them with T or S
+    Delete,
+}
+
+/// Whether `directory` holds nothing but directories that are empty the same way, none of them
+/// `kept` (ignored or protected).
+pub fn is_empty_dir(directory: &Path, kept: &impl Fn(&Path, bool) -> bool) -> bool {
+    fs::symlink_metadata(directory).is_ok_and(|m| m.is_dir())
+        && fs::read_dir(directory).is_ok_and(|entries| entries.into_iter().all(|e| e.is_ok_and(|e| {
+            let path = e.path();
+            is_empty_dir(&path, kept) && !kept(&path, true)
+        })))
+}
+
+/// Removes the directories of `deleted_from` that are empty now, then their parents that are
+/// empty after that, at most `levels` directories up from each one (all of them by default).
+/// `root` itself, directories outside of it and `kept` ones stay. Returns the removed directories.
+pub fn remove_emptied(deleted_from: &BTreeSet<PathBuf>, root: &Path, levels: Option<usize>, kept: impl Fn(&Path, bool) -> bool, file_manager: &impl HandleFiles) -> Vec<String> {
+    let mut removed = vec![];
+    // children sort after their parents
+    for directory in deleted_from.iter().rev() {
+        let mut directory = directory.as_path();
+        let mut level = 0;
+        while directory != root && directory.starts_with(root) && levels.is_none_or(|l| level < l)
+            && !kept(directory, true) && is_empty_dir(directory, &kept) {
+            let path = directory.to_string_lossy().to_string();
+            if let Err(e) = file_manager.remove_empty_dir(&path) {
+                log::error!(path = path.as_str(); "Unable to remove empty directory: {}", e);
+                break;
+            }
+            removed.push(path);
+            let Some(parent) = directory.parent() else {
+                break;
+            };
+            directory = parent;
+            level += 1;
+        }
+    }
+    removed
+}
+
+/// Zero-byte files and empty directories (see `is_empty_dir`) below `root`, walked with
+/// `options`. Of nested empty directories only the topmost one is listed, nothing `kept` or in
+/// a kept directory is.
+pub fn find_empty(root: &Path, options: &WalkOptions, kept: impl Fn(&Path, bool) -> bool, file_manager: &impl HandleFiles) -> (Vec<String>, Vec<String>) {
+    let mut files = vec![];
+    let mut directories = vec![];
+    let mut walker = file_manager.walkdir(&root.to_string_lossy(), options);
+    while let Some(entry) = walker.next() {
+        let Ok(entry) = entry else {
+            continue;
+        };
+        if entry.depth() == 0 {
+            continue;
+        }
+        let is_dir = entry.file_type().is_dir();
+        if kept(entry.path(), is_dir) {
+            if is_dir {
+                walker.skip_current_dir();
+            }
+            continue;
+        }
+        if is_dir {
+            if is_empty_dir(entry.path(), &kept) {
+                directories.push(entry.path().to_string_lossy().to_string());
+                walker.skip_current_dir();
+            }
+        } else if entry.file_type().is_file() && entry.metadata().is_ok_and(|m| m.len() == 0) {
+            files.push(entry.path().to_string_lossy().to_string());
+        }
+    }
+    (files, directories)
+}
@@ -0,0 +1,151 @@
+use clap::{Args, Parser, Subcommand};
+
+use crate::filters::FileFilter;
+use crate::report::ReportFormat;
+use crate::settings::Settings;
+
+/// Delete duplicate files, check hashes
+#[derive(Parser, Debug)]
+#[command(name = "duplicates", version, args_conflicts_with_subcommands = true)]
+pub struct Cli {
+    /// config file to use
+    #[arg(short, long, global = true, default_value = "config.toml")]
+    pub config: String,
+
+    /// overrides log_level from the config: error, warn, info or debug
+    #[arg(long, global = true, value_parser = parse_level)]
+    pub log_level: Option<log::LevelFilter>,
+
+    /// directory to check when there is no config file (duplicates are only reported)
+    pub path: Option<String>,
+
+    #[command(flatten)]
+    pub filters: FilterArgs,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+impl Cli {
+    /// Reads the config file and applies the command line overrides.
+    pub fn load_settings(&self) -> Result<Settings, std::io::Error> {
+        let mut settings = Settings::load(&self.config)?;
+        self.filters.apply(&mut settings).map_err(std::io::Error::other)?;
+        Ok(settings)
+    }
+}
+
+fn parse_level(s: &str) -> Result<log::LevelFilter, String> {
+    s.parse().map_err(|_| format!("invalid level \"{}\"", s))
+}
+
+/// Command line overrides for the file filter settings
+#[derive(Args, Debug, Default)]
+pub struct FilterArgs {
+    /// only process files at least this big, e.g. 100KiB
+    #[arg(long, global = true)]
+    pub min_size: Option<String>,
+    /// only process files at most this big, e.g. 2GiB
+    #[arg(long, global = true)]
+    pub max_size: Option<String>,
+    /// only process these extensions, e.g. jpg,cr2,mp4
+    #[arg(long, global = true, value_delimiter = ',')]
+    pub include_ext: Vec<String>,
+    /// never process these extensions
+    #[arg(long, global = true, value_delimiter = ',')]
+    pub exclude_ext: Vec<String>,
+    /// only process files modified before this date (YYYY-MM-DD)
+    #[arg(long, global = true)]
+    pub modified_before: Option<String>,
+    /// only process files modified on or after this date (YYYY-MM-DD)
+    #[arg(long, global = true)]
+    pub modified_after: Option<String>,
+    /// process zero-byte files too
+    #[arg(long, global = true)]
+    pub include_empty_files: bool,
+}
+
+impl FilterArgs {
+    pub fn apply(&self, settings: &mut Settings) -> Result<(), String> {
+        if self.min_size.is_some() {
+            settings.min_size = self.min_size.clone();
+        }
+        if self.max_size.is_some() {
+            settings.max_size = self.max_size.clone();
+        }
+        if !self.include_ext.is_empty() {
+            settings.include_extensions = self.include_ext.clone();
+        }
+        if !self.exclude_ext.is_empty() {
+            settings.exclude_extensions = self.exclude_ext.clone();
+        }
+        if self.modified_before.is_some() {
+            settings.modified_before = self.modified_before.clone();
+        }
+        if self.modified_after.is_some() {
+            settings.modified_after = self.modified_after.clone();
+        }
+        if self.include_empty_files {
+            settings.include_empty_files = true;
+        }
+        FileFilter::from_settings(settings).map(|_| ())
+    }
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Scan working_dir once, without watching it afterwards
+    Scan {
+        /// continue the last interrupted scan of working_dir instead of starting over
+        #[arg(long)]
+        resume: bool,
+    },
+    /// Scan working_dir, then keep watching it as a service: handles SIGTERM/SIGINT,
+    /// reloads the config on SIGHUP and reports readiness/watchdog pings to systemd
+    Daemon,
+    /// Write all duplicate groups found so far (from the hash database)
+    Report {
+        #[arg(long, value_enum, default_value_t = ReportFormat::Json)]
+        format: ReportFormat,
+        /// file to write to instead of stdout
+        #[arg(short, long)]
+        output: Option<String>,
+    },
+    /// Write groups of images, videos and recordings that are alike (needs similar_images, similar_videos or audio_fingerprint = true in the config)
+    Similar {
+        #[arg(long, value_enum, default_value_t = ReportFormat::Json)]
+        format: ReportFormat,
+        /// overrides image_max_distance from the config
+        #[arg(long, value_parser = clap::value_parser!(u32).range(0..=64))]
+        max_distance: Option<u32>,
+        /// file to write to instead of stdout
+        #[arg(short, long)]
+        output: Option<String>,
+    },
+    /// Hash every file again whose size and mtime did not change and report the ones whose content
+    /// did (bit rot). Nothing is deleted; with verify_restore an intact copy is copied over a corrupted file
+    Verify {
+        /// start over after each pass until SIGTERM/SIGINT, to run in the background
+        #[arg(long)]
+        continuous: bool,
+        /// overrides verify_rate from the config: bytes per second, e.g. 20MiB
+        #[arg(long)]
+        rate: Option<String>,
+    },
+    /// Maintenance of the hash database (filehashes.db)
+    Db {
+        #[command(subcommand)]
+        command: DbCommand,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum DbCommand {
+    /// Remove rows for files that are gone or outside working_dir (and not ignored),
+    /// recalculate hashes of files whose size or mtime changed
+    Prune {
+        /// delete rows of changed files instead of recalculating their hashes
+        #[arg(long)]
+        no_refresh: bool,
+    },
+}
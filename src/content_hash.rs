@@ -0,0 +1,291 @@
+use sha2::{Digest, Sha512};
+
+use std::fs::File;
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
+
+use crate::audio;
+use crate::daemon::KeepAlive;
+use crate::datastore::FileInfo;
+use crate::settings::Settings;
+
+/// Images that get a content hash when image_content_hash is on.
+const IMAGE_EXTENSIONS: [&str; 4] = ["jpg", "jpeg", "jpe", "png"];
+const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n'];
+/// PNG chunks that make up the image (animation frames of APNG included), all others are metadata
+const PNG_IMAGE_CHUNKS: [&[u8; 4]; 7] = [b"IHDR", b"PLTE", b"tRNS", b"IDAT", b"acTL", b"fcTL", b"fdAT"];
+const ID3V1_SIZE: u64 = 128;
+const APE_FOOTER_SIZE: u64 = 32;
+/// stored as the content hash of a file that was read for one and has none (not an image or audio
+/// file after all, cut off), so that it isn't read again until it changes
+pub const NONE: &str = "";
+
+/// Whether `path` gets a content hash with `settings`.
+pub fn applies(path: &Path, settings: &Settings) -> bool {
+    let extension = path.extension().map(|e| e.to_string_lossy().to_lowercase()).unwrap_or_default();
+    (settings.image_content_hash && IMAGE_EXTENSIONS.contains(&extension.as_str()))
+        || (settings.audio_content_hash && audio::EXTENSIONS.contains(&extension.as_str()))
+}
+
+/// The content hash of `info`, if it gets one with `settings` and has one.
+pub fn of<'a>(info: &'a FileInfo, settings: &Settings) -> Option<&'a str> {
+    info.content_hash.as_deref().filter(|h| *h != NONE && applies(Path::new(&info.full_path), settings))
+}
+
+/// sha512 (hex) of an image or audio file without its metadata, so that files that differ only
+/// in those get the same hash:
+/// - JPEG, PNG: EXIF, XMP, IPTC, ICC profiles, comments, text chunks...
+/// - MP3: ID3v1, ID3v2 and APE tags
+/// - FLAC: all metadata blocks but STREAMINFO
+/// - Ogg Vorbis and Opus: the comment header
+/// - WAV: all chunks but "fmt " and "data"
+///
+/// The format is taken from the content. None for other files and files that end too early.
+pub fn content_hash(path: &Path) -> Option<String> {
+    let mut file = File::open(path).ok()?;
+    let length = file.metadata().ok()?.len();
+    let start = skip_id3v2(&mut file).ok()?;
+    let mut magic = [0; 12];
+    file.read_exact(&mut magic).ok()?;
+    let is_audio = start > 0 || magic.starts_with(b"fLaC") || magic.starts_with(b"OggS")
+        || (magic.starts_with(b"RIFF") && &magic[8..] == b"WAVE") || is_mp3_frame(&magic);
+    let end = if is_audio { trailing_tags_start(&mut file, length).ok()? } else { length };
+    file.seek(SeekFrom::Start(start)).ok()?;
+    let mut reader = BufReader::new(KeepAlive(file.take(end.saturating_sub(start))));
+    let mut hasher = Sha512::new();
+    let hashed = if magic.starts_with(&[0xff, 0xd8]) && start == 0 {
+        hash_jpeg(&mut reader, &mut hasher)
+    } else if magic.starts_with(&PNG_SIGNATURE) && start == 0 {
+        hash_png(&mut reader, &mut hasher)
+    } else if magic.starts_with(b"fLaC") {
+        hash_flac(&mut reader, &mut hasher)
+    } else if magic.starts_with(b"OggS") {
+        hash_ogg(&mut reader, &mut hasher)
+    } else if magic.starts_with(b"RIFF") && &magic[8..] == b"WAVE" {
+        hash_wav(&mut reader, &mut hasher)
+    } else if is_mp3_frame(&magic) {
+        io::copy(&mut reader, &mut hasher).map(|_| ())
+    } else {
+        return None;
+    };
+    hashed.ok()?;
+    Some(format!("{:x}", hasher.finalize()))
+}
+
+fn is_mp3_frame(magic: &[u8]) -> bool {
+    magic[0] == 0xff && magic[1] & 0xe0 == 0xe0
+}
+
+/// Skips ID3v2 tags (there can be more than one) and the zero padding after them.
+/// Returns where the content starts, the file is positioned there.
+fn skip_id3v2(file: &mut File) -> io::Result<u64> {
+    let mut start = 0;
+    loop {
+        file.seek(SeekFrom::Start(start))?;
+        let mut header = [0; 10];
+        if file.read_exact(&mut header).is_err() || !header.starts_with(b"ID3") {
+            break;
+        }
+        // size is syncsafe: 7 bits per byte, without the header and the footer
+        let size = header[6..].iter().fold(0u64, |s, b| (s << 7) | (*b & 0x7f) as u64);
+        let footer = if header[5] & 0x10 != 0 { 10 } else { 0 };
+        start += 10 + size + footer;
+    }
+    if start > 0 {
+        file.seek(SeekFrom::Start(start))?;
+        let mut reader = BufReader::new(&mut *file);
+        let mut byte = [0];
+        while reader.read_exact(&mut byte).is_ok() && byte[0] == 0 {
+            start += 1;
+        }
+    }
+    file.seek(SeekFrom::Start(start))?;
+    Ok(start)
+}
+
+/// Where an ID3v1 tag and an APEv2 tag before it (or either one) at the end of the file start.
+fn trailing_tags_start(file: &mut File, length: u64) -> io::Result<u64> {
+    let mut end = length;
+    if end >= ID3V1_SIZE {
+        file.seek(SeekFrom::Start(end - ID3V1_SIZE))?;
+        let mut tag = [0; 3];
+        file.read_exact(&mut tag)?;
+        if &tag == b"TAG" {
+            end -= ID3V1_SIZE;
+        }
+    }
+    if end >= APE_FOOTER_SIZE {
+        file.seek(SeekFrom::Start(end - APE_FOOTER_SIZE))?;
+        let mut footer = [0; APE_FOOTER_SIZE as usize];
+        file.read_exact(&mut footer)?;
+        if footer.starts_with(b"APETAGEX") {
+            // the size counts the items and the footer, the header (if there is one) comes on top
+            let size = u32::from_le_bytes([footer[12], footer[13], footer[14], footer[15]]) as u64;
+            let has_header = footer[23] & 0x80 != 0;
+            end = end.saturating_sub(size + if has_header { APE_FOOTER_SIZE } else { 0 });
+        }
+    }
+    Ok(end)
+}
+
+/// Hashes every segment but APPn (except APP14, which changes how colors are decoded) and
+/// comments, then everything from the first scan on.
+fn hash_jpeg(reader: &mut impl Read, hasher: &mut Sha512) -> io::Result<()> {
+    let mut soi = [0; 2];
+    reader.read_exact(&mut soi)?;
+    loop {
+        let mut marker = [0; 2];
+        reader.read_exact(&mut marker)?;
+        if marker[0] != 0xff {
+            return Err(io::ErrorKind::InvalidData.into());
+        }
+        // fill bytes
+        while marker[1] == 0xff {
+            reader.read_exact(&mut marker[1..])?;
+        }
+        match marker[1] {
+            // EOI before any scan
+            0xd9 => return Ok(()),
+            // no length: TEM, RSTn
+            0x01 | 0xd0..=0xd7 => {
+                hasher.update(marker);
+                continue;
+            }
+            _ => (),
+        }
+        let mut length = [0; 2];
+        reader.read_exact(&mut length)?;
+        let data_length = (u16::from_be_bytes(length) as u64).checked_sub(2).ok_or(io::ErrorKind::InvalidData)?;
+        let metadata = matches!(marker[1], 0xe0..=0xed | 0xef | 0xfe);
+        if metadata {
+            copy_exactly(reader, &mut io::sink(), data_length)?;
+            continue;
+        }
+        hasher.update(marker);
+        hasher.update(length);
+        copy_exactly(reader, hasher, data_length)?;
+        if marker[1] == 0xda {
+            // start of scan: the compressed image data and everything after it
+            io::copy(reader, hasher)?;
+            return Ok(());
+        }
+    }
+}
+
+/// Hashes type and data of the image chunks, IDAT data as one stream so that it doesn't
+/// matter how it is split into chunks. CRCs are not checked.
+fn hash_png(reader: &mut impl Read, hasher: &mut Sha512) -> io::Result<()> {
+    copy_exactly(reader, &mut io::sink(), PNG_SIGNATURE.len() as u64)?;
+    loop {
+        let mut header = [0; 8];
+        reader.read_exact(&mut header)?;
+        let length = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as u64;
+        let kind = &header[4..];
+        if kind == b"IEND" {
+            return Ok(());
+        }
+        if !PNG_IMAGE_CHUNKS.iter().any(|c| c.as_slice() == kind) {
+            copy_exactly(reader, &mut io::sink(), length + 4)?;
+            continue;
+        }
+        if kind != b"IDAT" {
+            hasher.update(kind);
+        }
+        copy_exactly(reader, hasher, length)?;
+        copy_exactly(reader, &mut io::sink(), 4)?;
+    }
+}
+
+/// Hashes STREAMINFO and the audio frames after the metadata blocks.
+fn hash_flac(reader: &mut impl Read, hasher: &mut Sha512) -> io::Result<()> {
+    copy_exactly(reader, &mut io::sink(), 4)?;
+    loop {
+        let mut header = [0; 4];
+        reader.read_exact(&mut header)?;
+        let length = u32::from_be_bytes([0, header[1], header[2], header[3]]) as u64;
+        if header[0] & 0x7f == 0 {
+            copy_exactly(reader, hasher, length)?;
+        } else {
+            copy_exactly(reader, &mut io::sink(), length)?;
+        }
+        if header[0] & 0x80 != 0 {
+            io::copy(reader, hasher)?;
+            return Ok(());
+        }
+    }
+}
+
+/// Hashes the packets of the first logical stream except the second one, which is the
+/// comment header of Vorbis and Opus. Page headers (sequence numbers, CRCs) are left out
+/// because a longer comment can move the audio to other pages.
+fn hash_ogg(reader: &mut impl Read, hasher: &mut Sha512) -> io::Result<()> {
+    let mut serial = None;
+    let mut packet = 0;
+    loop {
+        let mut header = [0; 27];
+        match reader.read_exact(&mut header) {
+            Ok(()) => (),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof && serial.is_some() => return Ok(()),
+            Err(e) => return Err(e),
+        }
+        if !header.starts_with(b"OggS") {
+            return Err(io::ErrorKind::InvalidData.into());
+        }
+        let mut lacing = vec![0; header[26] as usize];
+        reader.read_exact(&mut lacing)?;
+        let mut data = vec![0; lacing.iter().map(|l| *l as usize).sum()];
+        reader.read_exact(&mut data)?;
+        let page_serial = &header[14..18];
+        match serial {
+            None => {
+                if !data.starts_with(b"\x01vorbis") && !data.starts_with(b"OpusHead") {
+                    return Err(io::ErrorKind::InvalidData.into());
+                }
+                serial = Some(page_serial.to_vec());
+            }
+            Some(ref s) if s != page_serial => continue,
+            Some(_) => (),
+        }
+        let mut offset = 0;
+        for segment in lacing {
+            let segment = segment as usize;
+            if packet != 1 {
+                hasher.update(&data[offset..offset + segment]);
+            }
+            offset += segment;
+            if segment < 255 {
+                packet += 1;
+            }
+        }
+    }
+}
+
+/// Hashes the "fmt " and "data" chunks, skipping LIST, id3 and the like.
+fn hash_wav(reader: &mut impl Read, hasher: &mut Sha512) -> io::Result<()> {
+    copy_exactly(reader, &mut io::sink(), 12)?;
+    loop {
+        let mut header = [0; 8];
+        match reader.read_exact(&mut header) {
+            Ok(()) => (),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e),
+        }
+        let length = u32::from_le_bytes([header[4], header[5], header[6], header[7]]) as u64;
+        // chunks are padded to an even length
+        let padded = length + length % 2;
+        if &header[..4] == b"fmt " || &header[..4] == b"data" {
+            hasher.update(&header[..4]);
+            copy_exactly(reader, hasher, length)?;
+            copy_exactly(reader, &mut io::sink(), padded - length)?;
+        } else {
+            copy_exactly(reader, &mut io::sink(), padded)?;
+        }
+    }
+}
+
+fn copy_exactly(reader: &mut impl Read, writer: &mut impl io::Write, length: u64) -> io::Result<()> {
+    if io::copy(&mut reader.take(length), writer)? < length {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(())
+}
@@ -0,0 +1,90 @@
+use sd_notify::NotifyState;
+use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM};
+use signal_hook::flag;
+
+use std::io::{self, Read};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::{Duration, Instant};
+
+static TERMINATE: LazyLock<Arc<AtomicBool>> = LazyLock::new(|| Arc::new(AtomicBool::new(false)));
+static RELOAD: LazyLock<Arc<AtomicBool>> = LazyLock::new(|| Arc::new(AtomicBool::new(false)));
+static WATCHDOG: LazyLock<Option<Duration>> = LazyLock::new(|| {
+    let mut usec = 0;
+    if sd_notify::watchdog_enabled(false, &mut usec) {
+        Some(Duration::from_micros(usec))
+    } else {
+        None
+    }
+});
+static LAST_PING: LazyLock<Mutex<Instant>> = LazyLock::new(|| Mutex::new(Instant::now()));
+
+/// Installs handlers for SIGTERM/SIGINT (finish the current file, then shut down) and
+/// SIGHUP (reload the configuration). A second SIGTERM/SIGINT exits immediately.
+pub fn install_signal_handlers() -> io::Result<()> {
+    for signal in [SIGTERM, SIGINT] {
+        flag::register_conditional_shutdown(signal, 1, Arc::clone(&TERMINATE))?;
+        flag::register(signal, Arc::clone(&TERMINATE))?;
+    }
+    flag::register(SIGHUP, Arc::clone(&RELOAD))?;
+    Ok(())
+}
+
+pub fn shutdown_requested() -> bool {
+    TERMINATE.load(Ordering::Relaxed)
+}
+
+/// Returns true (once) if a configuration reload was requested since the last call.
+pub fn take_reload_request() -> bool {
+    RELOAD.swap(false, Ordering::Relaxed)
+}
+
+/// How often the main loop should wake up to check signals and ping the systemd watchdog.
+pub fn tick() -> Duration {
+    match *WATCHDOG {
+        Some(w) => (w / 2).min(Duration::from_secs(1)),
+        None => Duration::from_secs(1),
+    }
+}
+
+/// Pings the systemd watchdog if it is enabled and half of its interval has passed.
+/// Cheap enough to be called for every processed file.
+pub fn keepalive() {
+    if let Some(w) = *WATCHDOG {
+        let mut last = LAST_PING.lock().unwrap();
+        if last.elapsed() >= w / 2 {
+            notify(&[NotifyState::Watchdog]);
+            *last = Instant::now();
+        }
+    }
+}
+
+/// Pings the watchdog (see `keepalive`) whenever something is read, so that reading one big
+/// file doesn't take longer than the watchdog waits.
+pub struct KeepAlive<R>(pub R);
+
+impl<R: Read> Read for KeepAlive<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        keepalive();
+        self.0.read(buf)
+    }
+}
+
+pub fn ready(status: &str) {
+    notify(&[NotifyState::Ready, NotifyState::Status(status)]);
+}
+
+pub fn reloading() {
+    notify(&[NotifyState::Reloading]);
+}
+
+pub fn stopping() {
+    notify(&[NotifyState::Stopping]);
+}
+
+/// Sends a state change to systemd. Does nothing when not started by systemd.
+fn notify(state: &[NotifyState]) {
+    if let Err(e) = sd_notify::notify(false, state) {
+        log::warn!("sd_notify failed: {:?}", e);
+    }
+}
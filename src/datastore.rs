@@ -1,34 +1,146 @@
 use rusqlite::{params, Connection, Result};
 use std::convert::TryInto;
 
-#[derive(Debug)]
+use crate::archive;
+use crate::stats::ScanSummary;
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct FileInfo {
     pub full_path: String,
     pub size: u64,
     pub hash: String,
-    pub last_modified: u64
+    pub last_modified: u64,
+    /// hash of the image without its metadata, see content_hash; content_hash::NONE if it has none
+    pub content_hash: Option<String>,
+}
+
+/// One run of `process_path`, used to resume an interrupted scan.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ScanRun {
+    pub id: i64,
+    pub root: String,
+    pub started_at: u64,
+    pub finished_at: Option<u64>,
+    /// running, interrupted or finished
+    pub status: String,
+    /// directory the walk was in when the last checkpoint was written;
+    /// everything before it (in walk order) has been processed
+    pub last_dir: Option<String>,
+    pub files_processed: u64,
+    pub dirs_processed: u64,
+    pub summary: ScanSummary,
+    /// copies counted in `summary` that were not saved yet (see ScanStats), with the hash and size
+    /// of their group. All of them when the run is read to be resumed, so that groups seen before
+    /// and after the interruption are counted once
+    pub copies: Vec<FileInfo>,
+}
+
+/// Perceptual hash of an image, see image_hash.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImageHash {
+    pub path: String,
+    /// ahash, dhash or phash
+    pub kind: String,
+    pub hash: u64,
+    /// mtime of the file when it was hashed
+    pub last_modified: u64,
+}
+
+/// EXIF identity of a photo, see photo.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PhotoEntry {
+    pub path: String,
+    /// None if the EXIF data doesn't say which shot it is
+    pub identity: Option<String>,
+    /// mtime of the file when it was read
+    pub last_modified: u64,
+}
+
+/// Acoustic fingerprint of an audio file, see audio.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AudioFingerprint {
+    pub path: String,
+    pub duration_ms: u64,
+    /// 32 bits per frame of the first two minutes
+    pub fingerprint: Vec<u32>,
+    /// mtime of the file when it was decoded
+    pub last_modified: u64,
+}
+
+/// Perceptual hashes of frames of a video, see video.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VideoHash {
+    pub path: String,
+    pub duration_ms: u64,
+    /// dHash of a frame at each of video::FRAME_POSITIONS
+    pub frames: Vec<u64>,
+    /// mtime of the file when it was sampled
+    pub last_modified: u64,
 }
 
-pub struct DataStore {}
+/// Columns of `scan_runs` holding the `ScanSummary`, in field order.
+const SUMMARY_COLUMNS: [&str; 9] = ["files_scanned", "files_hashed", "files_cached", "bytes_read", "duplicate_groups",
+    "redundant_copies", "bytes_reclaimable", "bytes_freed", "elapsed_secs"];
+
+pub struct DataStore {
+    path: String,
+}
 
 impl DataStore {
     pub fn new() -> DataStore {
-        DataStore{}
+        DataStore{ path: DBFILENAME.to_string() }
+    }
+
+    /// A database in another file than filehashes.db, e.g. for tests.
+    #[cfg(test)]
+    pub fn at(path: &str) -> DataStore {
+        DataStore{ path: path.to_string() }
     }
 }
 #[cfg_attr(test,mockall::automock)]
 pub trait DataManager {
     fn create_tables(&self) -> Result<()>;
     fn get_entries_by_hash(&self,hash: &str) -> Result<Vec<FileInfo>>;
+    fn get_entries_by_content_hash(&self,content_hash: &str) -> Result<Vec<FileInfo>>;
+    /// rows of the files inside the archive at `path`, see archive
+    fn get_entries_in_archive(&self,path: &str) -> Result<Vec<FileInfo>>;
     fn get_entry_for_path(&self,path: &str) -> Result<Option<FileInfo>>;
+    fn get_all_entries(&self) -> Result<Vec<FileInfo>>;
+    /// hashes that have more than one row
+    fn get_duplicate_hashes(&self) -> Result<Vec<String>>;
+    /// content hashes of files with more than one (byte) hash
+    fn get_duplicate_content_hashes(&self) -> Result<Vec<String>>;
     fn delete_entry_for_path(&self,path: &str) -> Result<()>;
     fn add_entry(&self,entry: &FileInfo) -> Result<()>;    
+    fn start_scan_run(&self, root: &str, started_at: u64) -> Result<ScanRun>;
+    fn update_scan_run(&self, run: &ScanRun) -> Result<()>;
+    /// latest run for `root` that did not finish, if any
+    fn get_resumable_scan_run(&self, root: &str) -> Result<Option<ScanRun>>;
+    fn get_image_hash(&self, path: &str, kind: &str) -> Result<Option<ImageHash>>;
+    /// replaces the hash of the same path and kind
+    fn add_image_hash(&self, entry: &ImageHash) -> Result<()>;
+    fn get_image_hashes(&self, kind: &str) -> Result<Vec<ImageHash>>;
+    fn get_photo(&self, path: &str) -> Result<Option<PhotoEntry>>;
+    /// replaces the entry of the same path
+    fn add_photo(&self, entry: &PhotoEntry) -> Result<()>;
+    fn get_photos_by_identity(&self, identity: &str) -> Result<Vec<PhotoEntry>>;
+    /// identities of more than one photo
+    fn get_shared_photo_identities(&self) -> Result<Vec<String>>;
+    fn get_audio_fingerprint(&self, path: &str) -> Result<Option<AudioFingerprint>>;
+    /// replaces the fingerprint of the same path
+    fn add_audio_fingerprint(&self, entry: &AudioFingerprint) -> Result<()>;
+    /// fingerprints of files at least `min_ms` and at most `max_ms` long
+    fn get_audio_fingerprints_by_duration(&self, min_ms: u64, max_ms: u64) -> Result<Vec<AudioFingerprint>>;
+    fn get_video_hash(&self, path: &str) -> Result<Option<VideoHash>>;
+    /// replaces the hashes of the same path
+    fn add_video_hash(&self, entry: &VideoHash) -> Result<()>;
+    fn get_video_hashes(&self) -> Result<Vec<VideoHash>>;
 }
 
-static DBFILENAME : &'static str = "filehashes.db";
+static DBFILENAME : &str = "filehashes.db";
 impl DataManager for DataStore {
      fn create_tables(&self) -> Result<()> {
-    let connection = Connection::open(DBFILENAME)?;
+    let connection = Connection::open(&self.path)?;
     //https://rust-lang-nursery.github.io/rust-cookbook/database/sqlite.html
     connection.execute(
         "CREATE TABLE IF NOT EXISTS file_hashes (
@@ -40,79 +152,524 @@ impl DataManager for DataStore {
          )",
         ()
     )?;
+    connection.execute(
+        "CREATE TABLE IF NOT EXISTS scan_runs (
+             id INTEGER PRIMARY KEY,
+             root TEXT NOT NULL,
+             started_at INTEGER NOT NULL,
+             finished_at INTEGER,
+             status TEXT NOT NULL,
+             last_dir TEXT,
+             files_processed INTEGER NOT NULL DEFAULT 0,
+             dirs_processed INTEGER NOT NULL DEFAULT 0
+         )",
+        ()
+    )?;
+    // summary columns were added later, databases from before get them here
+    let existing: Vec<String> = connection.prepare("SELECT name FROM pragma_table_info('scan_runs')")?
+        .query_map([], |row| row.get(0))?
+        .collect::<Result<_>>()?;
+    for column in SUMMARY_COLUMNS.iter().filter(|c| !existing.iter().any(|e| e == *c)) {
+        connection.execute(&format!("ALTER TABLE scan_runs ADD COLUMN {} INTEGER NOT NULL DEFAULT 0", column), ())?;
+    }
+    let hash_columns: Vec<String> = connection.prepare("SELECT name FROM pragma_table_info('file_hashes')")?
+        .query_map([], |row| row.get(0))?
+        .collect::<Result<_>>()?;
+    if !hash_columns.iter().any(|c| c == "content_hash") {
+        connection.execute("ALTER TABLE file_hashes ADD COLUMN content_hash TEXT", ())?;
+    }
+    connection.execute("CREATE INDEX IF NOT EXISTS file_hashes_content_hash ON file_hashes (content_hash)", ())?;
+    // hashes are u64 stored as i64 with the same bits
+    connection.execute(
+        "CREATE TABLE IF NOT EXISTS image_hashes (
+             path TEXT NOT NULL,
+             kind TEXT NOT NULL,
+             hash INTEGER NOT NULL,
+             last_modified INTEGER NOT NULL,
+             PRIMARY KEY (path, kind)
+         )",
+        ()
+    )?;
+    connection.execute(
+        "CREATE TABLE IF NOT EXISTS photo_exif (
+             path TEXT PRIMARY KEY,
+             identity TEXT,
+             last_modified INTEGER NOT NULL
+         )",
+        ()
+    )?;
+    connection.execute("CREATE INDEX IF NOT EXISTS photo_exif_identity ON photo_exif (identity)", ())?;
+    // fingerprints are u32 little endian
+    connection.execute(
+        "CREATE TABLE IF NOT EXISTS audio_fingerprints (
+             path TEXT PRIMARY KEY,
+             duration_ms INTEGER NOT NULL,
+             fingerprint BLOB NOT NULL,
+             last_modified INTEGER NOT NULL
+         )",
+        ()
+    )?;
+    connection.execute("CREATE INDEX IF NOT EXISTS audio_fingerprints_duration ON audio_fingerprints (duration_ms)", ())?;
+    // frame hashes are u64 little endian
+    connection.execute(
+        "CREATE TABLE IF NOT EXISTS video_hashes (
+             path TEXT PRIMARY KEY,
+             duration_ms INTEGER NOT NULL,
+             frames BLOB NOT NULL,
+             last_modified INTEGER NOT NULL
+         )",
+        ()
+    )?;
+    // kept until the run finishes
+    connection.execute(
+        "CREATE TABLE IF NOT EXISTS scan_run_copies (
+             run_id INTEGER NOT NULL,
+             hash TEXT NOT NULL,
+             size INTEGER NOT NULL,
+             path TEXT NOT NULL,
+             PRIMARY KEY (run_id, hash, path)
+         )",
+        ()
+    )?;
 
     Ok(())
 }
 
     fn get_entries_by_hash(&self,hash: &str) -> Result<Vec<FileInfo>> {
-        let connection = Connection::open(DBFILENAME)?;
+        let connection = Connection::open(&self.path)?;
 
-        let sql = r#"SELECT path, hash, file_size, last_modified
+        let sql = r#"SELECT path, hash, file_size, last_modified, content_hash
                     FROM file_hashes
                     WHERE hash=?"#;
         let mut stmt = connection.prepare(sql)?;
-        let mut entries = stmt.query_map(&[hash], 
+        let entries = stmt.query_map([hash], 
             |row| 
                 
             Ok(FileInfo { 
                 full_path : row.get(0)?, 
                 hash: row.get(1)?,
                 size : row.get::<usize,i64>(2)?.try_into().unwrap() ,
-                last_modified : row.get::<usize,i64>(3)?.try_into().unwrap() ,
+                last_modified : row.get::<usize,i64>(3)?.try_into().unwrap(),
+                content_hash: row.get(4)?,
             })).unwrap();
                 
         let mut list: Vec<FileInfo> = Vec::new();
-            while let Some(result) = entries.next() {
-                if let Some(entry) = result.ok() {
-                    list.push(entry);
-                }
+            for entry in entries.flatten() {
+                list.push(entry);
             }            
             Ok(list)                                      
     }
 
+    fn get_entries_by_content_hash(&self,content_hash: &str) -> Result<Vec<FileInfo>> {
+        let connection = Connection::open(&self.path)?;
+
+        let sql = r#"SELECT path, hash, file_size, last_modified, content_hash
+                    FROM file_hashes
+                    WHERE content_hash=?"#;
+        let mut stmt = connection.prepare(sql)?;
+        let entries = stmt.query_map([content_hash],
+            |row| {
+            Ok(FileInfo {
+                full_path : row.get(0)?,
+                hash: row.get(1)?,
+                size : row.get::<usize,i64>(2)?.try_into().unwrap() ,
+                last_modified : row.get::<usize,i64>(3)?.try_into().unwrap(),
+                content_hash: row.get(4)?,
+            })})?;
+
+        entries.collect()
+    }
+
+    fn get_entries_in_archive(&self,path: &str) -> Result<Vec<FileInfo>> {
+        let connection = Connection::open(&self.path)?;
+        let (first, last) = archive::member_range(path);
+
+        let sql = r#"SELECT path, hash, file_size, last_modified, content_hash
+                    FROM file_hashes
+                    WHERE path > ?1 AND path < ?2"#;
+        let mut stmt = connection.prepare(sql)?;
+        let entries = stmt.query_map([first, last],
+            |row| {
+            Ok(FileInfo {
+                full_path : row.get(0)?,
+                hash: row.get(1)?,
+                size : row.get::<usize,i64>(2)?.try_into().unwrap() ,
+                last_modified : row.get::<usize,i64>(3)?.try_into().unwrap(),
+                content_hash: row.get(4)?,
+            })
+        })?;
+        entries.collect()
+    }
+
     fn get_entry_for_path(&self,path: &str) -> Result<Option<FileInfo>> {
-        let connection = Connection::open(DBFILENAME)?;
+        let connection = Connection::open(&self.path)?;
 
-        let sql = r#"SELECT path, hash, file_size, last_modified
+        let sql = r#"SELECT path, hash, file_size, last_modified, content_hash
                     FROM file_hashes
                     WHERE path=?"#;
         let mut stmt = connection.prepare(sql)?;
-        let mut entries = stmt.query_map(&[path], 
+        let mut entries = stmt.query_map([path], 
             |row| {           
             Ok(FileInfo { 
                 full_path : row.get(0)?, 
                 hash: row.get(1)?,
                 size : row.get::<usize,i64>(2)?.try_into().unwrap() ,
-                last_modified : row.get::<usize,i64>(3)?.try_into().unwrap() 
+                last_modified : row.get::<usize,i64>(3)?.try_into().unwrap(),
+                content_hash: row.get(4)?,
             })}).unwrap();
     
-        if let Some(result) = entries.next() {
-            if let Some(entry) = result.ok() {
-                return Ok(Some(entry));
-            }
+        if let Some(Ok(entry)) = entries.next() {
+            return Ok(Some(entry));
         }      
         Ok(None)                                                
     }
 
+    fn get_all_entries(&self) -> Result<Vec<FileInfo>> {
+        let connection = Connection::open(&self.path)?;
+
+        let sql = r#"SELECT path, hash, file_size, last_modified, content_hash
+                    FROM file_hashes"#;
+        let mut stmt = connection.prepare(sql)?;
+        let entries = stmt.query_map([],
+            |row| {
+            Ok(FileInfo {
+                full_path : row.get(0)?,
+                hash: row.get(1)?,
+                size : row.get::<usize,i64>(2)?.try_into().unwrap() ,
+                last_modified : row.get::<usize,i64>(3)?.try_into().unwrap(),
+                content_hash: row.get(4)?,
+            })})?;
+
+        entries.collect()
+    }
+
+    fn get_duplicate_hashes(&self) -> Result<Vec<String>> {
+        let connection = Connection::open(&self.path)?;
+
+        let sql = r#"SELECT hash
+                    FROM file_hashes
+                    GROUP BY hash
+                    HAVING COUNT(*) > 1"#;
+        let mut stmt = connection.prepare(sql)?;
+        let hashes = stmt.query_map([], |row| row.get(0))?;
+        hashes.collect()
+    }
+
+    fn get_duplicate_content_hashes(&self) -> Result<Vec<String>> {
+        let connection = Connection::open(&self.path)?;
+
+        let sql = r#"SELECT content_hash
+                    FROM file_hashes
+                    WHERE content_hash IS NOT NULL
+                    GROUP BY content_hash
+                    HAVING COUNT(DISTINCT hash) > 1"#;
+        let mut stmt = connection.prepare(sql)?;
+        let hashes = stmt.query_map([], |row| row.get(0))?;
+        hashes.collect()
+    }
+
     fn delete_entry_for_path(&self,path: &str) -> Result<()> {
-        let connection = Connection::open(DBFILENAME)?;
+        let connection = Connection::open(&self.path)?;
 
         let sql = r#"DELETE
                     FROM file_hashes
                     WHERE path=?"#;
-        connection.execute(sql, &[path])?;
+        connection.execute(sql, [path])?;
+        // the files inside, if it is an archive
+        let (first, last) = archive::member_range(path);
+        connection.execute("DELETE FROM file_hashes WHERE path > ?1 AND path < ?2", [first, last])?;
+        connection.execute("DELETE FROM image_hashes WHERE path=?", [path])?;
+        connection.execute("DELETE FROM photo_exif WHERE path=?", [path])?;
+        connection.execute("DELETE FROM audio_fingerprints WHERE path=?", [path])?;
+        connection.execute("DELETE FROM video_hashes WHERE path=?", [path])?;
         Ok(())                                                
     }
 
     fn add_entry(&self,entry: &FileInfo) -> Result<()> {
-        let connection = Connection::open(DBFILENAME)?;
+        let connection = Connection::open(&self.path)?;
         let size_sql :i64 = entry.size.try_into().unwrap();
         let modified: i64 = entry.last_modified.try_into().unwrap();
         connection.execute(
-            "INSERT INTO file_hashes (path, hash, file_size, last_modified) values (?1,?2,?3,?4)",
-            params![&entry.full_path, &entry.hash, &size_sql, &modified]
+            "INSERT INTO file_hashes (path, hash, file_size, last_modified, content_hash) values (?1,?2,?3,?4,?5)",
+            params![&entry.full_path, &entry.hash, &size_sql, &modified, &entry.content_hash]
+        )?;
+
+        Ok(())
+    }
+
+    fn start_scan_run(&self, root: &str, started_at: u64) -> Result<ScanRun> {
+        let connection = Connection::open(&self.path)?;
+        let started: i64 = started_at.try_into().unwrap();
+        connection.execute(
+            "INSERT INTO scan_runs (root, started_at, status) values (?1,?2,'running')",
+            params![root, &started]
+        )?;
+        Ok(ScanRun {
+            id: connection.last_insert_rowid(),
+            root: root.to_string(),
+            started_at,
+            status: "running".to_string(),
+            ..Default::default()
+        })
+    }
+
+    fn update_scan_run(&self, run: &ScanRun) -> Result<()> {
+        let connection = Connection::open(&self.path)?;
+        let finished: Option<i64> = run.finished_at.map(|f| f.try_into().unwrap());
+        let files: i64 = run.files_processed.try_into().unwrap();
+        let dirs: i64 = run.dirs_processed.try_into().unwrap();
+        let s = &run.summary;
+        let summary: Vec<i64> = [s.files_scanned, s.files_hashed, s.files_cached, s.bytes_read, s.duplicate_groups,
+            s.redundant_copies, s.bytes_reclaimable, s.bytes_freed, s.elapsed_secs]
+            .iter().map(|v| (*v).try_into().unwrap()).collect();
+        let summary_assignments: Vec<String> = SUMMARY_COLUMNS.iter().enumerate().map(|(i, c)| format!("{}=?{}", c, i + 7)).collect();
+        let sql = format!("UPDATE scan_runs SET finished_at=?1, status=?2, last_dir=?3, files_processed=?4, dirs_processed=?5, {} WHERE id=?6",
+            summary_assignments.join(", "));
+        let mut values: Vec<&dyn rusqlite::ToSql> = vec![&finished, &run.status, &run.last_dir, &files, &dirs, &run.id];
+        values.extend(summary.iter().map(|v| v as &dyn rusqlite::ToSql));
+        let transaction = connection.unchecked_transaction()?;
+        transaction.execute(&sql, values.as_slice())?;
+        if run.finished_at.is_some() {
+            transaction.execute("DELETE FROM scan_run_copies WHERE run_id=?", [run.id])?;
+        } else {
+            let mut insert = transaction.prepare("INSERT OR IGNORE INTO scan_run_copies (run_id, hash, size, path) values (?1,?2,?3,?4)")?;
+            for copy in &run.copies {
+                let size: i64 = copy.size.try_into().unwrap();
+                insert.execute(params![&run.id, &copy.hash, &size, &copy.full_path])?;
+            }
+        }
+        transaction.commit()
+    }
+
+    fn get_resumable_scan_run(&self, root: &str) -> Result<Option<ScanRun>> {
+        let connection = Connection::open(&self.path)?;
+
+        let sql = format!(r#"SELECT id, root, started_at, finished_at, status, last_dir, files_processed, dirs_processed, {}
+                    FROM scan_runs
+                    WHERE root=? AND finished_at IS NULL
+                    ORDER BY id DESC
+                    LIMIT 1"#, SUMMARY_COLUMNS.join(", "));
+        let mut stmt = connection.prepare(&sql)?;
+        let mut runs = stmt.query_map([root],
+            |row| {
+            Ok(ScanRun {
+                id: row.get(0)?,
+                root: row.get(1)?,
+                started_at: row.get::<usize,i64>(2)?.try_into().unwrap(),
+                finished_at: row.get::<usize,Option<i64>>(3)?.map(|f| f.try_into().unwrap()),
+                status: row.get(4)?,
+                last_dir: row.get(5)?,
+                files_processed: row.get::<usize,i64>(6)?.try_into().unwrap(),
+                dirs_processed: row.get::<usize,i64>(7)?.try_into().unwrap(),
+                summary: ScanSummary {
+                    files_scanned: row.get::<usize,i64>(8)?.try_into().unwrap(),
+                    files_hashed: row.get::<usize,i64>(9)?.try_into().unwrap(),
+                    files_cached: row.get::<usize,i64>(10)?.try_into().unwrap(),
+                    bytes_read: row.get::<usize,i64>(11)?.try_into().unwrap(),
+                    duplicate_groups: row.get::<usize,i64>(12)?.try_into().unwrap(),
+                    redundant_copies: row.get::<usize,i64>(13)?.try_into().unwrap(),
+                    bytes_reclaimable: row.get::<usize,i64>(14)?.try_into().unwrap(),
+                    bytes_freed: row.get::<usize,i64>(15)?.try_into().unwrap(),
+                    elapsed_secs: row.get::<usize,i64>(16)?.try_into().unwrap(),
+                },
+                copies: vec![],
+            })})?;
+        let Some(mut run) = runs.next().transpose()? else {
+            return Ok(None);
+        };
+
+        let mut stmt = connection.prepare("SELECT hash, size, path FROM scan_run_copies WHERE run_id=?")?;
+        let copies = stmt.query_map([run.id], |row| Ok(FileInfo {
+            hash: row.get(0)?,
+            size: row.get::<usize,i64>(1)?.try_into().unwrap(),
+            full_path: row.get(2)?,
+            last_modified: 0,
+            content_hash: None,
+        }))?;
+        run.copies = copies.collect::<Result<_>>()?;
+        Ok(Some(run))
+    }
+
+    fn get_image_hash(&self, path: &str, kind: &str) -> Result<Option<ImageHash>> {
+        let connection = Connection::open(&self.path)?;
+
+        let sql = r#"SELECT path, kind, hash, last_modified
+                    FROM image_hashes
+                    WHERE path=? AND kind=?"#;
+        let mut stmt = connection.prepare(sql)?;
+        let mut entries = stmt.query_map([path, kind], image_hash_from_row)?;
+        entries.next().transpose()
+    }
+
+    fn add_image_hash(&self, entry: &ImageHash) -> Result<()> {
+        let connection = Connection::open(&self.path)?;
+        let modified: i64 = entry.last_modified.try_into().unwrap();
+        connection.execute(
+            "INSERT OR REPLACE INTO image_hashes (path, kind, hash, last_modified) values (?1,?2,?3,?4)",
+            params![&entry.path, &entry.kind, entry.hash as i64, &modified]
         )?;
+        Ok(())
+    }
+
+    fn get_image_hashes(&self, kind: &str) -> Result<Vec<ImageHash>> {
+        let connection = Connection::open(&self.path)?;
+
+        let sql = r#"SELECT path, kind, hash, last_modified
+                    FROM image_hashes
+                    WHERE kind=?"#;
+        let mut stmt = connection.prepare(sql)?;
+        let entries = stmt.query_map([kind], image_hash_from_row)?;
+        entries.collect()
+    }
+
+    fn get_photo(&self, path: &str) -> Result<Option<PhotoEntry>> {
+        let connection = Connection::open(&self.path)?;
+
+        let sql = r#"SELECT path, identity, last_modified
+                    FROM photo_exif
+                    WHERE path=?"#;
+        let mut stmt = connection.prepare(sql)?;
+        let mut entries = stmt.query_map([path], photo_from_row)?;
+        entries.next().transpose()
+    }
 
+    fn add_photo(&self, entry: &PhotoEntry) -> Result<()> {
+        let connection = Connection::open(&self.path)?;
+        let modified: i64 = entry.last_modified.try_into().unwrap();
+        connection.execute(
+            "INSERT OR REPLACE INTO photo_exif (path, identity, last_modified) values (?1,?2,?3)",
+            params![&entry.path, &entry.identity, &modified]
+        )?;
         Ok(())
     }
+
+    fn get_photos_by_identity(&self, identity: &str) -> Result<Vec<PhotoEntry>> {
+        let connection = Connection::open(&self.path)?;
+
+        let sql = r#"SELECT path, identity, last_modified
+                    FROM photo_exif
+                    WHERE identity=?"#;
+        let mut stmt = connection.prepare(sql)?;
+        let entries = stmt.query_map([identity], photo_from_row)?;
+        entries.collect()
+    }
+
+    fn get_shared_photo_identities(&self) -> Result<Vec<String>> {
+        let connection = Connection::open(&self.path)?;
+
+        let sql = r#"SELECT identity
+                    FROM photo_exif
+                    WHERE identity IS NOT NULL
+                    GROUP BY identity
+                    HAVING COUNT(*) > 1"#;
+        let mut stmt = connection.prepare(sql)?;
+        let identities = stmt.query_map([], |row| row.get(0))?;
+        identities.collect()
+    }
+
+    fn get_audio_fingerprint(&self, path: &str) -> Result<Option<AudioFingerprint>> {
+        let connection = Connection::open(&self.path)?;
+
+        let sql = r#"SELECT path, duration_ms, fingerprint, last_modified
+                    FROM audio_fingerprints
+                    WHERE path=?"#;
+        let mut stmt = connection.prepare(sql)?;
+        let mut entries = stmt.query_map([path], fingerprint_from_row)?;
+        entries.next().transpose()
+    }
+
+    fn add_audio_fingerprint(&self, entry: &AudioFingerprint) -> Result<()> {
+        let connection = Connection::open(&self.path)?;
+        let duration: i64 = entry.duration_ms.try_into().unwrap();
+        let modified: i64 = entry.last_modified.try_into().unwrap();
+        let fingerprint: Vec<u8> = entry.fingerprint.iter().flat_map(|f| f.to_le_bytes()).collect();
+        connection.execute(
+            "INSERT OR REPLACE INTO audio_fingerprints (path, duration_ms, fingerprint, last_modified) values (?1,?2,?3,?4)",
+            params![&entry.path, &duration, &fingerprint, &modified]
+        )?;
+        Ok(())
+    }
+
+    fn get_audio_fingerprints_by_duration(&self, min_ms: u64, max_ms: u64) -> Result<Vec<AudioFingerprint>> {
+        let connection = Connection::open(&self.path)?;
+        let min: i64 = min_ms.try_into().unwrap();
+        let max: i64 = max_ms.try_into().unwrap();
+
+        let sql = r#"SELECT path, duration_ms, fingerprint, last_modified
+                    FROM audio_fingerprints
+                    WHERE duration_ms BETWEEN ?1 AND ?2"#;
+        let mut stmt = connection.prepare(sql)?;
+        let entries = stmt.query_map(params![&min, &max], fingerprint_from_row)?;
+        entries.collect()
+    }
+
+    fn get_video_hash(&self, path: &str) -> Result<Option<VideoHash>> {
+        let connection = Connection::open(&self.path)?;
+
+        let sql = r#"SELECT path, duration_ms, frames, last_modified
+                    FROM video_hashes
+                    WHERE path=?"#;
+        let mut stmt = connection.prepare(sql)?;
+        let mut entries = stmt.query_map([path], video_hash_from_row)?;
+        entries.next().transpose()
+    }
+
+    fn add_video_hash(&self, entry: &VideoHash) -> Result<()> {
+        let connection = Connection::open(&self.path)?;
+        let duration: i64 = entry.duration_ms.try_into().unwrap();
+        let modified: i64 = entry.last_modified.try_into().unwrap();
+        let frames: Vec<u8> = entry.frames.iter().flat_map(|f| f.to_le_bytes()).collect();
+        connection.execute(
+            "INSERT OR REPLACE INTO video_hashes (path, duration_ms, frames, last_modified) values (?1,?2,?3,?4)",
+            params![&entry.path, &duration, &frames, &modified]
+        )?;
+        Ok(())
+    }
+
+    fn get_video_hashes(&self) -> Result<Vec<VideoHash>> {
+        let connection = Connection::open(&self.path)?;
+
+        let sql = r#"SELECT path, duration_ms, frames, last_modified
+                    FROM video_hashes"#;
+        let mut stmt = connection.prepare(sql)?;
+        let entries = stmt.query_map([], video_hash_from_row)?;
+        entries.collect()
+    }
+}
+
+fn image_hash_from_row(row: &rusqlite::Row) -> Result<ImageHash> {
+    Ok(ImageHash {
+        path: row.get(0)?,
+        kind: row.get(1)?,
+        hash: row.get::<usize,i64>(2)? as u64,
+        last_modified: row.get::<usize,i64>(3)?.try_into().unwrap(),
+    })
+}
+
+fn photo_from_row(row: &rusqlite::Row) -> Result<PhotoEntry> {
+    Ok(PhotoEntry {
+        path: row.get(0)?,
+        identity: row.get(1)?,
+        last_modified: row.get::<usize,i64>(2)?.try_into().unwrap(),
+    })
+}
+
+fn fingerprint_from_row(row: &rusqlite::Row) -> Result<AudioFingerprint> {
+    let bytes: Vec<u8> = row.get(2)?;
+    Ok(AudioFingerprint {
+        path: row.get(0)?,
+        duration_ms: row.get::<usize,i64>(1)?.try_into().unwrap(),
+        fingerprint: bytes.chunks_exact(4).map(|c| u32::from_le_bytes([c[0], c[1], c[2], c[3]])).collect(),
+        last_modified: row.get::<usize,i64>(3)?.try_into().unwrap(),
+    })
+}
+
+fn video_hash_from_row(row: &rusqlite::Row) -> Result<VideoHash> {
+    let bytes: Vec<u8> = row.get(2)?;
+    Ok(VideoHash {
+        path: row.get(0)?,
+        duration_ms: row.get::<usize,i64>(1)?.try_into().unwrap(),
+        frames: bytes.chunks_exact(8).map(|c| u64::from_le_bytes(c.try_into().unwrap())).collect(),
+        last_modified: row.get::<usize,i64>(3)?.try_into().unwrap(),
+    })
 }
@@ -0,0 +1,187 @@
+use sha2::{Digest, Sha512};
+
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::datastore::FileInfo;
+
+/// A directory below working_dir, with what the hash database knows about the files in it.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct DirectoryNode {
+    /// Merkle hash: sha512 (hex) of the sorted hashes of its files and subdirectories.
+    /// Names don't matter, a renamed copy has the same hash
+    pub hash: String,
+    /// hashes of all files below it, sorted
+    pub contents: Vec<String>,
+    /// bytes of all files below it
+    pub size: u64,
+    /// every file below it is in the hash database, so deleting it loses nothing that was
+    /// not compared (files skipped by filters or ignore rules make it incomplete)
+    pub complete: bool,
+}
+
+/// Directories below `root` (not `root` itself) that hold any of `entries`, with their hashes.
+/// Looks at the disk to tell whether they are complete.
+pub fn directory_tree(root: &Path, entries: &[FileInfo]) -> BTreeMap<PathBuf, DirectoryNode> {
+    let mut files: BTreeMap<PathBuf, Vec<&FileInfo>> = BTreeMap::new();
+    let mut subdirectories: BTreeMap<PathBuf, BTreeSet<PathBuf>> = BTreeMap::new();
+    for entry in entries {
+        let Some(parent) = Path::new(&entry.full_path).parent() else {
+            continue;
+        };
+        if parent == root || !parent.starts_with(root) {
+            continue;
+        }
+        files.entry(parent.to_path_buf()).or_default().push(entry);
+        let mut directory = parent;
+        while let Some(up) = directory.parent().filter(|up| *up != root) {
+            subdirectories.entry(up.to_path_buf()).or_default().insert(directory.to_path_buf());
+            directory = up;
+        }
+        subdirectories.entry(parent.to_path_buf()).or_default();
+    }
+    // deepest first, so that subdirectories are done before their parents
+    let mut order: Vec<&PathBuf> = subdirectories.keys().collect();
+    order.sort_by_key(|d| std::cmp::Reverse(d.components().count()));
+    let mut tree: BTreeMap<PathBuf, DirectoryNode> = BTreeMap::new();
+    for directory in order {
+        let own = files.get(directory).map(Vec::as_slice).unwrap_or_default();
+        let children: Vec<&DirectoryNode> = subdirectories[directory].iter().map(|d| &tree[d]).collect();
+        let mut parts: Vec<String> = own.iter().map(|f| format!("f:{}", f.hash))
+            .chain(children.iter().map(|c| format!("d:{}", c.hash)))
+            .collect();
+        parts.sort();
+        let mut contents: Vec<String> = own.iter().map(|f| f.hash.clone())
+            .chain(children.iter().flat_map(|c| c.contents.iter().cloned()))
+            .collect();
+        contents.sort();
+        let known: HashSet<PathBuf> = own.iter().map(|f| PathBuf::from(&f.full_path))
+            .chain(subdirectories[directory].iter().cloned())
+            .collect();
+        let node = DirectoryNode {
+            hash: format!("{:x}", Sha512::digest(parts.join("\n"))),
+            contents,
+            size: own.iter().map(|f| f.size).sum::<u64>() + children.iter().map(|c| c.size).sum::<u64>(),
+            complete: children.iter().all(|c| c.complete) && holds_only(directory, &known),
+        };
+        tree.insert(directory.clone(), node);
+    }
+    tree
+}
+
+/// Whether everything in `directory` is in `known`, apart from empty directories.
+fn holds_only(directory: &Path, known: &HashSet<PathBuf>) -> bool {
+    let Ok(entries) = fs::read_dir(directory) else {
+        return false;
+    };
+    entries.into_iter().all(|e| e.is_ok_and(|e| known.contains(&e.path()) || holds_no_files(&e.path())))
+}
+
+fn holds_no_files(path: &Path) -> bool {
+    fs::symlink_metadata(path).is_ok_and(|m| m.is_dir())
+        && fs::read_dir(path).is_ok_and(|entries| entries.into_iter().all(|e| e.is_ok_and(|e| holds_no_files(&e.path()))))
+}
+
+/// Directories that can go as a whole.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DuplicateDirectories {
+    pub delete: Vec<String>,
+    pub keep: String,
+    /// `delete` is not a copy of `keep` but all its files are in `keep` too
+    pub subset: bool,
+    /// Merkle hash of the deleted directories
+    pub hash: String,
+    /// bytes of one deleted directory
+    pub size: u64,
+}
+
+/// Directories with the same files as another one, and directories whose files are all in
+/// another (bigger) one, found in `tree`. Of identical directories the one with the lowest
+/// `score` is kept, the first by path of equal ones; subsets are deleted in favour of the
+/// smallest directory holding all their files. Only complete directories are deleted, and
+/// nothing inside a directory that is deleted already is looked at.
+pub fn find_duplicates(tree: &BTreeMap<PathBuf, DirectoryNode>, score: impl Fn(&str) -> i32) -> Vec<DuplicateDirectories> {
+    let mut found = vec![];
+    let mut deleted: Vec<&PathBuf> = vec![];
+    let is_deleted = |deleted: &[&PathBuf], directory: &Path| deleted.iter().any(|d| directory.starts_with(d));
+    let depth = |directory: &Path| directory.components().count();
+
+    let mut by_hash: HashMap<&str, Vec<&PathBuf>> = HashMap::new();
+    for (directory, node) in tree.iter().filter(|(_, n)| !n.contents.is_empty()) {
+        by_hash.entry(node.hash.as_str()).or_default().push(directory);
+    }
+    let mut groups: Vec<Vec<&PathBuf>> = by_hash.into_values().filter(|g| g.len() > 1).collect();
+    groups.sort_by_key(|g| (g.iter().map(|d| depth(d)).min(), g[0].clone()));
+    for group in groups {
+        let mut members: Vec<&PathBuf> = group.into_iter().filter(|d| !is_deleted(&deleted, d)).collect();
+        members.sort_by_key(|d| (score(&d.to_string_lossy()), d.to_path_buf()));
+        let Some((keep, rest)) = members.split_first() else {
+            continue;
+        };
+        let delete: Vec<&PathBuf> = rest.iter().copied().filter(|d| tree[*d].complete).collect();
+        if delete.is_empty() {
+            continue;
+        }
+        found.push(DuplicateDirectories {
+            delete: delete.iter().map(|d| d.to_string_lossy().to_string()).collect(),
+            keep: keep.to_string_lossy().to_string(),
+            subset: false,
+            hash: tree[*keep].hash.clone(),
+            size: tree[*keep].size,
+        });
+        deleted.extend(delete);
+    }
+
+    // every directory a file is in, directly or below
+    let mut holding: HashMap<&str, Vec<&PathBuf>> = HashMap::new();
+    for (directory, node) in tree {
+        for hash in node.contents.iter().collect::<HashSet<_>>() {
+            holding.entry(hash.as_str()).or_default().push(directory);
+        }
+    }
+    // what is left of a directory once the directories deleted so far are gone
+    let remaining = |deleted: &[&PathBuf], directory: &Path| -> Vec<String> {
+        deleted.iter()
+            .filter(|d| d.starts_with(directory) && !deleted.iter().any(|up| up != *d && d.starts_with(up)))
+            .fold(tree[directory].contents.clone(), |contents, d| without(&contents, &tree[*d].contents))
+    };
+    let mut candidates: Vec<&PathBuf> = tree.iter().filter(|(_, n)| n.complete && !n.contents.is_empty()).map(|(d, _)| d).collect();
+    candidates.sort_by_key(|d| (depth(d), d.to_path_buf()));
+    for directory in candidates {
+        if is_deleted(&deleted, directory) {
+            continue;
+        }
+        let node = &tree[directory];
+        let within = holding[node.contents[0].as_str()].iter()
+            .filter(|other| !other.starts_with(directory) && !directory.starts_with(other) && !is_deleted(&deleted, other))
+            .filter(|other| tree[**other].hash != node.hash && contains(&remaining(&deleted, other), &node.contents))
+            .min_by_key(|other| (tree[**other].contents.len(), other.to_path_buf()));
+        if let Some(within) = within {
+            found.push(DuplicateDirectories {
+                delete: vec![directory.to_string_lossy().to_string()],
+                keep: within.to_string_lossy().to_string(),
+                subset: true,
+                hash: node.hash.clone(),
+                size: node.size,
+            });
+            deleted.push(directory);
+        }
+    }
+    found
+}
+
+/// Sorted `all` without the items of sorted `part`, each as often as `part` has it.
+fn without(all: &[String], part: &[String]) -> Vec<String> {
+    let mut part = part.iter().peekable();
+    all.iter().filter(|a| {
+        while part.next_if(|p| p < a).is_some() {}
+        part.next_if(|p| p == a).is_none()
+    }).cloned().collect()
+}
+
+/// Whether sorted `all` holds every item of sorted `part`, as often as `part` does.
+fn contains(all: &[String], part: &[String]) -> bool {
+    let mut all = all.iter();
+    part.iter().all(|p| all.by_ref().any(|a| a == p))
+}
@@ -1,8 +1,25 @@
 use std::fs;
 use std::fs::File;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::io;
-use walkdir::{WalkDir, IntoIter};
+use walkdir::{DirEntry, FilterEntry, WalkDir, IntoIter};
+
+/// Iterator over a directory tree, see `HandleFiles::walkdir`.
+pub type Walker = FilterEntry<IntoIter, fn(&DirEntry) -> bool>;
+
+/// How a directory tree is walked.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct WalkOptions {
+    /// descend into symlinked directories and process symlinked files (loops are detected)
+    pub follow_symlinks: bool,
+    /// don't cross into other mounted file systems
+    pub one_file_system: bool,
+    /// 0 is the root itself, 1 its direct children...
+    pub max_depth: Option<usize>,
+    /// skip files and directories starting with a dot
+    pub skip_hidden: bool,
+}
+
 pub struct FileManager {
 
 }
@@ -15,22 +32,58 @@ impl FileManager {
 #[cfg_attr(test,mockall::automock)]
 pub trait HandleFiles {
   fn remove_file(&self, path: &str) -> io::Result<()>;
-  fn get_full_path(&self, srcdir: &PathBuf) -> io::Result<PathBuf>;
-  fn walkdir(&self, srcdir: &str) -> IntoIter;
-  fn get_file(&self, path: &PathBuf) -> io::Result<File>;
+  /// removes a directory with everything in it
+  fn remove_dir_all(&self, path: &str) -> io::Result<()>;
+  /// removes a directory and the directories in it, bottom up; fails on the first one that
+  /// isn't empty by then, a file in it is never removed
+  fn remove_empty_dir(&self, path: &str) -> io::Result<()>;
+  /// copies the content of `from` over `to`, which keeps its mtime
+  fn copy_file(&self, from: &str, to: &str) -> io::Result<()>;
+  fn get_full_path(&self, srcdir: &Path) -> io::Result<PathBuf>;
+  fn walkdir(&self, srcdir: &str, options: &WalkOptions) -> Walker;
+  fn get_file(&self, path: &Path) -> io::Result<File>;
 }
 
 impl HandleFiles for FileManager {
     fn remove_file(&self, path: &str) -> io::Result<()>{
         fs::remove_file(path)
     }
-    fn get_full_path(&self, srcdir: &PathBuf) -> io::Result<PathBuf>{
-        fs::canonicalize(&srcdir)
+    fn remove_dir_all(&self, path: &str) -> io::Result<()>{
+        fs::remove_dir_all(path)
+    }
+    fn remove_empty_dir(&self, path: &str) -> io::Result<()>{
+        for entry in fs::read_dir(path)? {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() {
+                self.remove_empty_dir(&entry.path().to_string_lossy())?;
+            }
+        }
+        fs::remove_dir(path)
     }
-    fn walkdir(&self, srcdir: &str) -> IntoIter{
-        WalkDir::new(srcdir).into_iter()
+    fn copy_file(&self, from: &str, to: &str) -> io::Result<()>{
+        let modified = fs::metadata(to)?.modified()?;
+        fs::copy(from, to)?;
+        File::options().write(true).open(to)?.set_modified(modified)
     }
-    fn get_file(&self, path: &PathBuf) -> io::Result<File> {
+    fn get_full_path(&self, srcdir: &Path) -> io::Result<PathBuf>{
+        fs::canonicalize(srcdir)
+    }
+    fn walkdir(&self, srcdir: &str, options: &WalkOptions) -> Walker{
+        // sorted, so that an interrupted scan can be resumed in the same order
+        let mut walkdir = WalkDir::new(srcdir)
+            .sort_by_file_name()
+            .follow_links(options.follow_symlinks)
+            .same_file_system(options.one_file_system);
+        if let Some(depth) = options.max_depth {
+            walkdir = walkdir.max_depth(depth);
+        }
+        let filter: fn(&DirEntry) -> bool = if options.skip_hidden { |e| !is_hidden(e) } else { |_| true };
+        walkdir.into_iter().filter_entry(filter)
+    }
+    fn get_file(&self, path: &Path) -> io::Result<File> {
         File::open(path)
     }
-}
\ No newline at end of file
+}
+fn is_hidden(entry: &DirEntry) -> bool {
+    entry.depth() > 0 && entry.file_name().to_string_lossy().starts_with('.')
+}
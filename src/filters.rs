@@ -0,0 +1,97 @@
+use chrono::{DateTime, Local, NaiveDate, TimeZone};
+
+use std::fs::Metadata;
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+
+use crate::settings::Settings;
+
+/// Which files are candidates for hashing, built from the filter settings.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct FileFilter {
+    pub min_size: Option<u64>,
+    pub max_size: Option<u64>,
+    /// lowercase, without the dot
+    pub include_extensions: Vec<String>,
+    pub exclude_extensions: Vec<String>,
+    /// unix timestamps
+    pub modified_before: Option<u64>,
+    pub modified_after: Option<u64>,
+    pub include_empty_files: bool,
+}
+
+impl FileFilter {
+    pub fn from_settings(settings: &Settings) -> Result<Self, String> {
+        Ok(FileFilter {
+            min_size: settings.min_size.as_deref().map(parse_size).transpose()?,
+            max_size: settings.max_size.as_deref().map(parse_size).transpose()?,
+            include_extensions: normalize_extensions(&settings.include_extensions),
+            exclude_extensions: normalize_extensions(&settings.exclude_extensions),
+            modified_before: settings.modified_before.as_deref().map(parse_date).transpose()?,
+            modified_after: settings.modified_after.as_deref().map(parse_date).transpose()?,
+            include_empty_files: settings.include_empty_files,
+        })
+    }
+
+    pub fn accepts(&self, path: &Path, meta: &Metadata) -> bool {
+        let modified = meta.modified().ok()
+            .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        self.accepts_values(path, meta.len(), modified)
+    }
+
+    pub fn accepts_values(&self, path: &Path, size: u64, modified: u64) -> bool {
+        if size == 0 && !self.include_empty_files {
+            return false;
+        }
+        if self.min_size.is_some_and(|min| size < min) || self.max_size.is_some_and(|max| size > max) {
+            return false;
+        }
+        if self.modified_before.is_some_and(|b| modified >= b) || self.modified_after.is_some_and(|a| modified < a) {
+            return false;
+        }
+        let extension = path.extension().map(|e| e.to_string_lossy().to_lowercase()).unwrap_or_default();
+        if !self.include_extensions.is_empty() && !self.include_extensions.contains(&extension) {
+            return false;
+        }
+        !self.exclude_extensions.contains(&extension)
+    }
+}
+
+/// Accepts "1024", "100K", "100KiB", "1.5MB", "2G"... Units are powers of 1024.
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let split = s.find(|c: char| !(c.is_ascii_digit() || c == '.')).unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+    let number: f64 = number.parse().map_err(|_| format!("Invalid size \"{}\"", s))?;
+    let multiplier: u64 = match unit.trim().to_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kb" | "kib" => 1 << 10,
+        "m" | "mb" | "mib" => 1 << 20,
+        "g" | "gb" | "gib" => 1 << 30,
+        "t" | "tb" | "tib" => 1 << 40,
+        _ => return Err(format!("Invalid size unit in \"{}\"", s)),
+    };
+    Ok((number * multiplier as f64) as u64)
+}
+
+/// Accepts "2020-01-31" (local midnight) or an RFC 3339 timestamp.
+pub fn parse_date(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    if let Ok(dt) = DateTime::parse_from_rfc3339(s) {
+        return Ok(dt.timestamp().max(0) as u64);
+    }
+    let date = NaiveDate::parse_from_str(s, "%Y-%m-%d").map_err(|_| format!("Invalid date \"{}\", expected YYYY-MM-DD", s))?;
+    let midnight = Local.from_local_datetime(&date.and_time(chrono::NaiveTime::MIN)).earliest()
+        .ok_or_else(|| format!("Invalid date \"{}\"", s))?;
+    Ok(midnight.timestamp().max(0) as u64)
+}
+
+fn normalize_extensions(extensions: &[String]) -> Vec<String> {
+    extensions.iter()
+        .flat_map(|e| e.split('|'))
+        .map(|e| e.trim().trim_start_matches('.').to_lowercase())
+        .filter(|e| !e.is_empty())
+        .collect()
+}
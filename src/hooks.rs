@@ -0,0 +1,139 @@
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::report_events::{ReportBody, ReportCounts, ReportEvent};
+use crate::settings::Settings;
+use crate::unix_now;
+
+const DEFAULT_WEBHOOK_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// When a command hook runs.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HookTrigger {
+    /// once for every group of duplicates in the report
+    DuplicateGroup,
+    /// once per report, with the summary
+    Summary,
+}
+
+/// A local command run with `sh -c` when a report is sent.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CommandHook {
+    pub command: String,
+    pub on: HookTrigger,
+}
+
+/// JSON sent to webhooks and to summary hooks on stdin.
+#[derive(Debug, Serialize)]
+pub struct Payload<'a> {
+    /// unix timestamp
+    pub generated_at: u64,
+    pub working_dir: &'a str,
+    /// same text as at the top of the email
+    pub summary: String,
+    pub counts: ReportCounts,
+    pub events: &'a [ReportEvent],
+    /// events left out because there were too many, see the log
+    pub dropped: usize,
+}
+
+impl<'a> Payload<'a> {
+    pub fn new(settings: &'a Settings, body: &'a ReportBody) -> Self {
+        Payload {
+            generated_at: unix_now(),
+            working_dir: &settings.working_dir,
+            summary: body.summary(),
+            counts: body.counts(),
+            events: &body.events,
+            dropped: body.dropped,
+        }
+    }
+}
+
+/// Posts the report to every `webhook_urls` and runs the `command_hooks`.
+/// Failures are logged, one failing hook doesn't stop the others.
+pub fn notify(settings: &Settings, body: &ReportBody) {
+    if body.is_empty() || (settings.webhook_urls.is_empty() && settings.command_hooks.is_empty()) {
+        return;
+    }
+    let payload = serde_json::to_string(&Payload::new(settings, body)).expect("payload can always be serialized");
+    for url in &settings.webhook_urls {
+        match post_webhook(settings, url, &payload) {
+            Ok(()) => log::info!(url = url.as_str(); "Webhook called"),
+            Err(e) => log::error!(url = url.as_str(); "Webhook failed: {}", e),
+        }
+    }
+    for hook in &settings.command_hooks {
+        match hook.on {
+            HookTrigger::Summary => {
+                let counts = body.counts();
+                let env = vec![
+                    ("DUPLICATES_EVENT", "summary".to_string()),
+                    ("DUPLICATES_SUMMARY", body.summary()),
+                    ("DUPLICATES_GROUPS", counts.duplicate_groups.to_string()),
+                    ("DUPLICATES_FILES_DELETED", counts.files_deleted.to_string()),
+                    ("DUPLICATES_FILES_TO_DELETE", counts.files_to_delete.to_string()),
+                ];
+                run_command(hook, &env, &payload);
+            }
+            HookTrigger::DuplicateGroup => {
+                for event in &body.events {
+                    if let ReportEvent::Duplicates { delete, keep, deleted } = event {
+                        let env = vec![
+                            ("DUPLICATES_EVENT", "duplicate_group".to_string()),
+                            ("DUPLICATES_KEEP", keep.clone()),
+                            ("DUPLICATES_DELETE", delete.join("\n")),
+                            ("DUPLICATES_DELETED", deleted.to_string()),
+                            ("DUPLICATES_COUNT", (delete.len() + 1).to_string()),
+                        ];
+                        let json = serde_json::to_string(event).expect("event can always be serialized");
+                        run_command(hook, &env, &json);
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn post_webhook(settings: &Settings, url: &str, payload: &str) -> Result<(), String> {
+    let tls = native_tls::TlsConnector::new().map_err(|e| e.to_string())?;
+    let agent = ureq::AgentBuilder::new()
+        .tls_connector(Arc::new(tls))
+        .timeout(settings.webhook_timeout_secs.map(Duration::from_secs).unwrap_or(DEFAULT_WEBHOOK_TIMEOUT))
+        .build();
+    agent.post(url)
+        .set("Content-Type", "application/json")
+        .set("User-Agent", concat!("duplicates/", env!("CARGO_PKG_VERSION")))
+        .send_string(payload)
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
+
+/// Runs the hook with `env` set and `stdin` written to it, and waits for it to finish.
+fn run_command(hook: &CommandHook, env: &[(&str, String)], stdin: &str) {
+    let child = Command::new("sh")
+        .arg("-c")
+        .arg(&hook.command)
+        .envs(env.iter().map(|(k, v)| (k, v)))
+        .stdin(Stdio::piped())
+        .spawn();
+    let mut child = match child {
+        Ok(c) => c,
+        Err(e) => {
+            log::error!(command = hook.command.as_str(); "Unable to run hook: {}", e);
+            return;
+        }
+    };
+    if let Some(mut input) = child.stdin.take() {
+        // a hook that doesn't read stdin closes it early, that's fine
+        let _ = input.write_all(stdin.as_bytes());
+    }
+    match child.wait() {
+        Ok(status) if status.success() => log::debug!(command = hook.command.as_str(); "Hook finished"),
+        Ok(status) => log::warn!(command = hook.command.as_str(); "Hook failed: {}", status),
+        Err(e) => log::error!(command = hook.command.as_str(); "Unable to wait for hook: {}", e),
+    }
+}
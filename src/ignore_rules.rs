@@ -0,0 +1,150 @@
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use ignore::Match;
+use regex::Regex;
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// Name of the per-directory ignore file, same syntax as `ignore_paths`.
+pub const DUPIGNORE: &str = ".dupignore";
+
+#[derive(Debug)]
+enum Rule {
+    Glob(Gitignore),
+    Regex { regex: Regex, negated: bool },
+}
+
+/// Ignore patterns from one source (config or one `.dupignore`).
+/// As in gitignore, the last matching pattern decides.
+#[derive(Debug, Default)]
+struct RuleSet {
+    rules: Vec<Rule>,
+}
+
+impl RuleSet {
+    /// Gitignore-style lines relative to `base`. Lines starting with `re:` (or `!re:`)
+    /// are regular expressions matched against the absolute path.
+    fn parse<'a>(base: &Path, lines: impl Iterator<Item = &'a str>) -> Result<RuleSet, String> {
+        let mut rules = vec![];
+        for line in lines {
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (negated, pattern) = match line.strip_prefix('!') {
+                Some(p) => (true, p),
+                None => (false, line),
+            };
+            if let Some(re) = pattern.strip_prefix("re:") {
+                let regex = Regex::new(re).map_err(|e| format!("Invalid ignore regex \"{}\": {}", re, e))?;
+                rules.push(Rule::Regex { regex, negated });
+            } else {
+                let mut builder = GitignoreBuilder::new(base);
+                builder.add_line(None, line).map_err(|e| format!("Invalid ignore pattern \"{}\": {}", line, e))?;
+                rules.push(Rule::Glob(builder.build().map_err(|e| format!("Invalid ignore pattern \"{}\": {}", line, e))?));
+            }
+        }
+        Ok(RuleSet { rules })
+    }
+
+    /// Some(true) if ignored, Some(false) if a negation matched, None if nothing matched.
+    fn matched(&self, path: &Path, is_dir: bool) -> Option<bool> {
+        for rule in self.rules.iter().rev() {
+            match rule {
+                Rule::Glob(g) => match g.matched(path, is_dir) {
+                    Match::Ignore(_) => return Some(true),
+                    Match::Whitelist(_) => return Some(false),
+                    Match::None => (),
+                },
+                Rule::Regex { regex, negated } => {
+                    if regex.is_match(&path.to_string_lossy()) {
+                        return Some(!negated);
+                    }
+                }
+            }
+        }
+        None
+    }
+}
+
+/// Decides which paths below working_dir are skipped: `ignore_paths` from the config plus
+/// `.dupignore` files found in the directories on the way. Patterns in a deeper `.dupignore`
+/// win over the ones above it.
+#[derive(Debug, Default)]
+pub struct IgnoreRules {
+    root: PathBuf,
+    config: RuleSet,
+    /// parsed `.dupignore` per directory, None if the directory has none
+    dupignore: Mutex<HashMap<PathBuf, Option<RuleSet>>>,
+    /// only the patterns given, `.dupignore` files are not read
+    config_only: bool,
+}
+
+impl IgnoreRules {
+    /// `root` should be the canonical working_dir, `patterns` the `ignore_paths` setting.
+    pub fn new(root: &Path, patterns: &[String]) -> Result<Self, String> {
+        Ok(IgnoreRules {
+            root: root.to_path_buf(),
+            config: RuleSet::parse(root, patterns.iter().map(|p| p.as_str()))?,
+            dupignore: Mutex::new(HashMap::new()),
+            config_only: false,
+        })
+    }
+
+    /// Like `new`, without `.dupignore` files, e.g. for `protected_paths`.
+    pub fn config_only(root: &Path, patterns: &[String]) -> Result<Self, String> {
+        Ok(IgnoreRules { config_only: true, ..IgnoreRules::new(root, patterns)? })
+    }
+
+    /// Returns true if the absolute `path`, or any directory between working_dir and it, is ignored.
+    pub fn is_ignored(&self, path: &Path, is_dir: bool) -> bool {
+        if !path.starts_with(&self.root) || path == self.root {
+            return self.matched(path, is_dir);
+        }
+        let mut chain: Vec<&Path> = path.ancestors()
+            .take_while(|a| *a != self.root)
+            .collect();
+        chain.reverse();
+        let last = chain.len() - 1;
+        chain.iter().enumerate().any(|(i, p)| self.matched(p, i < last || is_dir))
+    }
+
+    /// Drops the cached `.dupignore` of `dir`, e.g. after the file changed.
+    pub fn forget_dir(&self, dir: &Path) {
+        self.dupignore.lock().unwrap().remove(dir);
+    }
+
+    fn matched(&self, path: &Path, is_dir: bool) -> bool {
+        let mut ignored = self.config.matched(path, is_dir);
+        if self.config_only {
+            return ignored == Some(true);
+        }
+        if let Some(parent) = path.parent() {
+            if parent.starts_with(&self.root) {
+                let mut dirs: Vec<&Path> = parent.ancestors().take_while(|a| a.starts_with(&self.root)).collect();
+                dirs.reverse();
+                let mut cache = self.dupignore.lock().unwrap();
+                for dir in dirs {
+                    let rules = cache.entry(dir.to_path_buf()).or_insert_with(|| load_dupignore(dir));
+                    if let Some(m) = rules.as_ref().and_then(|r| r.matched(path, is_dir)) {
+                        ignored = Some(m);
+                    }
+                }
+            }
+        }
+        ignored == Some(true)
+    }
+}
+
+fn load_dupignore(dir: &Path) -> Option<RuleSet> {
+    let file = dir.join(DUPIGNORE);
+    let content = fs::read_to_string(&file).ok()?;
+    match RuleSet::parse(dir, content.lines()) {
+        Ok(rules) => Some(rules),
+        Err(e) => {
+            log::error!("{} is not used: {}", file.display(), e);
+            None
+        }
+    }
+}
@@ -0,0 +1,220 @@
+use clap::ValueEnum;
+use image::imageops::{self, FilterType};
+use image::GrayImage;
+
+use std::collections::{HashMap, HashSet};
+use std::f64::consts::PI;
+use std::path::Path;
+
+use crate::bktree::{hamming_distance, BkTree};
+use crate::datastore::{DataManager, FileInfo, ImageHash};
+use crate::settings::Settings;
+
+/// Files with these extensions get a perceptual hash when similar_images is on.
+pub const IMAGE_EXTENSIONS: [&str; 8] = ["jpg", "jpeg", "png", "gif", "webp", "bmp", "tif", "tiff"];
+pub const DEFAULT_MAX_DISTANCE: u32 = 10;
+
+/// Perceptual hash of an image: 64 bits that change little when the image is resized,
+/// recompressed or slightly edited.
+#[derive(ValueEnum, Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ImageHashKind {
+    /// pixels brighter than the average, fast but easily fooled by brightness changes
+    Ahash,
+    /// brightness gradients between neighbouring pixels
+    #[default]
+    Dhash,
+    /// low frequencies of a DCT, the most robust and the slowest
+    Phash,
+}
+
+impl ImageHashKind {
+    /// as stored in the `kind` column of image_hashes
+    pub fn name(&self) -> &'static str {
+        match self {
+            ImageHashKind::Ahash => "ahash",
+            ImageHashKind::Dhash => "dhash",
+            ImageHashKind::Phash => "phash",
+        }
+    }
+}
+
+pub fn is_image(path: &Path) -> bool {
+    path.extension().is_some_and(|e| IMAGE_EXTENSIONS.contains(&e.to_string_lossy().to_lowercase().as_str()))
+}
+
+/// Decodes the image at `path` (the format is taken from the content, not the extension) and hashes it.
+pub fn hash_image(path: &Path, kind: ImageHashKind) -> Result<u64, String> {
+    let image = image::io::Reader::open(path).map_err(|e| e.to_string())?
+        .with_guessed_format().map_err(|e| e.to_string())?
+        .decode().map_err(|e| e.to_string())?;
+    Ok(hash_pixels(&image.to_luma8(), kind))
+}
+
+pub fn hash_pixels(gray: &GrayImage, kind: ImageHashKind) -> u64 {
+    match kind {
+        ImageHashKind::Ahash => ahash(gray),
+        ImageHashKind::Dhash => dhash(gray),
+        ImageHashKind::Phash => phash(gray),
+    }
+}
+
+/// first bit is the most significant one
+fn to_bits(bits: impl Iterator<Item = bool>) -> u64 {
+    bits.fold(0, |hash, bit| (hash << 1) | bit as u64)
+}
+
+fn ahash(gray: &GrayImage) -> u64 {
+    let small = imageops::resize(gray, 8, 8, FilterType::Triangle);
+    let sum: u32 = small.pixels().map(|p| p.0[0] as u32).sum();
+    to_bits(small.pixels().map(|p| p.0[0] as u32 * 64 > sum))
+}
+
+fn dhash(gray: &GrayImage) -> u64 {
+    let small = imageops::resize(gray, 9, 8, FilterType::Triangle);
+    to_bits((0..8).flat_map(|y| (0..8).map(move |x| (x, y)))
+        .map(|(x, y)| small.get_pixel(x, y).0[0] < small.get_pixel(x + 1, y).0[0]))
+}
+
+fn phash(gray: &GrayImage) -> u64 {
+    const N: usize = 32;
+    let small = imageops::resize(gray, N as u32, N as u32, FilterType::Triangle);
+    let pixels: Vec<f64> = small.pixels().map(|p| p.0[0] as f64).collect();
+    // DCT-II, rows first, then columns; only the 8x8 lowest frequencies are needed
+    let cos: Vec<f64> = (0..8 * N).map(|i| ((2 * (i % N) + 1) as f64 * (i / N) as f64 * PI / (2 * N) as f64).cos()).collect();
+    let mut rows = vec![0.0; N * 8];
+    for y in 0..N {
+        for u in 0..8 {
+            rows[y * 8 + u] = (0..N).map(|x| pixels[y * N + x] * cos[u * N + x]).sum();
+        }
+    }
+    let mut coefficients = [0.0; 64];
+    for v in 0..8 {
+        for u in 0..8 {
+            coefficients[v * 8 + u] = (0..N).map(|y| rows[y * 8 + u] * cos[v * N + y]).sum();
+        }
+    }
+    // the first coefficient is the average brightness, it would skew the median
+    let mut sorted = coefficients[1..].to_vec();
+    sorted.sort_by(f64::total_cmp);
+    let median = sorted[sorted.len() / 2];
+    to_bits(coefficients.iter().map(|c| *c > median))
+}
+
+/// Stores the perceptual hash of `info` if it is an image and its hash is missing or older than the file.
+pub fn update(info: &FileInfo, kind: ImageHashKind, data_manager: &impl DataManager) {
+    let path = Path::new(&info.full_path);
+    if !is_image(path) {
+        return;
+    }
+    match data_manager.get_image_hash(&info.full_path, kind.name()) {
+        Ok(Some(existing)) if existing.last_modified >= info.last_modified => return,
+        Ok(_) => (),
+        Err(e) => {
+            log::error!("Unable to read image hash of {}: {:?}", info.full_path, e);
+            return;
+        }
+    }
+    match hash_image(path, kind) {
+        Ok(hash) => {
+            let entry = ImageHash { path: info.full_path.clone(), kind: kind.name().to_string(), hash, last_modified: info.last_modified };
+            data_manager.add_image_hash(&entry).unwrap_or_else(|e| log::error!("Unable to add image hash of {}: {:?}", info.full_path, e));
+        }
+        Err(e) => log::warn!(path = info.full_path.as_str(); "Unable to read image: {}", e),
+    }
+}
+
+/// Indexes of `hashes` grouped so that every hash of a group is within `max_distance` of at
+/// least one other hash of the group. Only groups of two or more, each sorted.
+pub fn cluster(hashes: &[u64], max_distance: u32) -> Vec<Vec<usize>> {
+    let mut tree = BkTree::new();
+    for (i, hash) in hashes.iter().enumerate() {
+        tree.insert(*hash, i);
+    }
+    let pairs = hashes.iter().enumerate()
+        .flat_map(|(i, hash)| tree.find(*hash, max_distance).into_iter().map(move |(j, _)| (i, j)));
+    group_pairs(hashes.len(), pairs)
+}
+
+/// Indexes 0..count grouped so that both indexes of each pair end up in the same group
+/// (union-find). Only groups of two or more, each sorted.
+pub fn group_pairs(count: usize, pairs: impl IntoIterator<Item = (usize, usize)>) -> Vec<Vec<usize>> {
+    let mut parent: Vec<usize> = (0..count).collect();
+    fn root(parent: &mut [usize], mut i: usize) -> usize {
+        while parent[i] != i {
+            parent[i] = parent[parent[i]];
+            i = parent[i];
+        }
+        i
+    }
+    for (i, j) in pairs {
+        let (a, b) = (root(&mut parent, i), root(&mut parent, j));
+        if a != b {
+            parent[a.max(b)] = a.min(b);
+        }
+    }
+    let mut groups: HashMap<usize, Vec<usize>> = HashMap::new();
+    for i in 0..count {
+        groups.entry(root(&mut parent, i)).or_default().push(i);
+    }
+    let mut groups: Vec<Vec<usize>> = groups.into_values().filter(|g| g.len() > 1).collect();
+    groups.sort();
+    groups
+}
+
+/// Keeps the `entries` whose file at `path` is still there. Rows of the others are removed.
+pub fn retain_existing<T>(entries: &mut Vec<T>, path: impl Fn(&T) -> &str, data_manager: &impl DataManager) {
+    entries.retain(|e| {
+        let exists = Path::new(path(e)).exists();
+        if !exists {
+            data_manager.delete_entry_for_path(path(e)).unwrap_or_default();
+        }
+        exists
+    });
+}
+
+/// Biggest groups first, then by the path of their first file.
+pub fn sort_groups(groups: &mut [SimilarGroup]) {
+    groups.sort_by(|a, b| b.files.len().cmp(&a.files.len()).then_with(|| a.files[0].path.cmp(&b.files[0].path)));
+}
+
+/// Images that look alike.
+#[derive(Debug, Serialize, PartialEq)]
+pub struct SimilarGroup {
+    /// sorted by path
+    pub files: Vec<SimilarFile>,
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct SimilarFile {
+    pub path: String,
+    /// bits in which its hash differs from the hash of the first file
+    pub distance: u32,
+}
+
+/// Groups of similar images among the hashes in the database, biggest groups first. Groups of
+/// bit-identical files are left out, those are plain duplicates. Rows of files that are gone
+/// are removed on the way.
+pub fn similar_groups(settings: &Settings, data_manager: &impl DataManager) -> Vec<SimilarGroup> {
+    let kind = settings.image_hash.unwrap_or_default();
+    let max_distance = settings.image_max_distance.unwrap_or(DEFAULT_MAX_DISTANCE);
+    let mut entries = data_manager.get_image_hashes(kind.name()).expect("Unable to read image hashes");
+    retain_existing(&mut entries, |e| &e.path, data_manager);
+    entries.sort_by(|a, b| a.path.cmp(&b.path));
+    let hashes: Vec<u64> = entries.iter().map(|e| e.hash).collect();
+    let mut groups: Vec<SimilarGroup> = cluster(&hashes, max_distance).into_iter().filter_map(|members| {
+        let contents: HashSet<String> = members.iter()
+            .filter_map(|i| data_manager.get_entry_for_path(&entries[*i].path).ok().flatten())
+            .map(|e| e.hash)
+            .collect();
+        if contents.len() <= 1 {
+            return None;
+        }
+        let first = entries[members[0]].hash;
+        Some(SimilarGroup {
+            files: members.iter().map(|i| SimilarFile { path: entries[*i].path.clone(), distance: hamming_distance(first, entries[*i].hash) }).collect(),
+        })
+    }).collect();
+    sort_groups(&mut groups);
+    groups
+}
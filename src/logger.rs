@@ -1,18 +1,253 @@
+use chrono::Local;
+use log::kv::{Key, Value, VisitSource};
+use log::{Level, LevelFilter, Log, Metadata, Record};
 
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{LazyLock, Mutex};
 
-pub struct Logger {
-    pub  output: Vec<String>,
+use crate::filters::parse_size;
+use crate::progress;
+use crate::settings::Settings;
+
+const DEFAULT_MAX_FILE_SIZE: u64 = 10 << 20;
+const DEFAULT_KEEP_FILES: usize = 5;
+
+/// How log lines are written.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogFormat {
+    /// `2023-07-01T12:00:00+02:00 INFO message key=value`
+    Text,
+    /// one JSON object per line: ts, level, target, msg and the key-values
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("Invalid log_format \"{}\", expected text or json", s)),
+        }
+    }
+}
+
+/// Where log lines go, built from the log settings.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogConfig {
+    pub level: LevelFilter,
+    pub format: LogFormat,
+    pub stderr: bool,
+    pub file: Option<PathBuf>,
+    /// the file is rotated (file.1, file.2...) when it would grow beyond this
+    pub max_file_size: u64,
+    /// rotated files kept next to the current one
+    pub keep_files: usize,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        LogConfig {
+            level: LevelFilter::Info,
+            format: LogFormat::Text,
+            stderr: true,
+            file: None,
+            max_file_size: DEFAULT_MAX_FILE_SIZE,
+            keep_files: DEFAULT_KEEP_FILES,
+        }
+    }
+}
+
+impl LogConfig {
+    pub fn from_settings(settings: &Settings) -> Result<Self, String> {
+        Ok(LogConfig {
+            level: match &settings.log_level {
+                Some(l) => l.parse().map_err(|_| format!("Invalid log_level \"{}\", expected error, warn, info or debug", l))?,
+                None => LevelFilter::Info,
+            },
+            format: settings.log_format.as_deref().map(str::parse).transpose()?.unwrap_or(LogFormat::Text),
+            stderr: settings.log_stderr.unwrap_or(true),
+            file: settings.log_file.as_ref().map(PathBuf::from),
+            max_file_size: settings.log_file_max_size.as_deref().map(parse_size).transpose()?.unwrap_or(DEFAULT_MAX_FILE_SIZE),
+            keep_files: settings.log_file_keep.unwrap_or(DEFAULT_KEEP_FILES),
+        })
+    }
+}
+
+struct Sinks {
+    config: LogConfig,
+    file: Option<File>,
+    file_size: u64,
+}
+
+/// The logger behind the `log` macros. Installed once by `init`, reconfigured by `configure`.
+struct Logger {
+    sinks: Mutex<Sinks>,
 }
 
-impl Logger {
-    pub fn new() -> Self {
-        Logger { output: vec![] }
+static LOGGER: LazyLock<Logger> = LazyLock::new(|| Logger {
+    sinks: Mutex::new(Sinks { config: LogConfig::default(), file: None, file_size: 0 }),
+});
+
+/// Installs the logger with the default configuration (info and above to stderr),
+/// so that anything before the config file is read gets logged too.
+pub fn init() {
+    if log::set_logger(&*LOGGER).is_ok() {
+        log::set_max_level(LevelFilter::Info);
     }
-    pub fn log(&mut self, text: String) {
-        self.output.push(text);
+}
+
+/// Switches to the log settings of `settings`. On error the previous configuration stays.
+pub fn configure(settings: &Settings, level_override: Option<LevelFilter>) -> Result<(), String> {
+    let mut config = LogConfig::from_settings(settings)?;
+    if let Some(level) = level_override {
+        config.level = level;
     }
-    pub fn dump(&self) -> String {
-        self.output.join("\n")
+    let mut sinks = LOGGER.sinks.lock().unwrap();
+    if sinks.config == config {
+        return Ok(());
     }
+    let (file, file_size) = match &config.file {
+        Some(path) => {
+            let file = open_log_file(path).map_err(|e| format!("Unable to open log file {}: {}", path.display(), e))?;
+            let size = file.metadata().map(|m| m.len()).unwrap_or(0);
+            (Some(file), size)
+        }
+        None => (None, 0),
+    };
+    log::set_max_level(config.level);
+    *sinks = Sinks { config, file, file_size };
+    Ok(())
+}
+
+fn open_log_file(path: &PathBuf) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
 }
 
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let mut sinks = self.sinks.lock().unwrap();
+        let line = format_record(record, sinks.config.format);
+        if sinks.config.stderr {
+            progress::clear_line();
+            let _ = io::stderr().write_all(line.as_bytes());
+        }
+        if sinks.file.is_some() {
+            sinks.write_file(line.as_bytes());
+        }
+    }
+
+    fn flush(&self) {
+        if let Some(file) = self.sinks.lock().unwrap().file.as_mut() {
+            let _ = file.flush();
+        }
+    }
+}
+
+impl Sinks {
+    fn write_file(&mut self, line: &[u8]) {
+        if self.file_size > 0 && self.file_size + line.len() as u64 > self.config.max_file_size {
+            if let Err(e) = self.rotate() {
+                let _ = writeln!(io::stderr(), "Unable to rotate log file: {}", e);
+            }
+        }
+        if let Some(file) = self.file.as_mut() {
+            if file.write_all(line).is_ok() {
+                self.file_size += line.len() as u64;
+            }
+        }
+    }
+
+    /// log -> log.1 -> log.2 ... the oldest one beyond keep_files is removed
+    fn rotate(&mut self) -> io::Result<()> {
+        let path = match &self.config.file {
+            Some(p) => p.clone(),
+            None => return Ok(()),
+        };
+        let rotated = |n: usize| PathBuf::from(format!("{}.{}", path.display(), n));
+        self.file = None;
+        if self.config.keep_files == 0 {
+            fs::remove_file(&path)?;
+        } else {
+            let _ = fs::remove_file(rotated(self.config.keep_files));
+            for n in (1..self.config.keep_files).rev() {
+                let from = rotated(n);
+                if from.exists() {
+                    fs::rename(&from, rotated(n + 1))?;
+                }
+            }
+            fs::rename(&path, rotated(1))?;
+        }
+        self.file = Some(open_log_file(&path)?);
+        self.file_size = 0;
+        Ok(())
+    }
+}
+
+pub fn format_record(record: &Record, format: LogFormat) -> String {
+    let mut fields = Fields(vec![]);
+    let _ = record.key_values().visit(&mut fields);
+    let ts = Local::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, false);
+    match format {
+        LogFormat::Text => {
+            let mut line = format!("{} {:<5} {}", ts, record.level(), record.args());
+            for (k, v) in &fields.0 {
+                match v {
+                    serde_json::Value::String(s) => line.push_str(&format!(" {}={}", k, s)),
+                    v => line.push_str(&format!(" {}={}", k, v)),
+                }
+            }
+            line + "\n"
+        }
+        LogFormat::Json => {
+            let mut object = serde_json::Map::new();
+            object.insert("ts".into(), ts.into());
+            object.insert("level".into(), level_name(record.level()).into());
+            object.insert("target".into(), record.target().into());
+            object.insert("msg".into(), record.args().to_string().into());
+            for (k, v) in fields.0 {
+                object.insert(k, v);
+            }
+            serde_json::Value::Object(object).to_string() + "\n"
+        }
+    }
+}
+
+fn level_name(level: Level) -> &'static str {
+    match level {
+        Level::Error => "error",
+        Level::Warn => "warn",
+        Level::Info => "info",
+        Level::Debug => "debug",
+        Level::Trace => "trace",
+    }
+}
+
+/// Key-values of a record; numbers and booleans stay typed in JSON.
+struct Fields(Vec<(String, serde_json::Value)>);
+
+impl<'kvs> VisitSource<'kvs> for Fields {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), log::kv::Error> {
+        let json = if let Some(n) = value.to_u64() {
+            n.into()
+        } else if let Some(n) = value.to_i64() {
+            n.into()
+        } else if let Some(b) = value.to_bool() {
+            b.into()
+        } else {
+            value.to_string().into()
+        };
+        self.0.push((key.to_string(), json));
+        Ok(())
+    }
+}
@@ -0,0 +1,147 @@
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::message::header::ContentType;
+use lettre::message::{Attachment as AttachmentPart, Mailbox, MultiPart, SinglePart};
+use lettre::{Message, SmtpTransport, Transport};
+
+use std::thread;
+use std::time::Duration;
+
+use crate::daemon;
+use crate::report::Attachment;
+use crate::report_events::ReportBody;
+use crate::settings::Settings;
+
+const DEFAULT_FROM: &str = "Duplicates <duplicates@localhost>";
+const DEFAULT_RETRIES: u32 = 3;
+/// delay before the first retry, doubled for every next one
+const FIRST_RETRY_DELAY: Duration = Duration::from_secs(5);
+const SMTP_TIMEOUT: Duration = Duration::from_secs(60);
+/// events shown in the body, the full list is attached beyond that
+const DEFAULT_MAX_EVENTS: usize = 200;
+
+/// How the connection to the SMTP server is secured.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EmailTls {
+    /// plain text, e.g. a relay on localhost
+    None,
+    /// plain connection upgraded with STARTTLS (port 587)
+    #[default]
+    Starttls,
+    /// TLS from the start (port 465)
+    Implicit,
+}
+
+/// Checks the sender and recipients, so that a bad address is reported when the config is read.
+pub fn validate(settings: &Settings) -> Result<(), String> {
+    sender(settings)?;
+    recipients(settings)?;
+    Ok(())
+}
+
+fn sender(settings: &Settings) -> Result<Mailbox, String> {
+    let from = settings.email_from.as_deref().unwrap_or(DEFAULT_FROM);
+    from.parse().map_err(|e| format!("Invalid email_from {}: {}", from, e))
+}
+
+fn recipients(settings: &Settings) -> Result<Vec<Mailbox>, String> {
+    settings.email_result_to.iter()
+        .map(|to| to.parse().map_err(|e| format!("Invalid recipient {}: {}", to, e)))
+        .collect()
+}
+
+/// Sends `body` to the `email_result_to` recipients as plain text and HTML, with `attachments`.
+/// A body with more than `email_max_events` events is cut short and the full list attached.
+/// Does nothing if no recipient is configured or there is nothing to report. Failed attempts
+/// are retried with a growing delay, unless the server rejected the email for good.
+pub fn send_report(settings: &Settings, body: &ReportBody, attachments: Vec<Attachment>) -> Result<(), String> {
+    if settings.email_result_to.is_empty() {
+        return Ok(());
+    }
+    if body.is_empty() {
+        log::debug!("Nothing to report, no email sent");
+        return Ok(());
+    }
+    let email = build_email(settings, body, attachments)?;
+    let mailer = transport(settings)?;
+
+    let retries = settings.email_retries.unwrap_or(DEFAULT_RETRIES);
+    let mut delay = FIRST_RETRY_DELAY;
+    let mut attempt = 0;
+    loop {
+        match mailer.send(&email) {
+            Ok(_) => {
+                log::info!(recipients = settings.email_result_to.join(", ").as_str(); "Email sent successfully!");
+                return Ok(());
+            }
+            Err(e) if e.is_permanent() || attempt >= retries => {
+                return Err(format!("Could not send email: {}", e));
+            }
+            Err(e) => {
+                attempt += 1;
+                log::warn!("Could not send email ({}), retry {} of {} in {} s", e, attempt, retries, delay.as_secs());
+                sleep(delay);
+                delay *= 2;
+            }
+        }
+    }
+}
+
+pub fn build_email(settings: &Settings, body: &ReportBody, mut attachments: Vec<Attachment>) -> Result<Message, String> {
+    let mut builder = Message::builder()
+        .from(sender(settings)?)
+        .subject("Duplicates report");
+    for to in recipients(settings)? {
+        builder = builder.to(to);
+    }
+    let max_events = settings.email_max_events.unwrap_or(DEFAULT_MAX_EVENTS);
+    if body.is_truncated(max_events) {
+        attachments.insert(0, Attachment {
+            filename: "duplicates-events.txt".to_string(),
+            content_type: "text/plain; charset=utf-8",
+            content: body.full_text(),
+        });
+    }
+    let alternative = MultiPart::alternative()
+        .singlepart(SinglePart::plain(body.text(max_events)))
+        .singlepart(SinglePart::html(body.html(max_events)));
+    if attachments.is_empty() {
+        return builder.multipart(alternative).map_err(|e| format!("Could not build email: {}", e));
+    }
+    let mut mixed = MultiPart::mixed().multipart(alternative);
+    for a in attachments {
+        let content_type = ContentType::parse(a.content_type).map_err(|e| format!("Invalid content type {}: {:?}", a.content_type, e))?;
+        mixed = mixed.singlepart(AttachmentPart::new(a.filename).body(a.content, content_type));
+    }
+    builder.multipart(mixed).map_err(|e| format!("Could not build email: {}", e))
+}
+
+fn transport(settings: &Settings) -> Result<SmtpTransport, String> {
+    let hostname = settings.email_hostname.as_deref().unwrap_or("localhost");
+    let mut builder = match settings.email_tls.unwrap_or_default() {
+        EmailTls::None => SmtpTransport::builder_dangerous(hostname),
+        EmailTls::Starttls => SmtpTransport::starttls_relay(hostname)
+            .map_err(|e| format!("Could not set up TLS for {}: {}", hostname, e))?,
+        EmailTls::Implicit => SmtpTransport::relay(hostname)
+            .map_err(|e| format!("Could not set up TLS for {}: {}", hostname, e))?,
+    };
+    if let Some(port) = settings.email_port {
+        builder = builder.port(port);
+    }
+    // no username: the server doesn't need authentication
+    if let Some(username) = &settings.email_username {
+        builder = builder.credentials(Credentials::new(username.clone(), settings.email_password.clone().unwrap_or_default()));
+    }
+    Ok(builder.timeout(Some(SMTP_TIMEOUT)).build())
+}
+
+/// Sleeps while keeping the systemd watchdog happy.
+fn sleep(duration: Duration) {
+    let tick = daemon::tick();
+    let mut slept = Duration::ZERO;
+    while slept < duration {
+        daemon::keepalive();
+        thread::sleep(tick.min(duration - slept));
+        slept += tick;
+    }
+}
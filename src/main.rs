@@ -1,29 +1,56 @@
 
 extern crate serde;
-extern crate notify;
 
 use sha2::{Sha512,Digest};
-use notify::{Watcher, RecursiveMode,RecommendedWatcher, DebouncedEvent};
 
-use lettre::transport::smtp::authentication::Credentials;
-use lettre::{Message, SmtpTransport, Transport};
-
-use std::sync::mpsc::channel;
-use std::time::{UNIX_EPOCH, Duration};
-use std::fs::File;
+use std::sync::mpsc::RecvTimeoutError;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::fs::{self, File};
 use std::path::{PathBuf, Path};
 use std::io;
-use std::env;
+use std::thread;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashSet};
 
 mod datastore;
 mod settings;
 mod file_manager;
 mod logger;
+mod report_events;
+mod hooks;
+mod watcher;
+mod daemon;
+mod cli;
+mod schedule;
+mod mailer;
+mod reconcile;
+mod ignore_rules;
+mod filters;
+mod report;
+mod stats;
+mod progress;
+mod bktree;
+mod image_hash;
+mod photo;
+mod content_hash;
+mod audio;
+mod video;
+mod directories;
+mod archive;
+mod cleanup;
+mod verify;
 
 use file_manager::*;
 use datastore::*;
 use settings::Settings;
-use logger::*;
+use report_events::{ReportBody, ReportEvent, ReportEvents};
+use log::{debug, error, info, warn};
+use stats::ScanStats;
+use progress::{Progress, ProgressMode};
+use watcher::DirWatcher;
+use schedule::Timer;
+use cli::{Cli, Command, DbCommand};
+use clap::Parser;
 
 #[macro_use]
 extern crate serde_derive;
@@ -31,7 +58,10 @@ extern crate serde_derive;
 #[cfg(test)]
 mod tests;
 
-fn get_file_info(path: &str, file_manager: &impl HandleFiles, data_manager: &impl DataManager) -> Option<FileInfo> {
+/// how often the progress of a scan is saved to the DB
+const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(5);
+
+fn get_file_info(path: &str, settings: &Settings, file_manager: &impl HandleFiles, data_manager: &impl DataManager, stats: &mut ScanStats) -> Option<FileInfo> {
     let srcdir = PathBuf::from(&path);
     let full_path = file_manager.get_full_path(&srcdir).expect("File could not be processed");
     
@@ -42,25 +72,38 @@ fn get_file_info(path: &str, file_manager: &impl HandleFiles, data_manager: &imp
     }
     let last_update_time = meta.modified().unwrap().duration_since(UNIX_EPOCH).unwrap().as_secs();
     let mut hash = String::from("");
+    let mut content_hash = None;
     let existing_entry = data_manager.get_entry_for_path(full_path.to_str().unwrap()).unwrap();
     let should_recalculate = match existing_entry {
         None => true,
         Some(v) => {
             hash = v.hash;
+            content_hash = v.content_hash;
             v.last_modified < last_update_time
             }
     };
+    // also when image_content_hash was turned on after the file was hashed
+    if content_hash::applies(&full_path, settings) && (should_recalculate || content_hash.is_none()) {
+        content_hash = Some(content_hash::content_hash(&full_path).unwrap_or_else(|| content_hash::NONE.to_string()));
+        stats.bytes_read += meta.len();
+    }
     if should_recalculate { 
        // print!("(re)calculating hash for file {}", path);
         hash = calculate_hash_for_file(&mut file) ;
-    } 
+        stats.files_hashed += 1;
+        stats.bytes_read += meta.len();
+    } else {
+        stats.files_cached += 1;
+    }
     let file_length = meta.len();
+    stats.bytes_scanned += file_length;
    
     Some(FileInfo {
         full_path : full_path.to_str().expect("Path could not be translated").to_string(),
         size : file_length,
-        hash : hash,
-        last_modified : last_update_time       
+        hash,
+        last_modified : last_update_time,
+        content_hash,
     })
 }
 fn calculate_hash_for_file(file: &mut File) -> String {    
@@ -78,102 +121,189 @@ fn calculate_hash_for_file(file: &mut File) -> String {
     // }    
     
     // hasher.result_str()
-    let _n = io::copy(file, &mut hasher);
+    let _n = io::copy(&mut daemon::KeepAlive(file), &mut hasher);
     format!("{:x}", hasher.finalize())
     
 }
 fn get_duplicates_for_hash(hash:&str, data_manager: &impl DataManager) -> Vec<FileInfo> {
-    let entries = data_manager.get_entries_by_hash(&hash).expect("get_entries failed");
+    let entries = data_manager.get_entries_by_hash(hash).expect("get_entries failed");
     let mut result: Vec<FileInfo> = Vec::new(); 
     for entry_to_test in entries.into_iter() {
-        if Path::new(&entry_to_test.full_path).exists() {
+        if archive::exists(&entry_to_test, data_manager) {
             result.push(entry_to_test);
         } else {
-            // file doesn't exist anymore: let's delete its data
+            // file (or the archive it was read from) doesn't exist anymore: let's delete its data
             data_manager.delete_entry_for_path(&entry_to_test.full_path).unwrap_or_default();
         }
     }
     result
 }
+/// Existing files whose images are the same apart from metadata.
+fn get_duplicates_for_content_hash(content_hash: &str, data_manager: &impl DataManager) -> Vec<FileInfo> {
+    let entries = data_manager.get_entries_by_content_hash(content_hash).expect("get_entries failed");
+    entries.into_iter().filter(|e| {
+        let exists = archive::exists(e, data_manager);
+        if !exists {
+            data_manager.delete_entry_for_path(&e.full_path).unwrap_or_default();
+        }
+        exists
+    }).collect()
+}
 /// Main logic
-fn process_file(path: &str,settings: &Settings,file_manager: &impl HandleFiles, data_manager: &impl DataManager, log: &mut Logger) {
-    match get_file_info(path, file_manager,data_manager) {
+fn process_file(path: &str,settings: &Settings,file_manager: &impl HandleFiles, data_manager: &impl DataManager, events: &mut ReportEvents) {
+    if !passes_filters(Path::new(path), settings) {
+        return;
+    }
+    events.stats.files_scanned += 1;
+    match get_file_info(path, settings, file_manager, data_manager, &mut events.stats) {
         Some(info) => {
             let mut file_already_added = false;
             let data_for_path = data_manager.get_entry_for_path(&info.full_path).expect("I assume None but not error!");
-            match data_for_path {
-                Some(d) => {
+            if let Some(d) = data_for_path {
+                
+                if d.hash != info.hash {
                     
-                    if d.hash != info.hash {
-                        
-                        println!("HASH changed for file : {} ! ", info.full_path);
-                        log.log(format!("HASH changed for file : {} ! ", info.full_path).to_string());
-                        data_manager.delete_entry_for_path(path).unwrap();               // current fileinfo will be added as new
-                    } else {
-                        file_already_added = true;
-                    }
+                    warn!(path = info.full_path.as_str(); "HASH changed for file : {} ! ", info.full_path);
+                    events.push(ReportEvent::HashChanged { path: info.full_path.clone() });
+                    data_manager.delete_entry_for_path(path).unwrap();               // current fileinfo will be added as new
+                } else if d.content_hash != info.content_hash {
+                    data_manager.delete_entry_for_path(path).unwrap();
+                } else {
+                    file_already_added = true;
                 }
-                None => ()
             }
                      
             if !file_already_added {
                 data_manager.add_entry(&info).expect("Unable to add entry to db");
             }
+            if settings.scan_archives {
+                archive::update(&info, !file_already_added, settings, data_manager);
+            }
+            if settings.similar_images || settings.exif_matching {
+                image_hash::update(&info, settings.image_hash.unwrap_or_default(), data_manager);
+            }
+            if settings.similar_videos {
+                video::update(&info, settings, data_manager);
+            }
 
-            let possible_duplicates = get_duplicates_for_hash(&info.hash, data_manager);
-            //println!("possible duplicates: {:?}", &possible_duplicates);
-            if possible_duplicates.len() >1
-            {
-                process_duplicates(&info, possible_duplicates, settings, file_manager,data_manager, log);    // new method for handling duplicates
+            if settings.exif_matching {
+                photo::update(&info, data_manager);
+            }
+            if settings.audio_fingerprint {
+                audio::update(&info, data_manager);
+            }
+            let possible_duplicates = find_copies(&info, settings, data_manager);
+            if possible_duplicates.len() > 1 {
+                match events.pending_duplicates.as_mut() {
+                    // the group is looked up again when the directories are done
+                    Some(pending) => pending.push(info),
+                    None => process_duplicates(&info, possible_duplicates, settings, file_manager, data_manager, events),
+                }
             }
            
         }
-        None => println!("File at path {} was not processed", path) 
+        None => debug!("File at path {} was not processed", path) 
     }     
 }
-fn get_duplicates_sorted_by_score(dups: &Vec<FileInfo>, settings: &Settings) -> Vec<String>{
-    let mut scoring_items : Vec<String> = settings.delete_score.to_vec();
-    scoring_items.reverse();
-    let mut scores: Vec<(String, i32)> = dups.into_iter().map(
-        |e| {
-            let mut v = 1;
-            let mut s = 0; //score
-            for i in &scoring_items {
-                if e.full_path.contains(i) {
-                    s += v;
-                }
-                v += 1;
+/// Existing files with the content of `info`: the same hash, or the same content hash, EXIF
+/// identity or recording when those are on. `info` itself included, sorted by path.
+fn find_copies(info: &FileInfo, settings: &Settings, data_manager: &impl DataManager) -> Vec<FileInfo> {
+    let mut possible_duplicates = get_duplicates_for_hash(&info.hash, data_manager);
+    let mut copies = vec![];
+    if let Some(content_hash) = content_hash::of(info, settings) {
+        copies.extend(get_duplicates_for_content_hash(content_hash, data_manager));
+    }
+    if settings.exif_matching {
+        copies.extend(photo::matching(info, settings, data_manager));
+    }
+    if settings.audio_fingerprint_delete {
+        copies.extend(audio::matching(info, settings, data_manager));
+    }
+    if !copies.is_empty() {
+        for copy in copies {
+            if !possible_duplicates.iter().any(|d| d.full_path == copy.full_path) {
+                possible_duplicates.push(copy);
             }
-            (e.full_path.clone(), s)
-        } ).collect();
+        }
+        // the same first file whichever copy is processed, it names the group in the stats
+        possible_duplicates.sort_by(|a, b| a.full_path.cmp(&b.full_path));
+    }
+    possible_duplicates
+}
+/// Size, extension and age filters, checked before anything is hashed. With `scan_archives`
+/// archives always pass, the filters apply to their members (see `archive::update`).
+fn passes_filters(path: &Path, settings: &Settings) -> bool {
+    match fs::metadata(path) {
+        Ok(meta) => {
+            meta.is_dir()
+                || (settings.scan_archives && archive::is_archive(path))
+                || settings.file_filter().accepts(path, &meta)
+        }
+        Err(_) => true, // get_file_info reports it
+    }
+}
+/// Paths of `dups`, the one to keep last. Of copies that can differ, the best one is kept
+/// (see `copy_quality`); delete_score decides between equal ones. Files inside archives are
+/// never deleted: they are left out, or with archive_policy = "delete_files" one of them is kept.
+fn get_duplicates_sorted_by_score(dups: &[FileInfo], settings: &Settings) -> Vec<String>{
+    let (archived, dups): (Vec<&FileInfo>, Vec<&FileInfo>) = dups.iter().partition(|e| archive::is_member(&e.full_path));
+    let mut scores: Vec<(String, i32, (u64, usize))> = dups.iter().map(
+        |e| (e.full_path.clone(), delete_score(&e.full_path, settings), copy_quality(Path::new(&e.full_path), settings))
+    ).collect();
     
-    scores.sort_by_key(|i| i.1);
+    scores.sort_by_key(|i| (Reverse(i.2), i.1));
     scores.reverse();
 
     //println!("duplicates with score: {:?}", &scores);
 
-    let just_filenames : Vec<String>= scores.into_iter().map( |s| s.0).collect();
+    let mut just_filenames : Vec<String>= scores.into_iter().map( |s| s.0).collect();
+    if settings.archive_policy.unwrap_or_default() == archive::ArchivePolicy::DeleteFiles {
+        just_filenames.extend(archived.first().map(|a| a.full_path.clone()));
+    }
 
     just_filenames
 }
-fn mark_for_deletion(filenames: Vec<String>, log: &mut Logger) {
+/// Higher is more likely deleted: the earlier an item of delete_score that `path` contains is on the list, the more it adds.
+fn delete_score(path: &str, settings: &Settings) -> i32 {
+    let mut scoring_items : Vec<String> = settings.delete_score.to_vec();
+    scoring_items.reverse();
+    let mut s = 0; //score
+    for (v, i) in (1..).zip(scoring_items.iter()) {
+        if path.contains(i) {
+            s += v;
+        }
+    }
+    s
+}
+/// Higher is better: with exif_matching the photo with the most pixels, then the most EXIF fields;
+/// when tags are ignored or recordings compared, the biggest audio file (more tags, higher bitrate).
+fn copy_quality(path: &Path, settings: &Settings) -> (u64, usize) {
+    if settings.exif_matching && image_hash::is_image(path) {
+        photo::quality(path)
+    } else if (settings.audio_content_hash || settings.audio_fingerprint) && audio::is_audio(path) {
+        (fs::metadata(path).map(|m| m.len()).unwrap_or(0), 0)
+    } else {
+        (0, 0)
+    }
+}
+fn mark_for_deletion(filenames: Vec<String>, events: &mut ReportEvents) {
     if filenames.len() <= 1 {
         return;
     }
     let mut i = 0;
-    println!("Duplicates found:");
-    log.log("Duplicate found:".to_string());
+    info!("Duplicates found:");
     while i < filenames.len() -1 {// -1 is crucial as we don't want to delete every occurence
-        println!("DELETE: {}", &filenames[i]);
-        log.log(format!("DELETE: {}", &filenames[i]).to_string());
+        info!(path = filenames[i].as_str(), action = "delete"; "DELETE: {}", &filenames[i]);
         i+= 1;
     }
-    log.log(format!("LEAVE: {}" , &filenames.last().unwrap() ).to_string());
-    println!("LEAVE: {}" , &filenames.last().unwrap() );
+    let keep = filenames.last().unwrap();
+    info!(path = keep.as_str(), action = "keep"; "LEAVE: {}" , keep);
+    events.push(ReportEvent::Duplicates { delete: filenames[..i].to_vec(), keep: keep.clone(), deleted: false });
 }
-fn delete(filenames: Vec<String>, file_manager: &impl HandleFiles, data_manager: &impl DataManager, log: &mut Logger) {
+/// Deletes all but the last of `filenames`. Returns how many files were deleted.
+fn delete(filenames: Vec<String>, file_manager: &impl HandleFiles, data_manager: &impl DataManager, events: &mut ReportEvents) -> u64 {
     if filenames.len() <= 1 {
-        return;
+        return 0;
     }
     let mut i = 0;
     let mut items : Vec<String> = vec![];
@@ -183,31 +313,40 @@ fn delete(filenames: Vec<String>, file_manager: &impl HandleFiles, data_manager:
             items.push(i.clone());
         }
     }
-    log.log("Duplicate found:".to_string());
-    println!("Duplicates found:");
+    info!("Duplicates found:");
     while i < items.len() -1 {// -1 is crucial as we don't want to delete every occurence        
-        log.log(format!("DELETE: {}", &items[i]).to_string());
-        println!("DELETE: {}", &items[i]);
+        info!(path = items[i].as_str(), action = "delete"; "DELETE: {}", &items[i]);
         file_manager.remove_file(&items[i]).unwrap();
         data_manager.delete_entry_for_path(&items[i]).unwrap();        
+        events.deleted_from.extend(Path::new(&items[i]).parent().map(Path::to_path_buf));
         i+= 1;
     }
-    log.log(format!("LEAVE: {}" , &items.last().unwrap() ).to_string());
-    println!("LEAVE: {}" , &items.last().unwrap() );
+    let keep = items.last().unwrap();
+    info!(path = keep.as_str(), action = "keep"; "LEAVE: {}" , keep);
+    events.push(ReportEvent::Duplicates { delete: items[..i].to_vec(), keep: keep.clone(), deleted: true });
+    (items.len() - 1) as u64
 }
-fn process_duplicates(info: &FileInfo, dups: Vec<FileInfo>, settings: &Settings, file_manager: &impl HandleFiles, data_manager: &impl DataManager, log: &mut Logger) {
+fn process_duplicates(info: &FileInfo, dups: Vec<FileInfo>, settings: &Settings, file_manager: &impl HandleFiles, data_manager: &impl DataManager, events: &mut ReportEvents) {
     let d = get_duplicates_sorted_by_score(&dups, settings);
+    // files in archives only count when one of them is kept
+    let handled: Vec<FileInfo> = dups.iter().filter(|f| d.contains(&f.full_path)).cloned().collect();
+    events.stats.record_duplicates(&handled);
     match settings.action.as_str() {
-        "D" => delete(d, file_manager,data_manager, log), 
-        "T" => mark_for_deletion(d, log),
-        "S" => { mark_for_deletion(d, log); std::process::exit(1); }
+        "D" => {
+            let keep = d.last().cloned();
+            if delete(d, file_manager,data_manager, events) > 0 {
+                // copies matched by exif_matching can differ in size
+                events.stats.bytes_freed += handled.iter().filter(|f| Some(&f.full_path) != keep.as_ref()).map(|f| f.size).sum::<u64>();
+            }
+        }
+        "T" => mark_for_deletion(d, events),
+        "S" => { mark_for_deletion(d, events); std::process::exit(1); }
         _ => {  // default action - write about hashes
             for dup_info in dups.iter() {
-                if info.full_path != dup_info.full_path {
-                    if info.hash == dup_info.hash && info.size == dup_info.size {
-                        println!("Hashes are the same for files : {} and {} ! ", info.full_path, dup_info.full_path);
-                        log.log(format!("Hashes are the same for files : {} and {} ! ", info.full_path, dup_info.full_path).to_string());
-                    }     
+                if info.full_path != dup_info.full_path
+                    && info.hash == dup_info.hash && info.size == dup_info.size {
+                    info!("Hashes are the same for files : {} and {} ! ", info.full_path, dup_info.full_path);
+                    events.push(ReportEvent::SameHash { path: info.full_path.clone(), other: dup_info.full_path.clone() });
                 }
             }      
         }
@@ -215,122 +354,639 @@ fn process_duplicates(info: &FileInfo, dups: Vec<FileInfo>, settings: &Settings,
     
     
 }
-fn notify_changes( settings: &Settings,file_manager: &impl HandleFiles, data_manager: &impl DataManager, log: &mut Logger) {    
-    let (tx, rx) = channel();
-    let mut watcher: RecommendedWatcher = Watcher::new(tx, Duration::from_secs(2)).unwrap();
-    watcher.watch(&settings.working_dir, RecursiveMode::Recursive).unwrap_or_default();
-    loop {
-        match rx.recv() {
-            Ok(event) => {
-                match event {
-                    DebouncedEvent::Write(p) => process_file_check_ignore(&p, settings, file_manager,data_manager, log),
-                    DebouncedEvent::Create(p) => process_file_check_ignore(&p, settings, file_manager,data_manager, log), 
-                    _ =>  (),//println!("{:?}", event)
-                } 
-                
-            },
-            Err(e) => println!("watch error: {:?}", e),
+/// Watches working_dir until a shutdown is requested. On SIGHUP the config file is read again
+/// and the watches are rebuilt for the new settings.
+fn notify_changes( settings: &mut Settings, cli: &Cli, file_manager: &impl HandleFiles, data_manager: &impl DataManager, events: &mut ReportEvents) {    
+    'reload: loop {
+        let (mut watcher, rx) = match DirWatcher::new(settings) {
+            Ok(w) => w,
+            Err(e) => {
+                error!("Unable to start watching {}: {:?}", settings.working_dir, e);
+                return;
+            }
+        };
+        let ignore = |p: &Path| should_ignore_path(p, settings, file_manager);
+        let root = Path::new(&settings.working_dir);
+        watcher.watch_tree(root, &settings.walk_options(root), file_manager, ignore);
+        let mut rescan_timer = Timer::from_setting(&settings.rescan_schedule);
+        let mut digest_timer = Timer::from_setting(&settings.digest_schedule);
+        daemon::ready(&format!("Watching {}", settings.working_dir));
+        loop {
+            daemon::keepalive();
+            if daemon::shutdown_requested() {
+                return;
+            }
+            if rescan_timer.as_mut().is_some_and(|t| t.due()) {
+                info!("Scheduled rescan of {}", settings.working_dir);
+                let pruned = reconcile::prune(settings, true, file_manager, data_manager);
+                if pruned.cleaned() > 0 {
+                    info!("Database pruned: {}", pruned);
+                    events.push(ReportEvent::DatabasePruned { stats: pruned.to_string() });
+                }
+                process_path(settings, false, file_manager, data_manager, events);
+            }
+            if digest_timer.as_mut().is_some_and(|t| t.due()) && !events.is_empty() {
+                send_notifications(settings, events.take(), data_manager);
+            }
+            if daemon::take_reload_request() {
+                daemon::reloading();
+                match cli.load_settings() {
+                    Ok(new_settings) => {
+                        configure_logging(&new_settings, cli);
+                        info!("Configuration reloaded from {}", cli.config);
+                        let rescan = new_settings.working_dir != settings.working_dir;
+                        *settings = new_settings;
+                        if rescan {
+                            process_path(settings, false, file_manager, data_manager, events);
+                        }
+                        continue 'reload;
+                    }
+                    Err(e) => {
+                        error!("Unable to reload {}, keeping previous configuration: {:?}", cli.config, e);
+                        daemon::ready(&format!("Watching {}", settings.working_dir));
+                    }
+                }
+            }
+            match rx.recv_timeout(daemon::tick()) {
+                Ok(Ok(changes)) => {
+                    for p in watcher::changed_paths(changes) {
+                        if p.file_name().is_some_and(|n| n == ignore_rules::DUPIGNORE) {
+                            if let Some(dir) = p.parent() {
+                                settings.ignore_rules().forget_dir(dir);
+                            }
+                            continue;
+                        }
+                        if p.is_dir() {
+                            // directories created (or moved in) after start need their own watches
+                            if !watcher.is_watched(&p) && is_walked(&p, settings) && !ignore(&p) {
+                                watcher.watch_tree(&p, &settings.walk_options(&p), file_manager, ignore);
+                                process_dir(&p, settings, file_manager, data_manager, events);
+                            }
+                        } else if p.exists() {
+                            process_file_check_ignore(&p, settings, file_manager,data_manager, events);
+                        }
+                    }
+                    remove_empty_dirs(settings, file_manager, events);
+                },
+                Ok(Err(errors)) => watcher.handle_errors(errors),
+                Err(RecvTimeoutError::Timeout) => (),
+                Err(RecvTimeoutError::Disconnected) => {
+                    error!("watch error: watcher stopped");
+                    return;
+                }
+            }
         }
     }
 }
-fn process_file_check_ignore(path_buf: &PathBuf, settings: &Settings, file_manager: &impl HandleFiles, data_manager: &impl DataManager, log: &mut Logger) {
+fn process_file_check_ignore(path_buf: &Path, settings: &Settings, file_manager: &impl HandleFiles, data_manager: &impl DataManager, events: &mut ReportEvents) {
+    if !is_walked(path_buf, settings) {
+        return;
+    }
     if !should_ignore_path(path_buf, settings,file_manager) {
-        let f_path = file_manager.get_full_path(&path_buf);
-        if f_path.is_ok() {
-        let full_path = f_path.unwrap();
+        if let Ok(full_path) = file_manager.get_full_path(path_buf) {
         let s_path = full_path.to_str().unwrap();
-        process_file(s_path,settings, file_manager,data_manager, log);
+        process_file(s_path,settings, file_manager,data_manager, events);
         }
     }
 }
-fn should_ignore_path(path_buf: &PathBuf, settings: &Settings, file_manager: &impl HandleFiles) -> bool{
-    match file_manager.get_full_path(&path_buf) {
-        Ok(full_path) => {
-            let s_path = String::from(full_path.to_str().unwrap());
-            for s in &settings.ignore_paths {
-                if s_path.contains(s) {
-                    return true;
-                }
+/// Whether a walk of working_dir with the walk settings would reach `path`.
+/// Used for paths reported by the watcher.
+fn is_walked(path: &Path, settings: &Settings) -> bool {
+    if !settings.follow_symlinks && path.is_symlink() {
+        return false;
+    }
+    let relative = match path.strip_prefix(&settings.working_dir) {
+        Ok(r) => r,
+        Err(_) => return true,
+    };
+    if settings.max_depth.is_some_and(|max| relative.components().count() > max) {
+        return false;
+    }
+    !(settings.skip_hidden && relative.components().any(|c| c.as_os_str().to_string_lossy().starts_with('.')))
+}
+fn should_ignore_path(path_buf: &Path, settings: &Settings, file_manager: &impl HandleFiles) -> bool{
+    match file_manager.get_full_path(path_buf) {
+        Ok(full_path) => settings.ignore_rules().is_ignored(&full_path, full_path.is_dir()),
+        Err(e) => {
+            warn!("should ignore path err {:?}", e);
+            true
+        }
+    }
+}
+/// Scans working_dir. With `resume`, an unfinished scan of working_dir continues from its
+/// last checkpoint instead of starting over.
+fn process_path( settings: &Settings, resume: bool, file_manager: &impl HandleFiles, data_manager: &impl DataManager, events: &mut ReportEvents) {
+    let root = settings.working_dir.as_str();
+    let resumable = if resume {
+        data_manager.get_resumable_scan_run(root).unwrap_or_else(|e| {
+            error!("Unable to read previous scan runs: {:?}", e);
+            None
+        })
+    } else {
+        None
+    };
+    let mut run = match resumable {
+        Some(run) => {
+            info!("Resuming scan {} of {} from {}", run.id, root, run.last_dir.as_deref().unwrap_or(root));
+            run
+        }
+        None => {
+            if resume {
+                info!("No unfinished scan of {} found, starting a new one", root);
             }
-        },
+            data_manager.start_scan_run(root, unix_now()).expect("Unable to record scan run")
+        }
+    };
+    run.status = "running".to_string();
+    events.stats = ScanStats::resuming(run.summary.clone(), std::mem::take(&mut run.copies));
+    let progress = Progress::new(ProgressMode::for_settings(settings));
+    let checkpoint = run.last_dir.clone().map(PathBuf::from);
+    if settings.directory_duplicates {
+        events.pending_duplicates = Some(vec![]);
+    }
+    // a resumed scan did this when it started
+    if let (Some(policy), None) = (settings.existing_empty, &checkpoint) {
+        process_existing_empty(policy, settings, file_manager, data_manager, events);
+    }
+    let completed = thread::scope(|scope| {
+        if !progress.is_off() {
+            scope.spawn(|| progress.count(Path::new(root), settings, checkpoint.as_deref()));
+        }
+        let completed = walk_and_process(Path::new(root), settings, file_manager, data_manager, events, Some(&mut run), Some(&progress));
+        progress.finish();
+        completed
+    });
+    if let Some(pending) = events.pending_duplicates.take() {
+        // an interrupted scan did not see all files, directories would look incomplete
+        let removed = if completed { process_directories(root, settings, file_manager, data_manager, events) } else { vec![] };
+        process_pending_duplicates(pending, &removed, settings, file_manager, data_manager, events);
+    }
+    if completed && settings.scan_archives && settings.archive_policy == Some(archive::ArchivePolicy::DeleteArchives) {
+        process_archives(settings, file_manager, data_manager, events);
+    }
+    remove_empty_dirs(settings, file_manager, events);
+    run.summary = events.stats.summary();
+    run.copies = events.stats.take_unsaved();
+    let s = &run.summary;
+    info!(files_scanned = s.files_scanned, files_hashed = s.files_hashed, files_cached = s.files_cached,
+          bytes_read = s.bytes_read, duplicate_groups = s.duplicate_groups, redundant_copies = s.redundant_copies,
+          bytes_reclaimable = s.bytes_reclaimable, bytes_freed = s.bytes_freed, elapsed_secs = s.elapsed_secs;
+          "Scan of {} done", root);
+    // they take a while, a shutdown doesn't wait for them; the resumed scan reports them
+    if completed && !daemon::shutdown_requested() {
+        if settings.similar_images {
+            report_similar_images(settings, data_manager, events);
+        }
+        if settings.similar_videos {
+            report_similar_videos(settings, data_manager, events);
+        }
+        // with similar_images they are among the similar images already
+        if settings.exif_matching && !settings.similar_images {
+            report_similar_photos(settings, data_manager, events);
+        }
+        if settings.audio_fingerprint && !settings.audio_fingerprint_delete {
+            report_similar_audio(settings, data_manager, events);
+        }
+    }
+    events.push(ReportEvent::ScanFinished(run.summary.clone()));
+    if completed {
+        run.status = "finished".to_string();
+        run.finished_at = Some(unix_now());
+    } else {
+        run.status = "interrupted".to_string();
+        warn!("Scan {} interrupted, continue it with: duplicates scan --resume", run.id);
+    }
+    data_manager.update_scan_run(&run).unwrap_or_else(|e| error!("Unable to save scan run: {:?}", e));
+}
+/// Whether the cleanup of empty files and directories has to leave `path` alone.
+fn is_kept(path: &Path, is_dir: bool, settings: &Settings) -> bool {
+    settings.ignore_rules().is_ignored(path, is_dir) || settings.protected_rules().is_ignored(path, is_dir)
+}
+/// With remove_empty_dirs, removes the directories that files were deleted from since the last
+/// call if they are empty now, and their parents that are empty then.
+fn remove_empty_dirs(settings: &Settings, file_manager: &impl HandleFiles, events: &mut ReportEvents) {
+    let deleted_from = std::mem::take(&mut events.deleted_from);
+    if !settings.remove_empty_dirs || deleted_from.is_empty() {
+        return;
+    }
+    let Ok(root) = file_manager.get_full_path(Path::new(&settings.working_dir)) else {
+        return;
+    };
+    let removed = cleanup::remove_emptied(&deleted_from, &root, settings.remove_empty_dirs_levels, |p, d| is_kept(p, d, settings), file_manager);
+    for path in &removed {
+        info!(path = path.as_str(), action = "delete"; "Removed empty directory: {}", path);
+    }
+    if !removed.is_empty() {
+        events.push(ReportEvent::EmptyDirectoriesRemoved { paths: removed });
+    }
+}
+/// Lists the zero-byte files and empty directories in working_dir, and deletes them with
+/// existing_empty = "delete" and action D.
+fn process_existing_empty(policy: cleanup::EmptyPolicy, settings: &Settings, file_manager: &impl HandleFiles, data_manager: &impl DataManager, events: &mut ReportEvents) {
+    let Ok(root) = file_manager.get_full_path(Path::new(&settings.working_dir)) else {
+        return;
+    };
+    let (files, directories) = cleanup::find_empty(&root, &settings.walk_options(&root), |p, d| is_kept(p, d, settings), file_manager);
+    if files.is_empty() && directories.is_empty() {
+        return;
+    }
+    if policy != cleanup::EmptyPolicy::Delete || settings.action != "D" {
+        for path in files.iter().chain(&directories) {
+            info!(path = path.as_str(); "EMPTY: {}", path);
+        }
+        events.push(ReportEvent::ExistingEmpty { files, directories, deleted: false });
+        return;
+    }
+    let removed = |path: &String, result: io::Result<()>| match result {
+        Ok(()) => {
+            info!(path = path.as_str(), action = "delete"; "DELETE empty: {}", path);
+            true
+        }
         Err(e) => {
-            println!("should ignore path err {:?}", e);
-            return true; 
+            error!(path = path.as_str(); "Unable to delete: {}", e);
+            false
         }
+    };
+    let files: Vec<String> = files.into_iter().filter(|f| removed(f, file_manager.remove_file(f))).collect();
+    for file in &files {
+        data_manager.delete_entry_for_path(file).unwrap_or_default();
     }
-   
-    false
+    let directories: Vec<String> = directories.into_iter().filter(|d| removed(d, file_manager.remove_empty_dir(d))).collect();
+    events.deleted_from.extend(files.iter().chain(&directories).filter_map(|p| Path::new(p).parent()).map(Path::to_path_buf));
+    events.push(ReportEvent::ExistingEmpty { files, directories, deleted: true });
 }
-fn process_path( settings: &Settings, file_manager: &impl HandleFiles, data_manager: &impl DataManager, log: &mut Logger) {
-    
-    'filewalker: for entry in file_manager.walkdir(&settings.working_dir).filter_map(|e| e.ok()) {
+/// Finds directories with the same files as another one, or whose files are all in another one,
+/// and handles each as a whole like duplicate files. Returns the directories deleted or marked for deletion.
+fn process_directories(root: &str, settings: &Settings, file_manager: &impl HandleFiles, data_manager: &impl DataManager, events: &mut ReportEvents) -> Vec<String> {
+    let Ok(root) = file_manager.get_full_path(Path::new(root)) else {
+        return vec![];
+    };
+    let mut entries = data_manager.get_all_entries().expect("Unable to read hash database");
+    // a directory is only a copy of files that are still there
+    entries.retain(|e| Path::new(&e.full_path).exists());
+    let tree = directories::directory_tree(&root, &entries);
+    let mut removed = vec![];
+    for dups in directories::find_duplicates(&tree, |path| delete_score(path, settings)) {
+        // the deleted directories first, their size is what can be freed
+        let group: Vec<FileInfo> = dups.delete.iter().chain([&dups.keep]).map(|path| FileInfo {
+            full_path: path.clone(), size: dups.size, hash: format!("dir:{}", dups.hash), last_modified: 0, content_hash: None,
+        }).collect();
+        events.stats.record_duplicates(&group);
+        info!("{}", if dups.subset { "Directory contained in another one:" } else { "Duplicate directories:" });
+        match settings.action.as_str() {
+            "D" => {
+                let mut deleted = vec![];
+                for directory in &dups.delete {
+                    info!(path = directory.as_str(), action = "delete"; "DELETE: {}", directory);
+                    match file_manager.remove_dir_all(directory) {
+                        Ok(()) => {
+                            for entry in entries.iter().filter(|e| Path::new(&e.full_path).starts_with(directory)) {
+                                data_manager.delete_entry_for_path(&entry.full_path).unwrap();
+                            }
+                            events.stats.bytes_freed += dups.size;
+                            events.deleted_from.extend(Path::new(directory).parent().map(Path::to_path_buf));
+                            deleted.push(directory.clone());
+                        }
+                        Err(e) => error!(path = directory.as_str(); "Unable to delete directory: {}", e),
+                    }
+                }
+                info!(path = dups.keep.as_str(), action = "keep"; "LEAVE: {}", dups.keep);
+                removed.extend(deleted.iter().cloned());
+                events.push(ReportEvent::DuplicateDirectories { delete: deleted, keep: dups.keep, subset: dups.subset, deleted: true });
+            }
+            "T" | "S" => {
+                for directory in &dups.delete {
+                    info!(path = directory.as_str(), action = "delete"; "DELETE: {}", directory);
+                }
+                info!(path = dups.keep.as_str(), action = "keep"; "LEAVE: {}", dups.keep);
+                removed.extend(dups.delete.iter().cloned());
+                events.push(ReportEvent::DuplicateDirectories { delete: dups.delete, keep: dups.keep, subset: dups.subset, deleted: false });
+                if settings.action == "S" {
+                    std::process::exit(1);
+                }
+            }
+            _ => info!("Directories {} and {} hold the same files", dups.delete.join(", "), dups.keep),
+        }
+    }
+    removed
+}
+/// Handles the duplicates of the files put off while directories were looked at, apart from files
+/// in `removed` directories. Each group is looked up once, for the last file of it that was found.
+fn process_pending_duplicates(pending: Vec<FileInfo>, removed: &[String], settings: &Settings, file_manager: &impl HandleFiles, data_manager: &impl DataManager, events: &mut ReportEvents) {
+    let in_removed = |path: &str| removed.iter().any(|d| Path::new(path).starts_with(d));
+    let mut done: HashSet<String> = HashSet::new();
+    for info in pending.into_iter().rev() {
+        if done.contains(&info.full_path) || in_removed(&info.full_path) {
+            continue;
+        }
+        let dups: Vec<FileInfo> = find_copies(&info, settings, data_manager).into_iter()
+            .filter(|d| !in_removed(&d.full_path) && Path::new(&d.full_path).exists())
+            .collect();
+        done.extend(dups.iter().map(|d| d.full_path.clone()));
+        if dups.len() > 1 {
+            process_duplicates(&info, dups, settings, file_manager, data_manager, events);
+        }
+    }
+}
+/// Archives whose every file is also on disk, outside of archives, are deleted (or marked for
+/// deletion) like duplicates.
+fn process_archives(settings: &Settings, file_manager: &impl HandleFiles, data_manager: &impl DataManager, events: &mut ReportEvents) {
+    let entries = data_manager.get_all_entries().expect("Unable to read hash database");
+    let on_disk: HashSet<&str> = entries.iter()
+        .filter(|e| !archive::is_member(&e.full_path) && Path::new(&e.full_path).exists())
+        .map(|e| e.hash.as_str())
+        .collect();
+    let mut members: BTreeMap<&str, Vec<&FileInfo>> = BTreeMap::new();
+    for entry in entries.iter().filter(|e| archive::is_member(&e.full_path)) {
+        members.entry(archive::container(&entry.full_path)).or_default().push(entry);
+    }
+    for entry in entries.iter().filter(|e| !archive::is_member(&e.full_path) && archive::is_archive(Path::new(&e.full_path))) {
+        let Some(inside) = members.get(entry.full_path.as_str()) else {
+            continue;
+        };
+        if !Path::new(&entry.full_path).exists() || !inside.iter().all(|m| on_disk.contains(m.hash.as_str())) {
+            continue;
+        }
+        // files the filters left out are not in the database, every file of the archive has to be on disk
+        let files = match archive::read_members(Path::new(&entry.full_path)) {
+            Ok(all) if !all.is_empty() && all.iter().all(|m| on_disk.contains(m.hash.as_str())) => all.len(),
+            Ok(_) => continue,
+            Err(e) => {
+                warn!(path = entry.full_path.as_str(); "Unable to read archive: {}", e);
+                continue;
+            }
+        };
+        info!(path = entry.full_path.as_str(), action = "delete"; "Archive whose {} files are all on disk too: {}", files, entry.full_path);
+        match settings.action.as_str() {
+            "D" => match file_manager.remove_file(&entry.full_path) {
+                Ok(()) => {
+                    data_manager.delete_entry_for_path(&entry.full_path).unwrap();
+                    events.stats.bytes_freed += entry.size;
+                    events.deleted_from.extend(Path::new(&entry.full_path).parent().map(Path::to_path_buf));
+                    events.push(ReportEvent::RedundantArchive { path: entry.full_path.clone(), files, deleted: true });
+                }
+                Err(e) => error!(path = entry.full_path.as_str(); "Unable to delete archive: {}", e),
+            },
+            "T" | "S" => {
+                events.push(ReportEvent::RedundantArchive { path: entry.full_path.clone(), files, deleted: false });
+                if settings.action == "S" {
+                    std::process::exit(1);
+                }
+            }
+            _ => (),
+        }
+    }
+}
+fn report_similar_images(settings: &Settings, data_manager: &impl DataManager, events: &mut ReportEvents) {
+    for group in image_hash::similar_groups(settings, data_manager) {
+        let files: Vec<String> = group.files.into_iter().map(|f| f.path).collect();
+        info!("Similar images: {}", files.join(", "));
+        events.push(ReportEvent::SimilarImages { files });
+    }
+}
+fn report_similar_videos(settings: &Settings, data_manager: &impl DataManager, events: &mut ReportEvents) {
+    for group in video::similar_groups(settings, data_manager) {
+        let files: Vec<String> = group.files.into_iter().map(|f| f.path).collect();
+        info!("Similar videos: {}", files.join(", "));
+        events.push(ReportEvent::SimilarVideos { files });
+    }
+}
+fn report_similar_photos(settings: &Settings, data_manager: &impl DataManager, events: &mut ReportEvents) {
+    for group in photo::similar_groups(settings, data_manager) {
+        let files: Vec<String> = group.files.into_iter().map(|f| f.path).collect();
+        info!("Photos taken at the same time: {}", files.join(", "));
+        events.push(ReportEvent::SimilarImages { files });
+    }
+}
+fn report_similar_audio(settings: &Settings, data_manager: &impl DataManager, events: &mut ReportEvents) {
+    for group in audio::similar_groups(settings, data_manager) {
+        let files: Vec<String> = group.files.into_iter().map(|f| f.path).collect();
+        info!("Similar audio: {}", files.join(", "));
+        events.push(ReportEvent::SimilarAudio { files });
+    }
+}
+fn process_dir(root: &Path, settings: &Settings, file_manager: &impl HandleFiles, data_manager: &impl DataManager, events: &mut ReportEvents) {
+    walk_and_process(root, settings, file_manager, data_manager, events, None, None);
+}
+/// Returns true if `path` comes before `checkpoint` in walk order (and so was processed already),
+/// apart from the directories leading to `checkpoint`, which still have to be entered.
+fn before_checkpoint(path: &Path, checkpoint: &Path) -> bool {
+    // components are compared one by one, which is the order of a walk sorted by file name
+    path < checkpoint && !checkpoint.starts_with(path)
+}
+/// Processes every file below `root`. When `run` is given, progress is checkpointed to it and
+/// the walk skips everything before `run.last_dir`. Returns false if a shutdown stopped the walk.
+fn walk_and_process(root: &Path, settings: &Settings, file_manager: &impl HandleFiles, data_manager: &impl DataManager, events: &mut ReportEvents, mut run: Option<&mut ScanRun>, progress: Option<&Progress>) -> bool {
+    let root = match root.to_str() {
+        Some(r) => r,
+        None => return true,
+    };
+    let checkpoint = run.as_ref().and_then(|r| r.last_dir.clone()).map(PathBuf::from);
+    let mut last_checkpoint = Instant::now();
+    let mut walker = file_manager.walkdir(root, &settings.walk_options(Path::new(root)));
+    while let Some(entry) = walker.next() {
+        let entry = match entry {
+            Ok(e) => e,
+            Err(e) => {
+                report_walk_error(&e, events);
+                continue;
+            }
+        };
+        daemon::keepalive();
+        if daemon::shutdown_requested() {
+            info!("Shutdown requested, scan of {} stopped", root);
+            return false;
+        }
+        let is_dir = entry.file_type().is_dir();
+        if entry.path_is_symlink() && !settings.follow_symlinks {
+            continue;
+        }
+        if let Some(c) = &checkpoint {
+            if before_checkpoint(entry.path(), c) {
+                if is_dir {
+                    walker.skip_current_dir();
+                }
+                continue;
+            }
+        }
         let path = entry.path().to_str().unwrap();
         let srcdir = PathBuf::from(&path);
         let full_path_o = file_manager.get_full_path(&srcdir);
-        if full_path_o.is_ok() {
-            let s_path = String::from(full_path_o.unwrap().to_str().unwrap());
+        if let Ok(full_path) = full_path_o {
+            let s_path = String::from(full_path.to_str().unwrap());
         
             if should_ignore_path(&srcdir, settings, file_manager) {
-                continue 'filewalker;
+                if is_dir {
+                    // nothing below an ignored directory is processed
+                    walker.skip_current_dir();
+                }
+                continue;
             }
     
-            if !entry.file_type().is_dir() {            
-                process_file(&s_path, settings,file_manager,data_manager, log);               
+            if !is_dir {            
+                process_file(&s_path, settings,file_manager,data_manager, events);               
+                if let Some(p) = progress {
+                    p.update(&events.stats, entry.path().parent().unwrap_or(entry.path()));
+                }
             }
     }
-        
+        if let Some(run) = run.as_deref_mut() {
+            if is_dir {
+                // everything before this directory is done
+                run.last_dir = Some(path.to_string());
+                run.dirs_processed += 1;
+            } else {
+                run.files_processed += 1;
+            }
+            if last_checkpoint.elapsed() >= CHECKPOINT_INTERVAL {
+                run.summary = events.stats.summary();
+                run.copies = events.stats.take_unsaved();
+                data_manager.update_scan_run(run).unwrap_or_else(|e| error!("Unable to save scan checkpoint: {:?}", e));
+                last_checkpoint = Instant::now();
+            }
+        }
+    }
+    true
+}
+fn report_walk_error(e: &walkdir::Error, events: &mut ReportEvents) {
+    if let Some(ancestor) = e.loop_ancestor() {
+        let path = e.path().map(|p| p.display().to_string()).unwrap_or_default();
+        warn!("Symlink loop: {} points back to {}", path, ancestor.display());
+        events.push(ReportEvent::SymlinkLoop { path, ancestor: ancestor.display().to_string() });
+    } else {
+        warn!("Unable to read {}", e);
+    }
+}
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+/// `duplicates verify`: one pass over the hash database, or passes until a shutdown is requested
+/// with `continuous`. What was found is sent after every pass, a corruption only once.
+fn verify_hashes(settings: &Settings, continuous: bool, file_manager: &impl HandleFiles, data_manager: &impl DataManager, events: &mut ReportEvents) {
+    let rate = match settings.verify_rate.as_deref().map(filters::parse_size).transpose() {
+        Ok(rate) => rate,
+        Err(e) => {
+            error!("verify_rate: {}", e);
+            return;
+        }
+    };
+    let mut throttle = verify::Throttle::new(rate);
+    let mut reported = HashSet::new();
+    daemon::ready("Verifying hashes");
+    loop {
+        let stats = verify::verify(settings, &mut throttle, &mut reported, file_manager, data_manager, events);
+        info!("Verified: {}", stats);
+        if !events.is_empty() {
+            send_notifications(settings, events.take(), data_manager);
+        }
+        if !continuous || daemon::shutdown_requested() {
+            break;
+        }
+        if stats.bytes_read == 0 {
+            // nothing to read, don't spin
+            thread::sleep(daemon::tick());
+        }
+    }
+    daemon::stopping();
+}
+/// Sends the email report and calls the webhooks and command hooks.
+fn send_notifications(settings: &Settings, body: ReportBody, data_manager: &impl DataManager) {
+    let attachments = settings.email_attach_report
+        .filter(|_| !settings.email_result_to.is_empty() && !body.is_empty())
+        .map(|format| report::Report::new(report::duplicate_groups(settings, data_manager)).attachment(format))
+        .into_iter().collect();
+    if let Err(e) = mailer::send_report(settings, &body, attachments) {
+        error!("{}", e);
+    }
+    hooks::notify(settings, &body);
+}
+/// Applies the log settings (and --log-level). A log file that can't be opened is reported
+/// and the previous log configuration stays.
+fn configure_logging(settings: &Settings, cli: &Cli) {
+    if let Err(e) = logger::configure(settings, cli.log_level) {
+        error!("{}", e);
     }
 }
 fn main() -> std::result::Result<(), std::io::Error> {
-    let settings = Settings::new();
+    let cli = Cli::parse();
+    logger::init();
+    if let Some(level) = cli.log_level {
+        log::set_max_level(level);
+    }
+    let settings = cli.load_settings();
     let file_manager = FileManager::new();
     let data_manager = DataStore::new();
-    let mut log = Logger::new();
+    let mut events = ReportEvents::new();
     data_manager.create_tables().expect("I couldn't create tables!");            
-    if settings.is_ok() {  
-        let u_settings = settings.unwrap();      
-        
-        process_path(&u_settings, &file_manager,&data_manager, &mut log);
-        if u_settings.watchdog {
-            notify_changes(&u_settings, &file_manager,&data_manager, &mut log);
-        } 
-        if let Some(email_address) = u_settings.email_result_to {
-            let email = Message::builder()
-            .from("Report <jaroslaw@majatech.pl>".parse().unwrap())
-            .to(email_address.parse().unwrap())
-            .subject("Duplicates report")
-            .body(log.dump())
-            .unwrap();
-
-let creds = Credentials::new(u_settings.email_username.unwrap(), u_settings.email_password.unwrap());
-
-// Open a remote connection to gmail
-let mailer = SmtpTransport::starttls_relay(&u_settings.email_hostname.unwrap())
-    .unwrap()
-    .credentials(creds)
-    .build();
-
-// Send the email
-match mailer.send(&email) {
-    Ok(_) => println!("Email sent successfully!"),
-    Err(e) => panic!("Could not send email: {:?}", e),
-}
-
+    if let Err(e) = daemon::install_signal_handlers() {
+        error!("Unable to install signal handlers: {:?}", e);
+    }
+    if let Err(e) = &settings {
+        if Path::new(&cli.config).exists() {
+            error!("{}: {}", cli.config, e);
         }
-    } else
-    if let Some(arg) = env::args().nth(1) {
-
-        process_path(&Settings{ 
-                  ignore_paths : vec![], 
-                  working_dir : String::from(arg),
+    }
+    if let Ok(mut u_settings) = settings {
+        configure_logging(&u_settings, &cli);
+        if let Some(Command::Daemon) = cli.command {
+            u_settings.watchdog = true;
+        }
+        if let Some(Command::Db { command: DbCommand::Prune { no_refresh } }) = cli.command {
+            let stats = reconcile::prune(&u_settings, !no_refresh, &file_manager, &data_manager);
+            println!("Database pruned: {}", stats);
+            return Ok(());
+        }
+        if let Some(Command::Verify { continuous, rate }) = &cli.command {
+            if rate.is_some() {
+                u_settings.verify_rate = rate.clone();
+            }
+            verify_hashes(&u_settings, *continuous, &file_manager, &data_manager, &mut events);
+            return Ok(());
+        }
+        if let Some(Command::Report { format, output }) = &cli.command {
+            let report = report::Report::new(report::duplicate_groups(&u_settings, &data_manager));
+            return report::write_output(&report::render(&report, *format), output.as_deref());
+        }
+        if let Some(Command::Similar { format, max_distance, output }) = &cli.command {
+            if !u_settings.similar_images && !u_settings.similar_videos && !u_settings.audio_fingerprint {
+                warn!("similar_images, similar_videos and audio_fingerprint are off in {}, images, videos and recordings are not hashed by scans", cli.config);
+            }
+            if max_distance.is_some() {
+                u_settings.image_max_distance = *max_distance;
+            }
+            let video_groups = if u_settings.similar_videos { video::similar_groups(&u_settings, &data_manager) } else { vec![] };
+            let audio_groups = if u_settings.audio_fingerprint { audio::similar_groups(&u_settings, &data_manager) } else { vec![] };
+            let report = report::SimilarReport::new(u_settings.image_hash.unwrap_or_default(),
+                u_settings.image_max_distance.unwrap_or(image_hash::DEFAULT_MAX_DISTANCE),
+                image_hash::similar_groups(&u_settings, &data_manager),
+                u_settings.video_max_distance.unwrap_or(video::DEFAULT_MAX_DISTANCE),
+                video_groups,
+                (u_settings.audio_max_bit_error.unwrap_or(audio::DEFAULT_MAX_BIT_ERROR) * 100.0).round() as u32,
+                audio_groups);
+            return report::write_output(&report::render_similar(&report, *format), output.as_deref());
+        }
+        let (scan_only, resume) = match cli.command {
+            Some(Command::Scan { resume }) => (true, resume),
+            _ => (false, false),
+        };
+        daemon::ready(&format!("Scanning {}", u_settings.working_dir));
+        process_path(&u_settings, resume, &file_manager,&data_manager, &mut events);
+        if u_settings.watchdog && !scan_only && !daemon::shutdown_requested() {
+            notify_changes(&mut u_settings, &cli, &file_manager,&data_manager, &mut events);
+        } 
+        daemon::stopping();
+        send_notifications(&u_settings, events.take(), &data_manager);
+    } else if let Some(arg) = &cli.path {
+        let mut settings = Settings{ 
+                  working_dir : arg.clone(),
                   action: "T".to_string(), 
-                  delete_score: vec![], 
-                  watchdog: false,
-                  email_result_to: None,
-                  email_hostname: None,
-                  email_password: None,
-                  email_username: None
-                }, &file_manager,&data_manager, &mut log);
+                  ..Default::default()
+                };
+        if let Err(e) = cli.filters.apply(&mut settings) {
+            error!("{}", e);
+            return Ok(());
+        }
+        process_path(&settings, false, &file_manager,&data_manager, &mut events);
                 
         
     } else {
@@ -0,0 +1,198 @@
+use exif::{Exif, In, Reader, Tag, Value};
+
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+
+use crate::bktree::hamming_distance;
+use crate::datastore::{DataManager, FileInfo, PhotoEntry};
+use crate::image_hash::{group_pairs, is_image, retain_existing, sort_groups, SimilarFile, SimilarGroup};
+use crate::settings::Settings;
+
+/// bits the perceptual hashes of two copies of the same shot may differ in
+pub const DEFAULT_EXIF_MAX_DISTANCE: u32 = 2;
+/// photos of one shot are compared scaled down to this size, in colour
+const PIXEL_CHECK_SIZE: u32 = 32;
+/// how much one of those pixels may differ at most
+const MAX_PIXEL_DIFFERENCE: u8 = 16;
+/// and how much all of them may differ on average
+const MAX_MEAN_PIXEL_DIFFERENCE: f64 = 2.0;
+
+/// What the EXIF data of a photo says about it.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct PhotoExif {
+    /// the shot this file is a copy of, see `identity`
+    pub identity: Option<String>,
+    /// number of EXIF fields, more is a more complete copy
+    pub fields: usize,
+}
+
+/// Reads the EXIF data of `path`. Files without EXIF (or in a format without it) get the default.
+pub fn read_exif(path: &Path) -> PhotoExif {
+    let exif = File::open(path).map_err(exif::Error::Io)
+        .and_then(|f| Reader::new().read_from_container(&mut BufReader::new(f)));
+    match exif {
+        Ok(exif) => PhotoExif { identity: identity(&exif), fields: exif.fields().len() },
+        Err(e) => {
+            log::debug!(path = path.to_string_lossy().as_ref(); "No EXIF data: {}", e);
+            PhotoExif::default()
+        }
+    }
+}
+
+/// The camera's unique image ID if it wrote one, otherwise when the photo was taken and
+/// by which camera (serial number, or make and model). None without DateTimeOriginal.
+/// Without SubSecTimeOriginal the time is only whole seconds, see `names_one_shot`.
+fn identity(exif: &Exif) -> Option<String> {
+    // some cameras fill the ID with zeros
+    if let Some(id) = ascii(exif, Tag::ImageUniqueID).filter(|id| id.chars().any(|c| c != '0')) {
+        return Some(format!("id:{}", id));
+    }
+    let taken = ascii(exif, Tag::DateTimeOriginal)?;
+    let subsec = ascii(exif, Tag::SubSecTimeOriginal).unwrap_or_default();
+    let camera = match ascii(exif, Tag::BodySerialNumber) {
+        Some(serial) => format!("serial {}", serial),
+        None => format!("{} {}", ascii(exif, Tag::Make).unwrap_or_default(), ascii(exif, Tag::Model).unwrap_or_default()),
+    };
+    Some(format!("taken:{}.{}|{}", taken, subsec, camera))
+}
+
+/// Whether `identity` can only be one shot: an image unique ID, or a time with subseconds.
+/// Burst frames share the second they were taken in.
+fn names_one_shot(identity: &str) -> bool {
+    identity.starts_with("id:") || identity.strip_prefix("taken:")
+        .and_then(|rest| rest.split_once('.'))
+        .is_some_and(|(_, subsec)| !subsec.starts_with('|'))
+}
+
+/// Whether the images at `a` and `b`, scaled down to PIXEL_CHECK_SIZE, are the same but for
+/// rounding and compression. Compared in colour, a black and white or graded export keeps the
+/// brightness of its original. False if either can't be read.
+fn same_pixels(a: &Path, b: &Path) -> bool {
+    let small = |path: &Path| image::open(path).ok()
+        .map(|i| i.resize_exact(PIXEL_CHECK_SIZE, PIXEL_CHECK_SIZE, image::imageops::FilterType::Triangle).to_rgb8());
+    let (Some(a), Some(b)) = (small(a), small(b)) else {
+        return false;
+    };
+    let differences: Vec<u8> = a.as_raw().iter().zip(b.as_raw()).map(|(x, y)| x.abs_diff(*y)).collect();
+    let total: u64 = differences.iter().map(|d| *d as u64).sum();
+    differences.iter().all(|d| *d <= MAX_PIXEL_DIFFERENCE) && total as f64 / differences.len() as f64 <= MAX_MEAN_PIXEL_DIFFERENCE
+}
+
+/// Whether the photos at `a` and `b` with the same `identity` are copies of one shot: an image
+/// unique ID or a time with subseconds, and the same pixels. Edited exports keep the unique ID.
+fn same_shot(identity: &str, a: &str, b: &str) -> bool {
+    names_one_shot(identity) && (a == b || same_pixels(Path::new(a), Path::new(b)))
+}
+
+fn ascii(exif: &Exif, tag: Tag) -> Option<String> {
+    match &exif.get_field(tag, In::PRIMARY)?.value {
+        Value::Ascii(values) => {
+            let text = values.iter().map(|v| String::from_utf8_lossy(v)).collect::<Vec<_>>().join(" ");
+            Some(text.trim().to_string()).filter(|t| !t.is_empty())
+        }
+        _ => None,
+    }
+}
+
+/// Which copy of a photo is worth keeping: number of pixels, then number of EXIF fields.
+/// Files that are not images (or can't be read) get (0, 0).
+pub fn quality(path: &Path) -> (u64, usize) {
+    if !is_image(path) {
+        return (0, 0);
+    }
+    let pixels = image::image_dimensions(path).map(|(w, h)| w as u64 * h as u64).unwrap_or(0);
+    (pixels, read_exif(path).fields)
+}
+
+/// Stores the EXIF identity of `info` if it is an image that was not read since it last changed.
+pub fn update(info: &FileInfo, data_manager: &impl DataManager) {
+    let path = Path::new(&info.full_path);
+    if !is_image(path) {
+        return;
+    }
+    match data_manager.get_photo(&info.full_path) {
+        Ok(Some(existing)) if existing.last_modified >= info.last_modified => return,
+        Ok(_) => (),
+        Err(e) => {
+            log::error!("Unable to read EXIF identity of {}: {:?}", info.full_path, e);
+            return;
+        }
+    }
+    let entry = PhotoEntry { path: info.full_path.clone(), identity: read_exif(path).identity, last_modified: info.last_modified };
+    data_manager.add_photo(&entry).unwrap_or_else(|e| log::error!("Unable to add EXIF identity of {}: {:?}", info.full_path, e));
+}
+
+/// Other copies of the shot `info` is: same EXIF identity, perceptual hashes at most
+/// exif_max_distance apart and `same_shot`. Sorted by path, `info` itself included if there
+/// are any. Rows of files that are gone are removed on the way.
+pub fn matching(info: &FileInfo, settings: &Settings, data_manager: &impl DataManager) -> Vec<FileInfo> {
+    let kind = settings.image_hash.unwrap_or_default();
+    let max_distance = settings.exif_max_distance.unwrap_or(DEFAULT_EXIF_MAX_DISTANCE);
+    let Ok(Some(PhotoEntry { identity: Some(identity), .. })) = data_manager.get_photo(&info.full_path) else {
+        return vec![];
+    };
+    if !names_one_shot(&identity) {
+        return vec![];
+    }
+    let Ok(Some(own)) = data_manager.get_image_hash(&info.full_path, kind.name()) else {
+        return vec![];
+    };
+    let mut photos = data_manager.get_photos_by_identity(&identity).unwrap_or_else(|e| {
+        log::error!("Unable to read photos taken as {}: {:?}", identity, e);
+        vec![]
+    });
+    retain_existing(&mut photos, |p| &p.path, data_manager);
+    let mut matches: Vec<FileInfo> = photos.into_iter()
+        .filter(|p| matches!(data_manager.get_image_hash(&p.path, kind.name()), Ok(Some(h)) if hamming_distance(h.hash, own.hash) <= max_distance))
+        .filter(|p| same_shot(&identity, &info.full_path, &p.path))
+        .filter_map(|p| data_manager.get_entry_for_path(&p.path).ok().flatten())
+        .collect();
+    if matches.len() <= 1 {
+        return vec![];
+    }
+    matches.sort_by(|a, b| a.full_path.cmp(&b.full_path));
+    matches
+}
+
+/// Photos with the same EXIF identity and perceptual hashes at most exif_max_distance apart
+/// that are not `same_shot` (burst frames, edits), biggest groups first. Groups of files with
+/// the same content are left out. Rows of files that are gone are removed on the way.
+pub fn similar_groups(settings: &Settings, data_manager: &impl DataManager) -> Vec<SimilarGroup> {
+    let kind = settings.image_hash.unwrap_or_default();
+    let max_distance = settings.exif_max_distance.unwrap_or(DEFAULT_EXIF_MAX_DISTANCE);
+    let identities = data_manager.get_shared_photo_identities().expect("Unable to read EXIF identities");
+    let mut groups: Vec<SimilarGroup> = vec![];
+    for identity in identities {
+        let mut entries = data_manager.get_photos_by_identity(&identity).unwrap_or_default();
+        retain_existing(&mut entries, |p| &p.path, data_manager);
+        let mut photos: Vec<(String, u64)> = entries.into_iter()
+            .filter_map(|p| data_manager.get_image_hash(&p.path, kind.name()).ok().flatten().map(|h| (p.path, h.hash)))
+            .collect();
+        photos.sort();
+        let mut pairs = vec![];
+        for (i, a) in photos.iter().enumerate() {
+            for (j, b) in photos.iter().enumerate().skip(i + 1) {
+                if hamming_distance(a.1, b.1) <= max_distance && !same_shot(&identity, &a.0, &b.0) {
+                    pairs.push((i, j));
+                }
+            }
+        }
+        groups.extend(group_pairs(photos.len(), pairs).into_iter().filter_map(|members| {
+            let contents: HashSet<String> = members.iter()
+                .filter_map(|i| data_manager.get_entry_for_path(&photos[*i].0).ok().flatten())
+                .map(|e| e.hash)
+                .collect();
+            if contents.len() <= 1 {
+                return None;
+            }
+            let first = photos[members[0]].1;
+            Some(SimilarGroup {
+                files: members.iter().map(|i| SimilarFile { path: photos[*i].0.clone(), distance: hamming_distance(first, photos[*i].1) }).collect(),
+            })
+        }));
+    }
+    sort_groups(&mut groups);
+    groups
+}
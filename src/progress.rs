@@ -0,0 +1,229 @@
+use std::fmt;
+use std::io::{self, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::file_manager::{FileManager, HandleFiles};
+use crate::report::format_bytes;
+use crate::settings::Settings;
+use crate::stats::{format_duration, ScanStats};
+use crate::{before_checkpoint, daemon, passes_filters, should_ignore_path};
+
+/// how often the progress line is redrawn
+const LINE_INTERVAL: Duration = Duration::from_millis(250);
+
+/// set while a progress line is on the terminal, so that log lines can clear it first
+static LINE_VISIBLE: AtomicBool = AtomicBool::new(false);
+
+/// How progress of a scan is shown.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ProgressMode {
+    /// a line on stdout that is redrawn in place
+    Line,
+    /// an info log line every interval
+    Log(Duration),
+    Off,
+}
+
+impl ProgressMode {
+    /// A line if stdout is a terminal, periodic log lines when running as a service, nothing otherwise.
+    pub fn for_settings(settings: &Settings) -> ProgressMode {
+        if io::stdout().is_terminal() {
+            ProgressMode::Line
+        } else if settings.watchdog {
+            match settings.progress_log_secs.unwrap_or(60) {
+                0 => ProgressMode::Off,
+                secs => ProgressMode::Log(Duration::from_secs(secs)),
+            }
+        } else {
+            ProgressMode::Off
+        }
+    }
+}
+
+/// Progress of one scan. Files to be processed are counted by `count` in a thread of its own,
+/// `update` is called by the scan after every file.
+pub struct Progress {
+    mode: ProgressMode,
+    started: Instant,
+    discovered_files: AtomicU64,
+    discovered_bytes: AtomicU64,
+    counting_done: AtomicBool,
+    stop: AtomicBool,
+    last_shown: Mutex<Instant>,
+}
+
+impl Progress {
+    pub fn new(mode: ProgressMode) -> Self {
+        let started = Instant::now();
+        Progress {
+            mode,
+            started,
+            discovered_files: AtomicU64::new(0),
+            discovered_bytes: AtomicU64::new(0),
+            counting_done: AtomicBool::new(false),
+            stop: AtomicBool::new(false),
+            last_shown: Mutex::new(started),
+        }
+    }
+
+    pub fn is_off(&self) -> bool {
+        self.mode == ProgressMode::Off
+    }
+
+    /// Counts the files below `root` the scan will process (same walk options, ignore rules and
+    /// filters), skipping everything before `checkpoint`. Stops early when `finish` is called.
+    pub fn count(&self, root: &Path, settings: &Settings, checkpoint: Option<&Path>) {
+        let file_manager = FileManager::new();
+        let Some(root_str) = root.to_str() else {
+            return;
+        };
+        let mut walker = file_manager.walkdir(root_str, &settings.walk_options(root));
+        while let Some(entry) = walker.next() {
+            if self.stop.load(Ordering::Relaxed) || daemon::shutdown_requested() {
+                return;
+            }
+            let Ok(entry) = entry else {
+                continue;
+            };
+            let is_dir = entry.file_type().is_dir();
+            if entry.path_is_symlink() && !settings.follow_symlinks {
+                continue;
+            }
+            if checkpoint.is_some_and(|c| before_checkpoint(entry.path(), c)) || should_ignore_path(entry.path(), settings, &file_manager) {
+                if is_dir {
+                    walker.skip_current_dir();
+                }
+                continue;
+            }
+            if !is_dir && passes_filters(entry.path(), settings) {
+                self.discovered_files.fetch_add(1, Ordering::Relaxed);
+                self.discovered_bytes.fetch_add(entry.metadata().map(|m| m.len()).unwrap_or(0), Ordering::Relaxed);
+            }
+        }
+        self.counting_done.store(true, Ordering::Relaxed);
+    }
+
+    /// Shows the progress if it is time to.
+    pub fn update(&self, stats: &ScanStats, dir: &Path) {
+        let interval = match self.mode {
+            ProgressMode::Line => LINE_INTERVAL,
+            ProgressMode::Log(interval) => interval,
+            ProgressMode::Off => return,
+        };
+        {
+            let mut last = self.last_shown.lock().unwrap();
+            if last.elapsed() < interval {
+                return;
+            }
+            *last = Instant::now();
+        }
+        let snapshot = self.snapshot(stats, dir);
+        match self.mode {
+            ProgressMode::Line => {
+                let mut out = io::stdout().lock();
+                let _ = write!(out, "\r\x1b[K{}", snapshot.line(terminal_width()));
+                let _ = out.flush();
+                LINE_VISIBLE.store(true, Ordering::Relaxed);
+            }
+            _ => log::info!(files_processed = snapshot.processed_files, files_discovered = snapshot.discovered_files,
+                            bytes_hashed = snapshot.bytes_hashed, eta_secs = snapshot.eta().map(|e| e.as_secs());
+                            "Progress: {}", snapshot),
+        }
+    }
+
+    /// Stops counting and removes the progress line.
+    pub fn finish(&self) {
+        self.stop.store(true, Ordering::Relaxed);
+        clear_line();
+    }
+
+    pub fn snapshot(&self, stats: &ScanStats, dir: &Path) -> Snapshot {
+        Snapshot {
+            discovered_files: self.discovered_files.load(Ordering::Relaxed),
+            discovered_bytes: self.discovered_bytes.load(Ordering::Relaxed),
+            counting_done: self.counting_done.load(Ordering::Relaxed),
+            processed_files: stats.files_scanned,
+            processed_bytes: stats.bytes_scanned,
+            bytes_hashed: stats.bytes_read,
+            elapsed: self.started.elapsed(),
+            dir: dir.to_path_buf(),
+        }
+    }
+}
+
+/// Removes the progress line from the terminal, if there is one.
+pub fn clear_line() {
+    if LINE_VISIBLE.swap(false, Ordering::Relaxed) {
+        let mut out = io::stdout().lock();
+        let _ = write!(out, "\r\x1b[K");
+        let _ = out.flush();
+    }
+}
+
+fn terminal_width() -> usize {
+    std::env::var("COLUMNS").ok().and_then(|c| c.parse().ok()).unwrap_or(100)
+}
+
+/// Progress at one moment.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Snapshot {
+    pub discovered_files: u64,
+    pub discovered_bytes: u64,
+    /// false while files are still being counted, the totals only grow until then
+    pub counting_done: bool,
+    pub processed_files: u64,
+    /// size of all processed files, hashed or not
+    pub processed_bytes: u64,
+    pub bytes_hashed: u64,
+    pub elapsed: Duration,
+    pub dir: PathBuf,
+}
+
+impl Snapshot {
+    /// bytes hashed per second
+    pub fn throughput(&self) -> u64 {
+        match self.elapsed.as_secs_f64() {
+            secs if secs > 0.0 => (self.bytes_hashed as f64 / secs) as u64,
+            _ => 0,
+        }
+    }
+
+    /// Remaining time at the rate so far, by size. Only known once counting is done.
+    pub fn eta(&self) -> Option<Duration> {
+        if !self.counting_done || self.processed_bytes == 0 {
+            return None;
+        }
+        let remaining = self.discovered_bytes.saturating_sub(self.processed_bytes) as f64;
+        Some(Duration::from_secs_f64(self.elapsed.as_secs_f64() * remaining / self.processed_bytes as f64))
+    }
+
+    /// The progress line, cut to `width` characters by shortening the directory.
+    pub fn line(&self, width: usize) -> String {
+        let line = self.to_string();
+        if line.chars().count() <= width {
+            return line;
+        }
+        let dir = self.dir.to_string_lossy();
+        let overflow = line.chars().count() - width + 1;
+        let dir_chars = dir.chars().count();
+        if overflow >= dir_chars {
+            return line.chars().take(width).collect();
+        }
+        let short: String = dir.chars().skip(overflow).collect();
+        line.replacen(dir.as_ref(), &format!("…{}", short), 1)
+    }
+}
+
+impl fmt::Display for Snapshot {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let total = if self.counting_done { self.discovered_files.to_string() } else { format!("{}+", self.discovered_files) };
+        write!(f, "{}/{} files, {} hashed, {}/s", self.processed_files, total, format_bytes(self.bytes_hashed), format_bytes(self.throughput()))?;
+        if let Some(eta) = self.eta() {
+            write!(f, ", ETA {}", format_duration(eta.as_secs()))?;
+        }
+        write!(f, ", in {}", self.dir.display())
+    }
+}
@@ -0,0 +1,103 @@
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+
+use crate::archive;
+use crate::datastore::{DataManager, FileInfo};
+use crate::file_manager::HandleFiles;
+use crate::settings::Settings;
+use crate::{calculate_hash_for_file, should_ignore_path};
+
+/// What a reconciliation pass did.
+#[derive(Debug, Default, PartialEq)]
+pub struct PruneStats {
+    pub checked: u64,
+    /// path doesn't exist anymore
+    pub missing: u64,
+    /// path is not below working_dir or is ignored
+    pub outside_roots: u64,
+    /// size or mtime changed, hash recalculated
+    pub refreshed: u64,
+    /// size or mtime changed and the row was deleted instead (refresh = false)
+    pub deleted_changed: u64,
+}
+
+impl PruneStats {
+    pub fn cleaned(&self) -> u64 {
+        self.missing + self.outside_roots + self.refreshed + self.deleted_changed
+    }
+}
+
+/// Checks every row of `file_hashes` against the file system. Rows for missing files and files
+/// outside the configured roots are deleted. Rows whose size or mtime changed are refreshed,
+/// or deleted if `refresh` is false.
+pub fn prune(settings: &Settings, refresh: bool, file_manager: &impl HandleFiles, data_manager: &impl DataManager) -> PruneStats {
+    let mut stats = PruneStats::default();
+    let root = file_manager.get_full_path(Path::new(&settings.working_dir)).ok();
+    let entries = data_manager.get_all_entries().expect("Unable to read entries");
+    for entry in entries {
+        stats.checked += 1;
+        // files inside an archive are checked against the archive, a scan reads it again when it changed
+        let member = archive::is_member(&entry.full_path);
+        let path = Path::new(archive::container(&entry.full_path));
+        let meta = match fs::metadata(path) {
+            Ok(m) => m,
+            Err(_) => {
+                stats.missing += 1;
+                delete(&entry, data_manager);
+                continue;
+            }
+        };
+        let inside = root.as_ref().is_some_and(|r| path.starts_with(r));
+        if !inside || should_ignore_path(path, settings, file_manager) {
+            stats.outside_roots += 1;
+            delete(&entry, data_manager);
+            continue;
+        }
+        let modified = meta.modified().ok()
+            .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        if (member || meta.len() == entry.size) && modified == entry.last_modified {
+            continue;
+        }
+        if !refresh || member {
+            stats.deleted_changed += 1;
+            delete(&entry, data_manager);
+            continue;
+        }
+        match file_manager.get_file(path) {
+            Ok(mut file) => {
+                let info = FileInfo {
+                    full_path: entry.full_path.clone(),
+                    size: meta.len(),
+                    hash: calculate_hash_for_file(&mut file),
+                    last_modified: modified,
+                    // computed again by the next scan, if it is on
+                    content_hash: None,
+                };
+                delete(&entry, data_manager);
+                data_manager.add_entry(&info).expect("Unable to add entry to db");
+                stats.refreshed += 1;
+            }
+            Err(e) => {
+                log::warn!("Unable to read {}, removing it from db: {:?}", entry.full_path, e);
+                stats.missing += 1;
+                delete(&entry, data_manager);
+            }
+        }
+    }
+    stats
+}
+
+impl fmt::Display for PruneStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "checked {} rows, cleaned {}: {} missing, {} outside working_dir or ignored, {} refreshed, {} changed and deleted",
+            self.checked, self.cleaned(), self.missing, self.outside_roots, self.refreshed, self.deleted_changed)
+    }
+}
+
+fn delete(entry: &FileInfo, data_manager: &impl DataManager) {
+    data_manager.delete_entry_for_path(&entry.full_path).unwrap_or_else(|e| log::error!("Unable to delete {} from db: {:?}", entry.full_path, e));
+}
@@ -0,0 +1,363 @@
+use clap::ValueEnum;
+
+use std::collections::HashSet;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+
+use crate::content_hash;
+use crate::datastore::{DataManager, FileInfo};
+use crate::image_hash::{ImageHashKind, SimilarGroup};
+use crate::settings::Settings;
+use crate::{get_duplicates_for_content_hash, get_duplicates_for_hash, get_duplicates_sorted_by_score, unix_now};
+
+/// Version of the JSON report layout. Bumped on any incompatible change;
+/// new fields may be added without a bump.
+pub const SCHEMA_VERSION: u32 = 1;
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReportFormat {
+    /// see "JSON report" in README.md for the schema
+    Json,
+    /// one row per file
+    Csv,
+    /// a page for people: biggest savings first, thumbnails, links to folders
+    Html,
+}
+
+impl ReportFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ReportFormat::Json => "json",
+            ReportFormat::Csv => "csv",
+            ReportFormat::Html => "html",
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            ReportFormat::Json => "application/json",
+            ReportFormat::Csv => "text/csv",
+            ReportFormat::Html => "text/html; charset=utf-8",
+        }
+    }
+}
+
+/// Everything `duplicates report` knows about duplicates in the database.
+#[derive(Debug, Serialize)]
+pub struct Report {
+    pub schema_version: u32,
+    /// unix timestamp
+    pub generated_at: u64,
+    /// groups with the most reclaimable bytes first
+    pub groups: Vec<DuplicateGroup>,
+    pub total_reclaimable_bytes: u64,
+}
+
+/// Files with the same content, or the same image or audio apart from metadata.
+#[derive(Debug, Serialize, PartialEq)]
+pub struct DuplicateGroup {
+    /// sha512 of the content, hex. `content:` and the content hash for files that differ only in
+    /// metadata (image_content_hash, audio_content_hash)
+    pub hash: String,
+    /// size of each copy in bytes, of the survivor if they differ in metadata
+    pub size: u64,
+    /// sorted by path
+    pub files: Vec<GroupFile>,
+    /// the copy that would be kept; all others would be deleted
+    pub survivor: String,
+    /// size of all the others
+    pub reclaimable_bytes: u64,
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct GroupFile {
+    pub path: String,
+    /// unix timestamp
+    pub last_modified: u64,
+}
+
+/// An email attachment: file name, content type and content.
+pub struct Attachment {
+    pub filename: String,
+    pub content_type: &'static str,
+    pub content: String,
+}
+
+impl Report {
+    pub fn attachment(&self, format: ReportFormat) -> Attachment {
+        Attachment {
+            filename: format!("duplicates-report.{}", format.extension()),
+            content_type: format.content_type(),
+            content: render(self, format),
+        }
+    }
+
+    pub fn new(groups: Vec<DuplicateGroup>) -> Self {
+        Report {
+            schema_version: SCHEMA_VERSION,
+            generated_at: unix_now(),
+            total_reclaimable_bytes: groups.iter().map(|g| g.reclaimable_bytes).sum(),
+            groups,
+        }
+    }
+}
+
+/// Everything `duplicates similar` knows about images, videos and recordings that are alike.
+#[derive(Debug, Serialize)]
+pub struct SimilarReport {
+    pub schema_version: u32,
+    /// unix timestamp
+    pub generated_at: u64,
+    pub image_hash: ImageHashKind,
+    pub max_distance: u32,
+    /// images, biggest groups first
+    pub groups: Vec<SimilarGroup>,
+    /// average distance of the frame hashes
+    pub video_max_distance: u32,
+    /// videos, biggest groups first
+    pub video_groups: Vec<SimilarGroup>,
+    /// share of differing fingerprint bits in percent
+    pub audio_max_bit_error: u32,
+    /// recordings, biggest groups first
+    pub audio_groups: Vec<SimilarGroup>,
+}
+
+impl SimilarReport {
+    pub fn new(image_hash: ImageHashKind, max_distance: u32, groups: Vec<SimilarGroup>, video_max_distance: u32, video_groups: Vec<SimilarGroup>, audio_max_bit_error: u32, audio_groups: Vec<SimilarGroup>) -> Self {
+        SimilarReport { schema_version: SCHEMA_VERSION, generated_at: unix_now(), image_hash, max_distance, groups, video_max_distance, video_groups, audio_max_bit_error, audio_groups }
+    }
+}
+
+/// Every hash that has more than one existing file and, with image_content_hash or
+/// audio_content_hash, every content hash of files that differ only in metadata. Files with the
+/// same hash that are all in such a group are not listed again. Rows of files that are gone are
+/// removed on the way.
+pub fn duplicate_groups(settings: &Settings, data_manager: &impl DataManager) -> Vec<DuplicateGroup> {
+    let mut groups = vec![];
+    let mut grouped = HashSet::new();
+    if settings.image_content_hash || settings.audio_content_hash {
+        let content_hashes = data_manager.get_duplicate_content_hashes().expect("Unable to read duplicate content hashes");
+        for content_hash in content_hashes.iter().filter(|h| *h != content_hash::NONE) {
+            let dups: Vec<FileInfo> = get_duplicates_for_content_hash(content_hash, data_manager).into_iter()
+                .filter(|d| content_hash::of(d, settings).is_some())
+                .collect();
+            grouped.extend(dups.iter().map(|d| d.full_path.clone()));
+            groups.extend(group(format!("content:{}", content_hash), dups, settings));
+        }
+    }
+    let hashes = data_manager.get_duplicate_hashes().expect("Unable to read duplicate hashes");
+    for hash in &hashes {
+        let dups = get_duplicates_for_hash(hash, data_manager);
+        if dups.iter().all(|d| grouped.contains(&d.full_path)) {
+            continue;
+        }
+        groups.extend(group(hash.clone(), dups, settings));
+    }
+    groups.sort_by(|a, b| b.reclaimable_bytes.cmp(&a.reclaimable_bytes).then_with(|| a.hash.cmp(&b.hash)));
+    groups
+}
+
+/// The group of `dups` named `hash`, None unless there is more than one.
+fn group(hash: String, mut dups: Vec<FileInfo>, settings: &Settings) -> Option<DuplicateGroup> {
+    if dups.len() <= 1 {
+        return None;
+    }
+    // files in archives are listed, but can't be freed
+    let sorted = get_duplicates_sorted_by_score(&dups, settings);
+    let (survivor, deleted) = sorted.split_last()?;
+    dups.sort_by(|a, b| a.full_path.cmp(&b.full_path));
+    let size_of = |path: &String| dups.iter().find(|d| &d.full_path == path).map_or(0, |d| d.size);
+    Some(DuplicateGroup {
+        hash,
+        size: size_of(survivor),
+        reclaimable_bytes: deleted.iter().map(size_of).sum(),
+        survivor: survivor.clone(),
+        files: dups.into_iter().map(|d| GroupFile { path: d.full_path, last_modified: d.last_modified }).collect(),
+    })
+}
+
+pub fn render(report: &Report, format: ReportFormat) -> String {
+    match format {
+        ReportFormat::Json => render_json(report),
+        ReportFormat::Csv => render_csv(report),
+        ReportFormat::Html => render_html(report),
+    }
+}
+
+pub fn render_json(report: &Report) -> String {
+    serde_json::to_string_pretty(report).expect("report can always be serialized") + "\n"
+}
+
+/// One row per file: hash, size, path, last_modified, survivor, reclaimable_bytes (of its group).
+pub fn render_csv(report: &Report) -> String {
+    let mut writer = csv::Writer::from_writer(vec![]);
+    writer.write_record(["hash", "size", "path", "last_modified", "survivor", "reclaimable_bytes"]).unwrap();
+    for group in &report.groups {
+        for file in &group.files {
+            writer.write_record([
+                group.hash.as_str(),
+                &group.size.to_string(),
+                &file.path,
+                &file.last_modified.to_string(),
+                if file.path == group.survivor { "true" } else { "false" },
+                &group.reclaimable_bytes.to_string(),
+            ]).unwrap();
+        }
+    }
+    String::from_utf8(writer.into_inner().unwrap()).unwrap()
+}
+
+const IMAGE_EXTENSIONS: [&str; 7] = ["jpg", "jpeg", "png", "gif", "webp", "bmp", "svg"];
+
+const HTML_HEAD: &str = r#"<!DOCTYPE html>
+<html><head><meta charset="utf-8"><title>Duplicates report</title>
+<style>
+body { font-family: sans-serif; margin: 2em; }
+table { border-collapse: collapse; margin-bottom: 2em; }
+td, th { border: 1px solid #ccc; padding: 4px 8px; text-align: left; vertical-align: middle; }
+.keep { background: #e6f4e6; }
+img, video { max-width: 120px; max-height: 90px; }
+</style></head><body>
+"#;
+
+pub fn render_html(report: &Report) -> String {
+    let mut html = String::from(HTML_HEAD);
+    html.push_str(&format!("<h1>Duplicates report</h1>\n<p>{} groups of duplicates, {} can be freed.</p>\n",
+        report.groups.len(), format_bytes(report.total_reclaimable_bytes)));
+    for group in &report.groups {
+        html.push_str(&format!("<h2>{} copies of {} &ndash; {} to free</h2>\n<table>\n<tr><th></th><th>File</th><th>Folder</th><th></th></tr>\n",
+            group.files.len(), format_bytes(group.size), format_bytes(group.reclaimable_bytes)));
+        for file in &group.files {
+            let path = Path::new(&file.path);
+            let is_image = path.extension()
+                .is_some_and(|e| IMAGE_EXTENSIONS.contains(&e.to_string_lossy().to_lowercase().as_str()));
+            let thumbnail = if is_image {
+                format!(r#"<img src="{}" loading="lazy" alt="">"#, escape_html(&file_url(path)))
+            } else {
+                String::new()
+            };
+            let folder = path.parent().unwrap_or(Path::new("/"));
+            let keep = file.path == group.survivor;
+            html.push_str(&format!("<tr{}><td>{}</td><td><a href=\"{}\">{}</a></td><td><a href=\"{}\">{}</a></td><td>{}</td></tr>\n",
+                if keep { r#" class="keep""# } else { "" },
+                thumbnail,
+                escape_html(&file_url(path)),
+                escape_html(&path.file_name().unwrap_or_default().to_string_lossy()),
+                escape_html(&file_url(folder)),
+                escape_html(&folder.to_string_lossy()),
+                if keep { "keep" } else { "delete" }));
+        }
+        html.push_str("</table>\n");
+    }
+    html.push_str("</body></html>\n");
+    html
+}
+
+pub fn render_similar(report: &SimilarReport, format: ReportFormat) -> String {
+    match format {
+        ReportFormat::Json => serde_json::to_string_pretty(report).expect("report can always be serialized") + "\n",
+        ReportFormat::Csv => render_similar_csv(report),
+        ReportFormat::Html => render_similar_html(report),
+    }
+}
+
+/// One row per file: group (numbered from 1, images first), path, distance (to the first file
+/// of the group), kind (image, video or audio).
+fn render_similar_csv(report: &SimilarReport) -> String {
+    let mut writer = csv::Writer::from_writer(vec![]);
+    writer.write_record(["group", "path", "distance", "kind"]).unwrap();
+    let groups = report.groups.iter().map(|g| (g, "image")).chain(report.video_groups.iter().map(|g| (g, "video")))
+        .chain(report.audio_groups.iter().map(|g| (g, "audio")));
+    for (i, (group, kind)) in groups.enumerate() {
+        for file in &group.files {
+            writer.write_record([&(i + 1).to_string(), &file.path, &file.distance.to_string(), kind]).unwrap();
+        }
+    }
+    String::from_utf8(writer.into_inner().unwrap()).unwrap()
+}
+
+fn render_similar_html(report: &SimilarReport) -> String {
+    let mut html = String::from(HTML_HEAD);
+    html.push_str(&format!("<h1>Similar images</h1>\n<p>{} groups of images at most {} bits apart ({}).</p>\n",
+        report.groups.len(), report.max_distance, report.image_hash.name()));
+    push_similar_groups(&mut html, &report.groups, "images", |url| format!("<img src=\"{}\" loading=\"lazy\" alt=\"\">", url));
+    if !report.video_groups.is_empty() {
+        html.push_str(&format!("<h1>Similar videos</h1>\n<p>{} groups of videos whose frames are on average at most {} bits apart.</p>\n",
+            report.video_groups.len(), report.video_max_distance));
+        push_similar_groups(&mut html, &report.video_groups, "videos", |url| format!("<video src=\"{}\" preload=\"metadata\"></video>", url));
+    }
+    if !report.audio_groups.is_empty() {
+        html.push_str(&format!("<h1>Similar audio</h1>\n<p>{} groups of recordings whose fingerprints differ in at most {}% of their bits.</p>\n",
+            report.audio_groups.len(), report.audio_max_bit_error));
+        push_similar_groups(&mut html, &report.audio_groups, "recordings", |url| format!("<audio src=\"{}\" controls preload=\"none\"></audio>", url));
+    }
+    html.push_str("</body></html>\n");
+    html
+}
+
+/// A table per group, `thumbnail` gives the element that shows a file (its URL already escaped).
+fn push_similar_groups(html: &mut String, groups: &[SimilarGroup], what: &str, thumbnail: fn(&str) -> String) {
+    for group in groups {
+        html.push_str(&format!("<h2>{} similar {}</h2>\n<table>\n<tr><th></th><th>File</th><th>Folder</th><th>Distance</th></tr>\n", group.files.len(), what));
+        for file in &group.files {
+            let path = Path::new(&file.path);
+            let folder = path.parent().unwrap_or(Path::new("/"));
+            let url = escape_html(&file_url(path));
+            html.push_str(&format!("<tr><td>{5}</td><td><a href=\"{0}\">{1}</a></td><td><a href=\"{2}\">{3}</a></td><td>{4}</td></tr>\n",
+                url,
+                escape_html(&path.file_name().unwrap_or_default().to_string_lossy()),
+                escape_html(&file_url(folder)),
+                escape_html(&folder.to_string_lossy()),
+                file.distance,
+                thumbnail(&url)));
+        }
+        html.push_str("</table>\n");
+    }
+}
+
+/// "1.5 MiB"
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+pub fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;").replace('\'', "&#39;")
+}
+
+/// file:// URL with everything but unreserved characters and '/' percent-encoded
+fn file_url(path: &Path) -> String {
+    let mut url = String::from("file://");
+    for b in path.to_string_lossy().bytes() {
+        if b.is_ascii_alphanumeric() || b"/-_.~".contains(&b) {
+            url.push(b as char);
+        } else {
+            url.push_str(&format!("%{:02X}", b));
+        }
+    }
+    url
+}
+
+/// Writes `content` to `output`, or to stdout if there is none.
+pub fn write_output(content: &str, output: Option<&str>) -> io::Result<()> {
+    match output {
+        Some(path) => fs::write(path, content),
+        None => match io::stdout().write_all(content.as_bytes()) {
+            // e.g. piped into head
+            Err(e) if e.kind() == io::ErrorKind::BrokenPipe => Ok(()),
+            r => r,
+        },
+    }
+}
@@ -0,0 +1,380 @@
+use std::collections::BTreeSet;
+use std::fmt;
+use std::path::PathBuf;
+
+use crate::datastore::FileInfo;
+use crate::report::escape_html;
+use crate::stats::{ScanStats, ScanSummary};
+
+/// Events kept for the next email report are capped, the rest is only counted.
+const MAX_EVENTS: usize = 10_000;
+
+/// Something the email report tells about.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ReportEvent {
+    /// `deleted` is false when the files were only marked (action T or S)
+    Duplicates { delete: Vec<String>, keep: String, deleted: bool },
+    /// action other than D, T or S: two files with the same content
+    SameHash { path: String, other: String },
+    HashChanged { path: String },
+    /// `duplicates verify`: the content changed, the size and mtime did not. `restored` from the
+    /// `intact` copy with verify_restore
+    Corrupted { path: String, intact: Option<String>, restored: bool },
+    SymlinkLoop { path: String, ancestor: String },
+    DatabasePruned { stats: String },
+    /// directories handled as a whole, `subset` if `delete` holds only files that are in `keep` too
+    DuplicateDirectories { delete: Vec<String>, keep: String, subset: bool, deleted: bool },
+    /// an archive whose every file is also on disk (archive_policy = "delete_archives")
+    RedundantArchive { path: String, files: usize, deleted: bool },
+    /// directories left empty by deleted files, removed (remove_empty_dirs)
+    EmptyDirectoriesRemoved { paths: Vec<String> },
+    /// zero-byte files and empty directories that were there before a scan (existing_empty)
+    ExistingEmpty { files: Vec<String>, directories: Vec<String>, deleted: bool },
+    /// images that look alike but are not bit-identical, never deleted
+    SimilarImages { files: Vec<String> },
+    /// videos with frames that look alike, never deleted
+    SimilarVideos { files: Vec<String> },
+    /// recordings with fingerprints that match but different content, deleted only with
+    /// audio_fingerprint_delete, then they are duplicates instead
+    SimilarAudio { files: Vec<String> },
+    ScanFinished(ScanSummary),
+}
+
+impl ReportEvent {
+    /// Something worth a report on its own. Scan summaries and database maintenance are only
+    /// sent along with findings.
+    pub fn is_finding(&self) -> bool {
+        !matches!(self, ReportEvent::ScanFinished(_) | ReportEvent::DatabasePruned { .. })
+    }
+}
+
+impl fmt::Display for ReportEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ReportEvent::Duplicates { delete, keep, deleted } => {
+                writeln!(f, "Duplicates found:")?;
+                for path in delete {
+                    writeln!(f, "{}: {}", if *deleted { "DELETED" } else { "DELETE" }, path)?;
+                }
+                write!(f, "LEAVE: {}", keep)
+            }
+            ReportEvent::DuplicateDirectories { delete, keep, subset, deleted } => {
+                writeln!(f, "{}", if *subset { "Directory contained in another one:" } else { "Duplicate directories:" })?;
+                for path in delete {
+                    writeln!(f, "{}: {}", if *deleted { "DELETED" } else { "DELETE" }, path)?;
+                }
+                write!(f, "LEAVE: {}", keep)
+            }
+            ReportEvent::RedundantArchive { path, files, deleted } => {
+                writeln!(f, "Archive whose {} files are all on disk too:", files)?;
+                write!(f, "{}: {}", if *deleted { "DELETED" } else { "DELETE" }, path)
+            }
+            ReportEvent::EmptyDirectoriesRemoved { paths } => {
+                write!(f, "Empty directories left by deleted files:")?;
+                paths.iter().try_for_each(|path| write!(f, "\nREMOVED: {}", path))
+            }
+            ReportEvent::ExistingEmpty { files, directories, deleted } => {
+                write!(f, "Zero-byte files and empty directories:")?;
+                let action = if *deleted { "DELETED" } else { "EMPTY" };
+                files.iter().chain(directories).try_for_each(|path| write!(f, "\n{}: {}", action, path))
+            }
+            ReportEvent::SameHash { path, other } => write!(f, "Hashes are the same for files : {} and {} ! ", path, other),
+            ReportEvent::HashChanged { path } => write!(f, "HASH changed for file : {} ! ", path),
+            ReportEvent::Corrupted { path, intact, restored } => {
+                write!(f, "{}: {}", if *restored { "CORRUPTED, RESTORED" } else { "CORRUPTED" }, path)?;
+                match intact {
+                    Some(intact) => write!(f, "\nINTACT COPY: {}", intact),
+                    None => write!(f, " (no intact copy)"),
+                }
+            }
+            ReportEvent::SymlinkLoop { path, ancestor } => write!(f, "Symlink loop: {} points back to {}", path, ancestor),
+            ReportEvent::DatabasePruned { stats } => write!(f, "Database pruned: {}", stats),
+            ReportEvent::SimilarImages { files } => write!(f, "Similar images: {}", files.join(", ")),
+            ReportEvent::SimilarVideos { files } => write!(f, "Similar videos: {}", files.join(", ")),
+            ReportEvent::SimilarAudio { files } => write!(f, "Similar audio: {}", files.join(", ")),
+            ReportEvent::ScanFinished(summary) => write!(f, "{}", summary),
+        }
+    }
+}
+
+/// Collects what goes into the next email report, separate from the log.
+pub struct ReportEvents {
+    events: Vec<ReportEvent>,
+    /// events that did not fit below MAX_EVENTS since the last flush
+    dropped: usize,
+    /// counters of the current scan
+    pub stats: ScanStats,
+    /// while a scan with directory_duplicates collects them: files that have copies, their groups
+    /// are looked up and handled after the directories
+    pub pending_duplicates: Option<Vec<FileInfo>>,
+    /// directories files were deleted from since they were last checked for being empty
+    pub deleted_from: BTreeSet<PathBuf>,
+}
+
+impl ReportEvents {
+    pub fn new() -> Self {
+        ReportEvents { events: vec![], dropped: 0, stats: ScanStats::default(), pending_duplicates: None, deleted_from: BTreeSet::new() }
+    }
+
+    pub fn push(&mut self, event: ReportEvent) {
+        if self.events.len() < MAX_EVENTS {
+            self.events.push(event);
+        } else {
+            self.dropped += 1;
+        }
+    }
+
+    /// Nothing to report yet, see `ReportEvent::is_finding`.
+    pub fn is_empty(&self) -> bool {
+        !self.events.iter().any(ReportEvent::is_finding)
+    }
+
+    /// Returns everything collected so far and starts over.
+    pub fn take(&mut self) -> ReportBody {
+        ReportBody { events: std::mem::take(&mut self.events), dropped: std::mem::take(&mut self.dropped) }
+    }
+}
+
+/// What the events of a report add up to.
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub struct ReportCounts {
+    pub duplicate_groups: usize,
+    pub files_deleted: usize,
+    /// marked by action T or S
+    pub files_to_delete: usize,
+    pub same_content: usize,
+    pub content_changed: usize,
+    pub corrupted: usize,
+    pub symlink_loops: usize,
+    pub duplicate_directories: usize,
+    pub directories_deleted: usize,
+    /// marked by action T or S
+    pub directories_to_delete: usize,
+    pub redundant_archives: usize,
+    pub empty_directories_removed: usize,
+    pub existing_empty_files: usize,
+    pub existing_empty_directories: usize,
+    pub existing_empty_deleted: usize,
+    pub similar_image_groups: usize,
+    pub similar_video_groups: usize,
+    pub similar_audio_groups: usize,
+}
+
+/// The events of one email report.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ReportBody {
+    pub events: Vec<ReportEvent>,
+    /// events that were not kept, see MAX_EVENTS
+    pub dropped: usize,
+}
+
+impl ReportBody {
+    /// Nothing to report: no events, or only scan summaries (see `ReportEvent::is_finding`).
+    pub fn is_empty(&self) -> bool {
+        !self.events.iter().any(ReportEvent::is_finding) && self.dropped == 0
+    }
+
+    /// True if the body shows fewer than all events when limited to `max_events`.
+    pub fn is_truncated(&self, max_events: usize) -> bool {
+        self.events.len() > max_events || self.dropped > 0
+    }
+
+    pub fn counts(&self) -> ReportCounts {
+        let mut counts = ReportCounts::default();
+        for event in &self.events {
+            match event {
+                ReportEvent::Duplicates { delete, deleted, .. } => {
+                    counts.duplicate_groups += 1;
+                    if *deleted {
+                        counts.files_deleted += delete.len();
+                    } else {
+                        counts.files_to_delete += delete.len();
+                    }
+                }
+                ReportEvent::DuplicateDirectories { delete, deleted, .. } => {
+                    counts.duplicate_directories += 1;
+                    if *deleted {
+                        counts.directories_deleted += delete.len();
+                    } else {
+                        counts.directories_to_delete += delete.len();
+                    }
+                }
+                ReportEvent::RedundantArchive { .. } => counts.redundant_archives += 1,
+                ReportEvent::EmptyDirectoriesRemoved { paths } => counts.empty_directories_removed += paths.len(),
+                ReportEvent::ExistingEmpty { files, directories, deleted } => {
+                    counts.existing_empty_files += files.len();
+                    counts.existing_empty_directories += directories.len();
+                    if *deleted {
+                        counts.existing_empty_deleted += files.len() + directories.len();
+                    }
+                }
+                ReportEvent::SameHash { .. } => counts.same_content += 1,
+                ReportEvent::HashChanged { .. } => counts.content_changed += 1,
+                ReportEvent::Corrupted { .. } => counts.corrupted += 1,
+                ReportEvent::SymlinkLoop { .. } => counts.symlink_loops += 1,
+                ReportEvent::SimilarImages { .. } => counts.similar_image_groups += 1,
+                ReportEvent::SimilarVideos { .. } => counts.similar_video_groups += 1,
+                ReportEvent::SimilarAudio { .. } => counts.similar_audio_groups += 1,
+                ReportEvent::ScanFinished(_) | ReportEvent::DatabasePruned { .. } => (),
+            }
+        }
+        counts
+    }
+
+    /// A few lines counting what happened, followed by each scan summary.
+    pub fn summary(&self) -> String {
+        let counts = self.counts();
+        let mut lines = vec![format!("Duplicates found: {} ({} files deleted, {} to delete)",
+            counts.duplicate_groups, counts.files_deleted, counts.files_to_delete)];
+        if counts.duplicate_directories > 0 {
+            lines.push(format!("Duplicate directories: {} ({} deleted, {} to delete)",
+                counts.duplicate_directories, counts.directories_deleted, counts.directories_to_delete));
+        }
+        if counts.redundant_archives > 0 {
+            lines.push(format!("Archives with all files on disk: {}", counts.redundant_archives));
+        }
+        if counts.empty_directories_removed > 0 {
+            lines.push(format!("Empty directories removed: {}", counts.empty_directories_removed));
+        }
+        if counts.existing_empty_files + counts.existing_empty_directories > 0 {
+            lines.push(format!("Zero-byte files: {}, empty directories: {} ({} deleted)",
+                counts.existing_empty_files, counts.existing_empty_directories, counts.existing_empty_deleted));
+        }
+        if counts.same_content > 0 {
+            lines.push(format!("Files with the same content: {}", counts.same_content));
+        }
+        if counts.content_changed > 0 {
+            lines.push(format!("Files whose content changed: {}", counts.content_changed));
+        }
+        if counts.corrupted > 0 {
+            lines.push(format!("Corrupted files: {}", counts.corrupted));
+        }
+        if counts.symlink_loops > 0 {
+            lines.push(format!("Symlink loops: {}", counts.symlink_loops));
+        }
+        if counts.similar_image_groups > 0 {
+            lines.push(format!("Groups of similar images: {}", counts.similar_image_groups));
+        }
+        if counts.similar_video_groups > 0 {
+            lines.push(format!("Groups of similar videos: {}", counts.similar_video_groups));
+        }
+        if counts.similar_audio_groups > 0 {
+            lines.push(format!("Groups of similar audio: {}", counts.similar_audio_groups));
+        }
+        lines.extend(self.events.iter().filter_map(|e| match e {
+            ReportEvent::ScanFinished(summary) => Some(summary.to_string()),
+            _ => None,
+        }));
+        lines.join("\n")
+    }
+
+    /// The summary and the first `max_events` events.
+    pub fn text(&self, max_events: usize) -> String {
+        let mut text = self.summary() + "\n\n";
+        for event in self.events.iter().take(max_events) {
+            text.push_str(&event.to_string());
+            text.push('\n');
+        }
+        if self.is_truncated(max_events) {
+            text.push_str(&format!("\n... {} more events, the full list is attached\n", self.hidden(max_events)));
+        }
+        text
+    }
+
+    /// Every event, for the attachment of a truncated report.
+    pub fn full_text(&self) -> String {
+        let mut text: String = self.events.iter().map(|e| e.to_string() + "\n").collect();
+        if self.dropped > 0 {
+            text.push_str(&format!("... and {} more events, see the log\n", self.dropped));
+        }
+        text
+    }
+
+    /// The summary and a table of the first `max_events` events: one row per file for duplicates.
+    pub fn html(&self, max_events: usize) -> String {
+        let mut html = String::from("<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><style>\n\
+            body { font-family: sans-serif; }\n\
+            table { border-collapse: collapse; }\n\
+            td, th { border: 1px solid #ccc; padding: 4px 8px; text-align: left; }\n\
+            .keep { background: #e6f4e6; }\n\
+            </style></head><body>\n");
+        html.push_str(&format!("<pre>{}</pre>\n", escape_html(&self.summary())));
+        html.push_str("<table>\n<tr><th>Action</th><th>File</th></tr>\n");
+        for event in self.events.iter().take(max_events) {
+            match event {
+                ReportEvent::Duplicates { delete, keep, deleted } => {
+                    for path in delete {
+                        html.push_str(&format!("<tr><td>{}</td><td>{}</td></tr>\n", if *deleted { "deleted" } else { "delete" }, escape_html(path)));
+                    }
+                    html.push_str(&format!("<tr class=\"keep\"><td>keep</td><td>{}</td></tr>\n", escape_html(keep)));
+                }
+                ReportEvent::DuplicateDirectories { delete, keep, subset, deleted } => {
+                    let action = match (*subset, *deleted) {
+                        (false, true) => "deleted copy",
+                        (false, false) => "delete copy",
+                        (true, true) => "deleted, contained",
+                        (true, false) => "delete, contained",
+                    };
+                    for path in delete {
+                        html.push_str(&format!("<tr><td>{} (directory)</td><td>{}</td></tr>\n", action, escape_html(path)));
+                    }
+                    html.push_str(&format!("<tr class=\"keep\"><td>keep (directory)</td><td>{}</td></tr>\n", escape_html(keep)));
+                }
+                ReportEvent::RedundantArchive { path, files, deleted } =>
+                    html.push_str(&format!("<tr><td>{} archive, all {} files on disk</td><td>{}</td></tr>\n", if *deleted { "deleted" } else { "delete" }, files, escape_html(path))),
+                ReportEvent::EmptyDirectoriesRemoved { paths } => {
+                    for path in paths {
+                        html.push_str(&format!("<tr><td>removed empty directory</td><td>{}</td></tr>\n", escape_html(path)));
+                    }
+                }
+                ReportEvent::ExistingEmpty { files, directories, deleted } => {
+                    let action = if *deleted { "deleted" } else { "empty" };
+                    for path in files {
+                        html.push_str(&format!("<tr><td>{} (zero bytes)</td><td>{}</td></tr>\n", action, escape_html(path)));
+                    }
+                    for path in directories {
+                        html.push_str(&format!("<tr><td>{} (directory)</td><td>{}</td></tr>\n", action, escape_html(path)));
+                    }
+                }
+                ReportEvent::SameHash { path, other } =>
+                    html.push_str(&format!("<tr><td>same content</td><td>{}<br>{}</td></tr>\n", escape_html(path), escape_html(other))),
+                ReportEvent::HashChanged { path } =>
+                    html.push_str(&format!("<tr><td>content changed</td><td>{}</td></tr>\n", escape_html(path))),
+                ReportEvent::Corrupted { path, intact, restored } => {
+                    html.push_str(&format!("<tr><td>{}</td><td>{}</td></tr>\n", if *restored { "corrupted, restored" } else { "corrupted" }, escape_html(path)));
+                    if let Some(intact) = intact {
+                        html.push_str(&format!("<tr class=\"keep\"><td>intact copy</td><td>{}</td></tr>\n", escape_html(intact)));
+                    }
+                }
+                ReportEvent::SymlinkLoop { path, ancestor } =>
+                    html.push_str(&format!("<tr><td>symlink loop</td><td>{} &rarr; {}</td></tr>\n", escape_html(path), escape_html(ancestor))),
+                ReportEvent::SimilarImages { files } => {
+                    let files: Vec<String> = files.iter().map(|f| escape_html(f)).collect();
+                    html.push_str(&format!("<tr><td>similar images</td><td>{}</td></tr>\n", files.join("<br>")));
+                }
+                ReportEvent::SimilarVideos { files } => {
+                    let files: Vec<String> = files.iter().map(|f| escape_html(f)).collect();
+                    html.push_str(&format!("<tr><td>similar videos</td><td>{}</td></tr>\n", files.join("<br>")));
+                }
+                ReportEvent::SimilarAudio { files } => {
+                    let files: Vec<String> = files.iter().map(|f| escape_html(f)).collect();
+                    html.push_str(&format!("<tr><td>similar audio</td><td>{}</td></tr>\n", files.join("<br>")));
+                }
+                ReportEvent::DatabasePruned { stats } =>
+                    html.push_str(&format!("<tr><td>database pruned</td><td>{}</td></tr>\n", escape_html(stats))),
+                ReportEvent::ScanFinished(_) => (),
+            }
+        }
+        html.push_str("</table>\n");
+        if self.is_truncated(max_events) {
+            html.push_str(&format!("<p>&hellip; {} more events, the full list is attached.</p>\n", self.hidden(max_events)));
+        }
+        html.push_str("</body></html>\n");
+        html
+    }
+
+    fn hidden(&self, max_events: usize) -> usize {
+        self.events.len().saturating_sub(max_events) + self.dropped
+    }
+}
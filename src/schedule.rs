@@ -0,0 +1,102 @@
+use chrono::{DateTime, Duration, Local, NaiveTime, TimeZone};
+
+use std::str::FromStr;
+
+/// When a recurring job (rescan, digest) should run.
+///
+/// Accepted formats:
+/// - `every 30m`, `every 6h`, `every 1d` (also `s` for seconds)
+/// - `hourly`
+/// - `daily 03:00`, `daily` (midnight)
+#[derive(Debug, Clone, PartialEq)]
+pub enum Schedule {
+    Every(Duration),
+    Daily(NaiveTime),
+}
+
+impl FromStr for Schedule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let mut parts = s.split_whitespace();
+        let invalid = || format!("Invalid schedule \"{}\", expected e.g. \"every 6h\" or \"daily 03:00\"", s);
+        let schedule = match (parts.next(), parts.next()) {
+            (Some("hourly"), None) => Schedule::Every(Duration::hours(1)),
+            (Some("daily"), None) => Schedule::Daily(NaiveTime::MIN),
+            (Some("daily"), Some(time)) => Schedule::Daily(NaiveTime::parse_from_str(time, "%H:%M").map_err(|_| invalid())?),
+            (Some("every"), Some(interval)) => {
+                // the last character is the unit, which may be longer than a byte
+                let (at, _) = interval.char_indices().last().ok_or_else(invalid)?;
+                let (count, unit) = interval.split_at(at);
+                let count: i64 = count.parse().map_err(|_| invalid())?;
+                let duration = match unit {
+                    "s" => Duration::try_seconds(count),
+                    "m" => Duration::try_minutes(count),
+                    "h" => Duration::try_hours(count),
+                    "d" => Duration::try_days(count),
+                    _ => None,
+                };
+                match duration {
+                    Some(duration) if count > 0 => Schedule::Every(duration),
+                    _ => return Err(invalid()),
+                }
+            }
+            _ => return Err(invalid()),
+        };
+        if parts.next().is_some() {
+            return Err(invalid());
+        }
+        Ok(schedule)
+    }
+}
+
+impl Schedule {
+    /// First time this schedule fires strictly after `now`.
+    pub fn next_after<Tz: TimeZone>(&self, now: &DateTime<Tz>) -> DateTime<Tz> {
+        match self {
+            Schedule::Every(d) => now.clone() + *d,
+            Schedule::Daily(time) => {
+                let mut day = now.date_naive();
+                loop {
+                    // earliest() skips times that don't exist because of a DST change
+                    if let Some(t) = now.timezone().from_local_datetime(&day.and_time(*time)).earliest() {
+                        if t > *now {
+                            return t;
+                        }
+                    }
+                    day = day.succ_opt().expect("date out of range");
+                }
+            }
+        }
+    }
+}
+
+/// A schedule together with the next time it is due.
+pub struct Timer {
+    schedule: Schedule,
+    next: DateTime<Local>,
+}
+
+impl Timer {
+    pub fn new(schedule: Schedule) -> Self {
+        let next = schedule.next_after(&Local::now());
+        Timer { schedule, next }
+    }
+
+    /// Timer for an optional schedule setting. Settings are validated on load, so an invalid
+    /// value here just disables the timer.
+    pub fn from_setting(setting: &Option<String>) -> Option<Self> {
+        setting.as_ref().and_then(|s| s.parse().ok()).map(Timer::new)
+    }
+
+    /// Returns true if the timer fired; the next run is scheduled at the same time.
+    pub fn due(&mut self) -> bool {
+        let now = Local::now();
+        if now >= self.next {
+            self.next = self.schedule.next_after(&now);
+            return true;
+        }
+        false
+    }
+}
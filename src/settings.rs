@@ -1,8 +1,27 @@
+use serde::{Deserialize, Deserializer};
+
 use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use crate::archive::ArchivePolicy;
+use crate::cleanup::EmptyPolicy;
+use crate::file_manager::WalkOptions;
+use crate::filters::{parse_size, FileFilter};
+use crate::hooks::CommandHook;
+use crate::ignore_rules::IgnoreRules;
+use crate::image_hash::ImageHashKind;
+use crate::logger::LogConfig;
+use crate::mailer::{self, EmailTls};
+use crate::report::ReportFormat;
+use crate::schedule::Schedule;
 
 
 #[derive(Default,Debug,Serialize, Deserialize)]
 pub struct Settings {
+    /// gitignore-style patterns relative to working_dir: "target" matches any file or directory
+    /// named target, "/target" only the one directly in working_dir, "*.tmp" globs, "!keep" negates,
+    /// "re:<regex>" is matched against the absolute path. `.dupignore` files use the same syntax.
     pub ignore_paths: Vec<String>,
     pub working_dir: String,
     pub delete_score: Vec<String>,
@@ -15,20 +34,269 @@ pub struct Settings {
 
     pub watchdog: bool,
 
-    pub email_result_to: Option<String>,
+    /// follow symlinked files and directories; when false, symlinks are skipped
+    #[serde(default)]
+    pub follow_symlinks: bool,
+    /// don't descend into other file systems mounted below working_dir
+    #[serde(default)]
+    pub one_file_system: bool,
+    /// how deep below working_dir to go, 1 means only files directly in it
+    pub max_depth: Option<usize>,
+    /// skip files and directories whose name starts with a dot
+    #[serde(default)]
+    pub skip_hidden: bool,
+
+    /// only files at least / at most this big: bytes or e.g. "100KiB", "2GiB"
+    #[serde(default, deserialize_with = "size_setting")]
+    pub min_size: Option<String>,
+    #[serde(default, deserialize_with = "size_setting")]
+    pub max_size: Option<String>,
+    /// e.g. ["jpg", "cr2", "mp4"]; empty means all
+    #[serde(default)]
+    pub include_extensions: Vec<String>,
+    #[serde(default)]
+    pub exclude_extensions: Vec<String>,
+    /// only files modified before / on or after this date: "YYYY-MM-DD" or RFC 3339
+    pub modified_before: Option<String>,
+    pub modified_after: Option<String>,
+    /// zero-byte files are skipped unless this is true, they would all be duplicates of each other
+    #[serde(default)]
+    pub include_empty_files: bool,
+    /// seconds to wait for a file to settle before it is processed (default 2)
+    pub watch_debounce_secs: Option<u64>,
+    /// how often directories that could not get an OS watch are polled, in seconds (default 60)
+    pub watch_poll_interval_secs: Option<u64>,
+
+    /// while watching: full rescan of working_dir, e.g. "daily 03:00" or "every 6h"
+    pub rescan_schedule: Option<String>,
+    /// while watching: email everything found since the last digest, e.g. "daily 20:00"
+    pub digest_schedule: Option<String>,
+
+    /// one address or a list of them
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub email_result_to: Vec<String>,
+    /// sender, e.g. "Duplicates <duplicates@example.com>"
+    pub email_from: Option<String>,
+    /// SMTP server (default localhost)
+    pub email_hostname: Option<String>,
+    /// default depends on email_tls: 25, 587 or 465
+    pub email_port: Option<u16>,
+    /// "none", "starttls" (default) or "implicit"
+    pub email_tls: Option<EmailTls>,
+    /// leave out if the server doesn't need authentication
     pub email_username: Option<String>,
     pub email_password: Option<String>,
-    pub email_hostname: Option<String>
+    /// how often a failed email is retried, with a growing delay (default 3)
+    pub email_retries: Option<u32>,
+    /// events listed in the email body (default 200), longer reports are attached in full
+    pub email_max_events: Option<usize>,
+    /// URLs the report is POSTed to as JSON, one or a list
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub webhook_urls: Vec<String>,
+    /// default 30
+    pub webhook_timeout_secs: Option<u64>,
+    /// local commands run per duplicate group or per report summary
+    #[serde(default)]
+    pub command_hooks: Vec<CommandHook>,
+    /// attach the full duplicates report to every email: "json", "csv" or "html"
+    pub email_attach_report: Option<ReportFormat>,
+
+    /// error, warn, info (default) or debug
+    pub log_level: Option<String>,
+    /// "text" (default) or "json" - one JSON object per line, for log collectors
+    pub log_format: Option<String>,
+    /// log to stderr (default true)
+    pub log_stderr: Option<bool>,
+    /// also log to this file
+    pub log_file: Option<String>,
+    /// the log file is rotated when it reaches this size (default 10MiB)
+    #[serde(default, deserialize_with = "size_setting")]
+    pub log_file_max_size: Option<String>,
+    /// rotated log files to keep (default 5)
+    pub log_file_keep: Option<usize>,
+
+    /// when running as a service (not on a terminal), log the progress of scans every N seconds (default 60, 0 = never)
+    pub progress_log_secs: Option<u64>,
+
+    /// also hash images perceptually to find ones that look alike (resized, re-saved, edited),
+    /// see `duplicates similar`. They are only reported, never deleted
+    #[serde(default)]
+    pub similar_images: bool,
+    /// "dhash" (default), "ahash" or "phash"
+    pub image_hash: Option<ImageHashKind>,
+    /// how many of the 64 bits may differ for two images to be similar (default 10)
+    pub image_max_distance: Option<u32>,
+    /// JPEG and PNG files are also hashed without their metadata (EXIF, XMP, ICC profiles, comments...),
+    /// files that differ only in those are duplicates
+    #[serde(default)]
+    pub image_content_hash: bool,
+    /// MP3, FLAC, Ogg Vorbis/Opus and WAV files are also hashed without their tags (ID3v1/v2, APE,
+    /// FLAC metadata blocks, Vorbis comments, RIFF chunks), files that differ only in tags are duplicates
+    #[serde(default)]
+    pub audio_content_hash: bool,
+    /// decode audio files and compare acoustic fingerprints of their first two minutes, to also
+    /// find the same recording in another format or bitrate. Slow
+    #[serde(default)]
+    pub audio_fingerprint: bool,
+    /// recordings found by their fingerprints are duplicates the action applies to, instead of
+    /// only being reported as similar audio
+    #[serde(default)]
+    pub audio_fingerprint_delete: bool,
+    /// share of the fingerprint bits that may differ for two files to be the same recording (default 0.25)
+    pub audio_max_bit_error: Option<f64>,
+    /// photos with the same EXIF identity (image unique ID, or time taken and camera) and
+    /// nearly the same pixels are duplicates too, even if their files differ
+    #[serde(default)]
+    pub exif_matching: bool,
+    /// how many bits of the perceptual hashes (image_hash) may differ for exif_matching (default 2)
+    pub exif_max_distance: Option<u32>,
+    /// sample frames of videos with ffmpeg to find re-encoded copies, see `duplicates similar`.
+    /// They are only reported, never deleted
+    #[serde(default)]
+    pub similar_videos: bool,
+    /// how many bits the frame hashes of two videos may differ on average to be similar (default 8)
+    pub video_max_distance: Option<u32>,
+    /// the ffmpeg binary (default "ffmpeg" from PATH), ffprobe is expected next to it
+    pub ffmpeg: Option<String>,
+    /// after a scan, find directories with the same files as another one (or whose files are all
+    /// in another one) and handle them as a whole instead of file by file
+    #[serde(default)]
+    pub directory_duplicates: bool,
+    /// also hash the files inside zip, tar and tar.gz archives, as "archive.zip!/dir/file.jpg".
+    /// They take part in duplicate groups but are never deleted
+    #[serde(default)]
+    pub scan_archives: bool,
+    /// "report" (default), "delete_files" or "delete_archives", see ArchivePolicy
+    pub archive_policy: Option<ArchivePolicy>,
+    /// after files were deleted, remove the directories they leave empty
+    #[serde(default)]
+    pub remove_empty_dirs: bool,
+    /// how many levels above a deleted file are removed when empty, default: up to working_dir (never itself)
+    pub remove_empty_dirs_levels: Option<usize>,
+    /// files and directories that are never removed by the cleanup of empty ones (ignore_paths syntax)
+    #[serde(default)]
+    pub protected_paths: Vec<String>,
+    /// zero-byte files and empty directories that were there before: "report" or "delete" (with action D),
+    /// see EmptyPolicy. Left alone by default
+    pub existing_empty: Option<EmptyPolicy>,
+    /// bytes per second `duplicates verify` reads at most, e.g. "20MiB". Not limited by default
+    #[serde(default, deserialize_with = "size_setting")]
+    pub verify_rate: Option<String>,
+    /// `duplicates verify` copies an intact copy over a corrupted file instead of only reporting it
+    #[serde(default)]
+    pub verify_restore: bool,
+
+    /// not part of the config, use `ignore_rules()`
+    #[serde(skip)]
+    pub ignore_rules: OnceLock<IgnoreRules>,
+    /// not part of the config, use `file_filter()`
+    #[serde(skip)]
+    pub file_filter: OnceLock<FileFilter>,
+    /// not part of the config, use `protected_rules()`
+    #[serde(skip)]
+    pub protected_rules: OnceLock<IgnoreRules>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SizeSetting {
+    Bytes(u64),
+    Text(String),
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum OneOrMany {
+    One(String),
+    Many(Vec<String>),
+}
+
+fn one_or_many<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(s) => vec![s],
+        OneOrMany::Many(v) => v,
+    })
+}
+
+/// sizes can be given as a number of bytes or as a string with a unit
+fn size_setting<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<String>, D::Error> {
+    Ok(Option::<SizeSetting>::deserialize(deserializer)?.map(|s| match s {
+        SizeSetting::Bytes(b) => b.to_string(),
+        SizeSetting::Text(t) => t,
+    }))
 }
 impl Settings {
-    pub fn new() -> Result<Self, std::io::Error> {
-       let s = fs::read_to_string("config.toml")?;
+    pub fn load(path: &str) -> Result<Self, std::io::Error> {
+       let s = fs::read_to_string(path)?;
        
        let r = toml::from_str::<Settings>(s.as_str());
        if r.is_err() {
-            return Err(std::io::Error::new(std::io::ErrorKind::Other, "Unable to read config"));
+            return Err(std::io::Error::other("Unable to read config"));
+       }
+       let settings = r.unwrap();
+       for schedule in [&settings.rescan_schedule, &settings.digest_schedule].into_iter().flatten() {
+            schedule.parse::<Schedule>().map_err(std::io::Error::other)?;
+       }
+       IgnoreRules::new(Path::new(&settings.working_dir), &settings.ignore_paths).map_err(std::io::Error::other)?;
+       IgnoreRules::config_only(Path::new(&settings.working_dir), &settings.protected_paths)
+            .map_err(|e| std::io::Error::other(format!("protected_paths: {}", e)))?;
+       FileFilter::from_settings(&settings).map_err(std::io::Error::other)?;
+       settings.verify_rate.as_deref().map(parse_size).transpose()
+            .map_err(|e| std::io::Error::other(format!("verify_rate: {}", e)))?;
+       LogConfig::from_settings(&settings).map_err(std::io::Error::other)?;
+       mailer::validate(&settings).map_err(std::io::Error::other)?;
+       if settings.image_max_distance.is_some_and(|d| d > 64) || settings.exif_max_distance.is_some_and(|d| d > 64) {
+            return Err(std::io::Error::other("image_max_distance and exif_max_distance can be at most 64"));
        }
-       Ok(r.unwrap())
+       if settings.video_max_distance.is_some_and(|d| d > 64) {
+            return Err(std::io::Error::other("video_max_distance can be at most 64"));
+       }
+       if settings.audio_max_bit_error.is_some_and(|e| !(0.0..=1.0).contains(&e)) {
+            return Err(std::io::Error::other("audio_max_bit_error has to be between 0 and 1"));
+       }
+       Ok(settings)
+    }
+
+    /// Options for walking `root`, which is working_dir or a directory below it.
+    pub fn walk_options(&self, root: &Path) -> WalkOptions {
+        // max_depth counts from working_dir, also when walking a directory below it
+        let depth = root.strip_prefix(&self.working_dir).map(|p| p.components().count()).unwrap_or(0);
+        WalkOptions {
+            follow_symlinks: self.follow_symlinks,
+            one_file_system: self.one_file_system,
+            max_depth: self.max_depth.map(|m| m.saturating_sub(depth)),
+            skip_hidden: self.skip_hidden,
+        }
+    }
+
+    /// Filter settings parsed on first use. They are validated on load, so this shouldn't fail.
+    pub fn file_filter(&self) -> &FileFilter {
+        self.file_filter.get_or_init(|| FileFilter::from_settings(self).unwrap_or_else(|e| {
+            log::error!("{}, files are not filtered", e);
+            FileFilter::default()
+        }))
+    }
+
+    /// Compiled `ignore_paths`, built on first use.
+    pub fn ignore_rules(&self) -> &IgnoreRules {
+        self.ignore_rules.get_or_init(|| {
+            let root = fs::canonicalize(&self.working_dir).unwrap_or_else(|_| PathBuf::from(&self.working_dir));
+            IgnoreRules::new(&root, &self.ignore_paths).unwrap_or_else(|e| {
+                log::error!("{}, nothing will be ignored", e);
+                IgnoreRules::default()
+            })
+        })
+    }
+
+    /// Compiled `protected_paths`, built on first use.
+    pub fn protected_rules(&self) -> &IgnoreRules {
+        self.protected_rules.get_or_init(|| {
+            let root = fs::canonicalize(&self.working_dir).unwrap_or_else(|_| PathBuf::from(&self.working_dir));
+            IgnoreRules::config_only(&root, &self.protected_paths).unwrap_or_else(|e| {
+                log::error!("protected_paths: {}, nothing is protected", e);
+                IgnoreRules::config_only(&root, &[]).unwrap_or_default()
+            })
+        })
     }
 
 }
@@ -0,0 +1,156 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::time::Instant;
+
+use crate::datastore::FileInfo;
+use crate::report::format_bytes;
+
+/// Counters of one scan, saved with its row in `scan_runs`.
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub struct ScanSummary {
+    /// files that passed the filters
+    pub files_scanned: u64,
+    /// files whose hash had to be calculated
+    pub files_hashed: u64,
+    /// files whose hash was still valid in the database
+    pub files_cached: u64,
+    /// bytes read while hashing
+    pub bytes_read: u64,
+    pub duplicate_groups: u64,
+    /// copies beyond the first one of every group
+    pub redundant_copies: u64,
+    /// what deleting every redundant copy frees
+    pub bytes_reclaimable: u64,
+    /// what action D actually deleted
+    pub bytes_freed: u64,
+    pub elapsed_secs: u64,
+}
+
+impl ScanSummary {
+    /// Counters of both parts of a resumed scan together.
+    pub fn add(&self, other: &ScanSummary) -> ScanSummary {
+        ScanSummary {
+            files_scanned: self.files_scanned + other.files_scanned,
+            files_hashed: self.files_hashed + other.files_hashed,
+            files_cached: self.files_cached + other.files_cached,
+            bytes_read: self.bytes_read + other.bytes_read,
+            duplicate_groups: self.duplicate_groups + other.duplicate_groups,
+            redundant_copies: self.redundant_copies + other.redundant_copies,
+            bytes_reclaimable: self.bytes_reclaimable + other.bytes_reclaimable,
+            bytes_freed: self.bytes_freed + other.bytes_freed,
+            elapsed_secs: self.elapsed_secs + other.elapsed_secs,
+        }
+    }
+}
+
+impl fmt::Display for ScanSummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Files scanned: {} ({} hashed, {} from cache)", self.files_scanned, self.files_hashed, self.files_cached)?;
+        writeln!(f, "Read: {}", format_bytes(self.bytes_read))?;
+        writeln!(f, "Duplicates: {} groups, {} redundant copies", self.duplicate_groups, self.redundant_copies)?;
+        writeln!(f, "Reclaimable: {}", format_bytes(self.bytes_reclaimable))?;
+        writeln!(f, "Freed: {}", format_bytes(self.bytes_freed))?;
+        write!(f, "Elapsed: {}", format_duration(self.elapsed_secs))
+    }
+}
+
+/// Collects the counters while files are processed.
+#[derive(Debug)]
+pub struct ScanStats {
+    /// counters of the interrupted part, when a scan is resumed
+    previous: ScanSummary,
+    started: Instant,
+    pub files_scanned: u64,
+    pub files_hashed: u64,
+    pub files_cached: u64,
+    pub bytes_read: u64,
+    /// size of the scanned files, hashed or not
+    pub bytes_scanned: u64,
+    pub bytes_freed: u64,
+    /// size and every path seen so far, per hash. A group is seen again for every
+    /// file of it that is processed, so copies are counted by path.
+    groups: HashMap<String, (u64, HashSet<String>)>,
+    /// paths added to `groups` since `take_unsaved`, with the hash and size of their group
+    unsaved: Vec<FileInfo>,
+}
+
+impl Default for ScanStats {
+    fn default() -> Self {
+        ScanStats {
+            previous: ScanSummary::default(),
+            started: Instant::now(),
+            files_scanned: 0,
+            files_hashed: 0,
+            files_cached: 0,
+            bytes_read: 0,
+            bytes_scanned: 0,
+            bytes_freed: 0,
+            groups: HashMap::new(),
+            unsaved: vec![],
+        }
+    }
+}
+
+impl ScanStats {
+    /// Counts on top of the summary of an interrupted scan. Its groups are counted again from
+    /// the `copies` it saved, a group seen before and after the interruption is only one group.
+    /// Scans saved without copies keep their counts.
+    pub fn resuming(previous: ScanSummary, copies: Vec<FileInfo>) -> Self {
+        let previous = if copies.is_empty() {
+            previous
+        } else {
+            ScanSummary { duplicate_groups: 0, redundant_copies: 0, bytes_reclaimable: 0, ..previous }
+        };
+        let mut stats = ScanStats { previous, ..Default::default() };
+        for copy in &copies {
+            stats.record_duplicates(std::slice::from_ref(copy));
+        }
+        stats.unsaved.clear();
+        stats
+    }
+
+    /// Remembers a group of files with the same content.
+    pub fn record_duplicates(&mut self, dups: &[FileInfo]) {
+        let Some(first) = dups.first() else {
+            return;
+        };
+        let (_, paths) = self.groups.entry(first.hash.clone()).or_insert_with(|| (first.size, HashSet::new()));
+        for dup in dups {
+            if paths.insert(dup.full_path.clone()) {
+                self.unsaved.push(FileInfo { hash: first.hash.clone(), size: first.size, ..dup.clone() });
+            }
+        }
+    }
+
+    /// The copies recorded since the last call, to be saved with the scan run.
+    pub fn take_unsaved(&mut self) -> Vec<FileInfo> {
+        std::mem::take(&mut self.unsaved)
+    }
+
+    pub fn summary(&self) -> ScanSummary {
+        let redundant = |paths: &HashSet<String>| paths.len().saturating_sub(1) as u64;
+        self.previous.add(&ScanSummary {
+            files_scanned: self.files_scanned,
+            files_hashed: self.files_hashed,
+            files_cached: self.files_cached,
+            bytes_read: self.bytes_read,
+            duplicate_groups: self.groups.values().filter(|(_, paths)| paths.len() > 1).count() as u64,
+            redundant_copies: self.groups.values().map(|(_, paths)| redundant(paths)).sum(),
+            bytes_reclaimable: self.groups.values().map(|(size, paths)| size * redundant(paths)).sum(),
+            bytes_freed: self.bytes_freed,
+            elapsed_secs: self.started.elapsed().as_secs(),
+        })
+    }
+}
+
+/// "1h 2m 3s"
+pub fn format_duration(secs: u64) -> String {
+    let (h, m, s) = (secs / 3600, secs / 60 % 60, secs % 60);
+    if h > 0 {
+        format!("{}h {}m {}s", h, m, s)
+    } else if m > 0 {
+        format!("{}m {}s", m, s)
+    } else {
+        format!("{}s", s)
+    }
+}
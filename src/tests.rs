@@ -15,26 +15,1459 @@
         d_mock.expect_delete_entry_for_path().with(eq("1")).times(1).return_once(move |_x| Ok(()));
         d_mock.expect_delete_entry_for_path().with(eq("2")).times(1).return_once(move |_x| Ok(()));
 
-        delete(vec![String::from("1"),String::from("2"),String::from("3")], &f_mock, &d_mock);       
+        delete(vec![String::from("1"),String::from("2"),String::from("3")], &f_mock, &d_mock, &mut ReportEvents::new());       
     }
 
     #[test]
     fn test_d_no_delete_if_only_1() {
         let f_mock = MockHandleFiles::new();        
         let d_mock = MockDataManager::new();
-        delete(vec![String::from("1")], &f_mock, &d_mock);
+        delete(vec![String::from("1")], &f_mock, &d_mock, &mut ReportEvents::new());
     }
 
    #[test]
    fn test_the_same_entry_twice() {
 	let f_mock = MockHandleFiles::new();
 	let d_mock = MockDataManager::new();
-	delete(vec![String::from("1"), String::from("1")], &f_mock, &d_mock);
+	delete(vec![String::from("1"), String::from("1")], &f_mock, &d_mock, &mut ReportEvents::new());
    }
 
    #[test]
    fn test_empty_vector_dont_crash() {
 	let f_mock = MockHandleFiles::new();
 	let d_mock = MockDataManager::new();
-	delete(vec![], &f_mock, &d_mock);
-   }
\ No newline at end of file
+	delete(vec![], &f_mock, &d_mock, &mut ReportEvents::new());
+   }
+   #[test]
+   fn test_changed_paths_skips_access_and_duplicates() {
+	use notify::event::{AccessKind, CreateKind, Event, EventKind, ModifyKind};
+	use notify_debouncer_full::DebouncedEvent;
+	use std::time::Instant;
+	let event = |kind, path: &str| DebouncedEvent::new(Event::new(kind).add_path(PathBuf::from(path)), Instant::now());
+	let events = vec![
+	    event(EventKind::Create(CreateKind::File), "/a"),
+	    event(EventKind::Access(AccessKind::Read), "/b"),
+	    event(EventKind::Modify(ModifyKind::Any), "/a"),
+	];
+	assert_eq!(watcher::changed_paths(events), vec![PathBuf::from("/a")]);
+   }
+
+   #[test]
+   fn test_schedule_parsing() {
+	use schedule::Schedule;
+	use chrono::{Duration, NaiveTime};
+	assert_eq!("every 6h".parse::<Schedule>(), Ok(Schedule::Every(Duration::hours(6))));
+	assert_eq!("hourly".parse::<Schedule>(), Ok(Schedule::Every(Duration::hours(1))));
+	assert_eq!("daily 03:30".parse::<Schedule>(), Ok(Schedule::Daily(NaiveTime::from_hms_opt(3, 30, 0).unwrap())));
+	assert!("every 0m".parse::<Schedule>().is_err());
+	assert!("every 5x".parse::<Schedule>().is_err());
+	assert!("every 5µ".parse::<Schedule>().is_err());
+	assert!("every µ".parse::<Schedule>().is_err());
+	assert!("every 9223372036854775807d".parse::<Schedule>().is_err());
+	assert!("daily 25:00".parse::<Schedule>().is_err());
+	assert!("weekly".parse::<Schedule>().is_err());
+   }
+
+   #[test]
+   fn test_daily_schedule_next_run() {
+	use schedule::Schedule;
+	use chrono::{TimeZone, Utc};
+	let daily: Schedule = "daily 03:00".parse().unwrap();
+	let before = Utc.with_ymd_and_hms(2023, 5, 1, 2, 0, 0).unwrap();
+	let after = Utc.with_ymd_and_hms(2023, 5, 1, 3, 0, 0).unwrap();
+	assert_eq!(daily.next_after(&before), Utc.with_ymd_and_hms(2023, 5, 1, 3, 0, 0).unwrap());
+	assert_eq!(daily.next_after(&after), Utc.with_ymd_and_hms(2023, 5, 2, 3, 0, 0).unwrap());
+   }
+
+   #[test]
+   fn test_before_checkpoint_follows_walk_order() {
+	let checkpoint = Path::new("root/b/c");
+	assert!(before_checkpoint(Path::new("root/a"), checkpoint));
+	assert!(before_checkpoint(Path::new("root/a/z"), checkpoint));
+	assert!(before_checkpoint(Path::new("root/b/a.jpg"), checkpoint));
+	// directories leading to the checkpoint must still be entered
+	assert!(!before_checkpoint(Path::new("root"), checkpoint));
+	assert!(!before_checkpoint(Path::new("root/b"), checkpoint));
+	assert!(!before_checkpoint(Path::new("root/b/c"), checkpoint));
+	assert!(!before_checkpoint(Path::new("root/b/c/a.jpg"), checkpoint));
+	assert!(!before_checkpoint(Path::new("root/b/d"), checkpoint));
+	assert!(!before_checkpoint(Path::new("root/c"), checkpoint));
+   }
+
+   /// A row for the file at `path` as a scan would write it.
+   fn entry_for(path: &Path) -> FileInfo {
+	let meta = std::fs::metadata(path).unwrap();
+	FileInfo {
+	    full_path: path.to_str().unwrap().to_string(),
+	    size: meta.len(),
+	    hash: calculate_hash_for_file(&mut File::open(path).unwrap()),
+	    last_modified: meta.modified().unwrap().duration_since(UNIX_EPOCH).unwrap().as_secs(),
+	    content_hash: None,
+	}
+   }
+   #[test]
+   fn test_prune_refreshes_changed_files() {
+	let root = TempDir::new("prune-refresh");
+	std::fs::create_dir_all(root.join("photos")).unwrap();
+	let photos = root.join("photos");
+	for name in ["same.jpg", "edited.jpg", "retouched.jpg"] {
+	    std::fs::write(photos.join(name), "before").unwrap();
+	}
+	let data_manager = DataStore::at(root.join("hashes.db").to_str().unwrap());
+	data_manager.create_tables().unwrap();
+	for name in ["same.jpg", "edited.jpg", "retouched.jpg"] {
+	    data_manager.add_entry(&entry_for(&photos.join(name))).unwrap();
+	}
+	std::fs::write(photos.join("edited.jpg"), "after the edit").unwrap();
+	let settings = Settings { working_dir: photos.to_str().unwrap().to_string(), ..Default::default() };
+	let row = |name: &str| data_manager.get_entry_for_path(photos.join(name).to_str().unwrap()).unwrap();
+
+	let stats = reconcile::prune(&settings, true, &FileManager::new(), &data_manager);
+	assert_eq!(stats, reconcile::PruneStats { checked: 3, refreshed: 1, ..Default::default() });
+	assert_eq!(row("edited.jpg"), Some(entry_for(&photos.join("edited.jpg"))));
+	assert_eq!(row("same.jpg"), Some(entry_for(&photos.join("same.jpg"))));
+
+	// without refresh the row of a changed file goes
+	std::fs::write(photos.join("retouched.jpg"), "after retouching").unwrap();
+	let stats = reconcile::prune(&settings, false, &FileManager::new(), &data_manager);
+	assert_eq!(stats, reconcile::PruneStats { checked: 3, deleted_changed: 1, ..Default::default() });
+	assert_eq!(row("retouched.jpg"), None);
+   }
+   #[test]
+   fn test_prune_deletes_rows_outside_working_dir_or_ignored() {
+	let root = TempDir::new("prune-outside");
+	std::fs::create_dir_all(root.join("photos")).unwrap();
+	std::fs::create_dir_all(root.join("elsewhere")).unwrap();
+	let files = [root.join("photos/a.jpg"), root.join("photos/a.tmp"), root.join("elsewhere/a.jpg")];
+	let data_manager = DataStore::at(root.join("hashes.db").to_str().unwrap());
+	data_manager.create_tables().unwrap();
+	for file in &files {
+	    std::fs::write(file, "a").unwrap();
+	    data_manager.add_entry(&entry_for(file)).unwrap();
+	}
+	let settings = Settings { working_dir: root.join("photos").to_str().unwrap().to_string(),
+	    ignore_paths: vec![String::from("*.tmp")], ..Default::default() };
+
+	let stats = reconcile::prune(&settings, true, &FileManager::new(), &data_manager);
+	assert_eq!(stats, reconcile::PruneStats { checked: 3, outside_roots: 2, ..Default::default() });
+	assert_eq!(data_manager.get_all_entries().unwrap(), [entry_for(&files[0])]);
+	// the files themselves stay
+	assert!(files.iter().all(|f| f.exists()));
+   }
+   #[test]
+   fn test_prune_deletes_missing_files() {
+	let mut f_mock = MockHandleFiles::new();
+	let mut d_mock = MockDataManager::new();
+	f_mock.expect_get_full_path().returning(|p| Ok(p.to_path_buf()));
+	d_mock.expect_get_all_entries().times(1).return_once(|| Ok(vec![FileInfo {
+	    full_path: String::from("/this/file/does/not/exist"),
+	    size: 1,
+	    hash: String::from("h"),
+	    last_modified: 1,
+	    content_hash: None,
+	}]));
+	d_mock.expect_delete_entry_for_path().with(eq("/this/file/does/not/exist")).times(1).return_once(|_x| Ok(()));
+	let settings = Settings { working_dir: String::from("/this"), ..Default::default() };
+	let stats = reconcile::prune(&settings, true, &f_mock, &d_mock);
+	assert_eq!(stats.checked, 1);
+	assert_eq!(stats.missing, 1);
+	assert_eq!(stats.cleaned(), 1);
+   }
+
+   #[test]
+   fn test_ignore_rules_match_components_not_substrings() {
+	use ignore_rules::IgnoreRules;
+	let root = Path::new("/photos");
+	let rules = IgnoreRules::new(root, &[String::from("src"), String::from("/target"), String::from("*.tmp"),
+	    String::from("!keep.tmp"), String::from("re:/cache[0-9]+/")]).unwrap();
+	assert!(rules.is_ignored(Path::new("/photos/a/src"), true));
+	assert!(rules.is_ignored(Path::new("/photos/a/src/b.jpg"), false));
+	assert!(!rules.is_ignored(Path::new("/photos/resources/b.jpg"), false));
+	assert!(rules.is_ignored(Path::new("/photos/target/b.jpg"), false));
+	assert!(!rules.is_ignored(Path::new("/photos/a/target/b.jpg"), false));
+	assert!(rules.is_ignored(Path::new("/photos/a/b.tmp"), false));
+	assert!(!rules.is_ignored(Path::new("/photos/a/keep.tmp"), false));
+	assert!(rules.is_ignored(Path::new("/photos/cache12/b.jpg"), false));
+	assert!(!rules.is_ignored(Path::new("/photos/cache/b.jpg"), false));
+   }
+
+   #[test]
+   fn test_invalid_ignore_regex_is_reported() {
+	assert!(ignore_rules::IgnoreRules::new(Path::new("/"), &[String::from("re:(")]).is_err());
+   }
+
+   #[test]
+   fn test_dupignore_overrides_config() {
+	use ignore_rules::{IgnoreRules, DUPIGNORE};
+	let root = TempDir::new("dupignore");
+	std::fs::create_dir_all(root.join("album")).unwrap();
+	std::fs::write(root.join("album").join(DUPIGNORE), "!*.jpg\nraw/\n").unwrap();
+	let rules = IgnoreRules::new(&root, &[String::from("*.jpg")]).unwrap();
+	assert!(rules.is_ignored(&root.join("a.jpg"), false));
+	assert!(!rules.is_ignored(&root.join("album").join("a.jpg"), false));
+	assert!(rules.is_ignored(&root.join("album").join("raw").join("a.cr2"), false));
+   }
+
+   #[test]
+   fn test_parse_size() {
+	use filters::parse_size;
+	assert_eq!(parse_size("1024"), Ok(1024));
+	assert_eq!(parse_size("100KiB"), Ok(100 * 1024));
+	assert_eq!(parse_size("1.5M"), Ok(3 * 512 * 1024));
+	assert!(parse_size("10 parsecs").is_err());
+	assert!(parse_size("").is_err());
+   }
+
+   #[test]
+   fn test_file_filter() {
+	use filters::FileFilter;
+	let filter = FileFilter {
+	    min_size: Some(100),
+	    include_extensions: vec![String::from("jpg"), String::from("cr2")],
+	    modified_after: Some(1000),
+	    ..Default::default()
+	};
+	assert!(filter.accepts_values(Path::new("/a/b.JPG"), 100, 1000));
+	assert!(!filter.accepts_values(Path::new("/a/b.jpg"), 99, 1000));
+	assert!(!filter.accepts_values(Path::new("/a/b.png"), 100, 1000));
+	assert!(!filter.accepts_values(Path::new("/a/b.jpg"), 100, 999));
+	let default = FileFilter::default();
+	assert!(!default.accepts_values(Path::new("/a/empty"), 0, 1000));
+	assert!(FileFilter { include_empty_files: true, ..Default::default() }.accepts_values(Path::new("/a/empty"), 0, 1000));
+   }
+
+   #[test]
+   fn test_walk_settings_for_watched_paths() {
+	let settings = Settings { working_dir: String::from("/w"), max_depth: Some(2), skip_hidden: true, ..Default::default() };
+	assert!(is_walked(Path::new("/w/a/b"), &settings));
+	assert!(!is_walked(Path::new("/w/a/b/c"), &settings));
+	assert!(!is_walked(Path::new("/w/.git/x"), &settings));
+	assert_eq!(settings.walk_options(Path::new("/w/a")).max_depth, Some(1));
+	assert_eq!(settings.walk_options(Path::new("/w")).max_depth, Some(2));
+   }
+
+   #[test]
+   fn test_duplicate_groups_report() {
+	let mut d_mock = MockDataManager::new();
+	d_mock.expect_get_duplicate_hashes().times(1).return_once(|| Ok(vec![String::from("h")]));
+	d_mock.expect_get_entries_by_hash().with(eq("h")).times(1).return_once(|_x| Ok(vec![
+	    FileInfo { full_path: String::from("src/main.rs"), size: 10, hash: String::from("h"), last_modified: 2, content_hash: None },
+	    FileInfo { full_path: String::from("Cargo.toml"), size: 10, hash: String::from("h"), last_modified: 1, content_hash: None },
+	    FileInfo { full_path: String::from("/no/such/file"), size: 10, hash: String::from("h"), last_modified: 1, content_hash: None },
+	]));
+	d_mock.expect_delete_entry_for_path().with(eq("/no/such/file")).times(1).return_once(|_x| Ok(()));
+	let settings = Settings { delete_score: vec![String::from("src")], ..Default::default() };
+	let groups = report::duplicate_groups(&settings, &d_mock);
+	assert_eq!(groups.len(), 1);
+	assert_eq!(groups[0].survivor, "Cargo.toml");
+	assert_eq!(groups[0].reclaimable_bytes, 10);
+	assert_eq!(groups[0].files[0].path, "Cargo.toml");
+	let json = report::render_json(&report::Report::new(groups));
+	assert!(json.contains("\"schema_version\": 1"));
+	assert!(json.contains("\"total_reclaimable_bytes\": 10"));
+   }
+   #[test]
+   fn test_csv_and_html_reports() {
+	use report::{DuplicateGroup, GroupFile, Report, ReportFormat};
+	let report = Report::new(vec![DuplicateGroup {
+	    hash: String::from("h"),
+	    size: 2048,
+	    files: vec![
+	        GroupFile { path: String::from("/photos/a, b.jpg"), last_modified: 1 },
+	        GroupFile { path: String::from("/photos/<c>.jpg"), last_modified: 2 },
+	    ],
+	    survivor: String::from("/photos/a, b.jpg"),
+	    reclaimable_bytes: 2048,
+	}]);
+	let csv = report::render(&report, ReportFormat::Csv);
+	let lines: Vec<&str> = csv.lines().collect();
+	assert_eq!(lines[0], "hash,size,path,last_modified,survivor,reclaimable_bytes");
+	assert_eq!(lines[1], "h,2048,\"/photos/a, b.jpg\",1,true,2048");
+	assert_eq!(lines[2], "h,2048,/photos/<c>.jpg,2,false,2048");
+	let html = report::render(&report, ReportFormat::Html);
+	assert!(html.contains("2.0 KiB to free"));
+	assert!(html.contains(r#"<img src="file:///photos/a%2C%20b.jpg""#));
+	assert!(html.contains("&lt;c&gt;.jpg"));
+	assert!(!html.contains("<c>"));
+	assert_eq!(report.attachment(ReportFormat::Html).filename, "duplicates-report.html");
+   }
+   #[test]
+   fn test_scan_stats_count_each_copy_once() {
+	use stats::ScanStats;
+	let file = |path: &str| FileInfo { full_path: String::from(path), size: 100, hash: String::from("h"), last_modified: 1, content_hash: None };
+	let mut stats = ScanStats::default();
+	// the group is seen again for every file of it that is processed
+	stats.record_duplicates(&[file("a"), file("b")]);
+	stats.record_duplicates(&[file("a"), file("b"), file("c")]);
+	stats.files_hashed = 3;
+	let summary = stats.summary();
+	assert_eq!(summary.duplicate_groups, 1);
+	assert_eq!(summary.redundant_copies, 2);
+	assert_eq!(summary.bytes_reclaimable, 200);
+	// scans saved before copies were saved keep their counts
+	let resumed = ScanStats::resuming(summary.clone(), vec![]).summary();
+	assert_eq!(resumed.files_hashed, 3);
+	assert_eq!(resumed.redundant_copies, 2);
+
+	let copies = stats.take_unsaved();
+	assert_eq!(copies.len(), 3);
+	assert!(stats.take_unsaved().is_empty());
+	let mut resumed = ScanStats::resuming(summary, copies);
+	// the group is seen again after the interruption
+	resumed.record_duplicates(&[file("a"), file("b"), file("c"), file("d")]);
+	assert_eq!(resumed.take_unsaved(), [file("d")]);
+	let resumed = resumed.summary();
+	assert_eq!(resumed.files_hashed, 3);
+	assert_eq!(resumed.duplicate_groups, 1);
+	assert_eq!(resumed.redundant_copies, 3);
+	assert_eq!(resumed.bytes_reclaimable, 300);
+   }
+   #[test]
+   fn test_log_record_formats() {
+	use logger::{format_record, LogFormat};
+	let fields = [("path", log::kv::Value::from("/a b")), ("size", log::kv::Value::from(10u64))];
+	let format = |f| format_record(&log::Record::builder()
+	    .args(format_args!("DELETE: {}", "/a b"))
+	    .level(log::Level::Info)
+	    .target("duplicates")
+	    .key_values(&fields)
+	    .build(), f);
+	assert!(format(LogFormat::Text).ends_with("INFO  DELETE: /a b path=/a b size=10\n"));
+	let json: serde_json::Value = serde_json::from_str(&format(LogFormat::Json)).unwrap();
+	assert_eq!(json["level"], "info");
+	assert_eq!(json["msg"], "DELETE: /a b");
+	assert_eq!(json["path"], "/a b");
+	assert_eq!(json["size"], 10);
+   }
+   #[test]
+   fn test_report_events_are_capped() {
+	let mut events = ReportEvents::new();
+	for i in 0..10_005 {
+	    events.push(ReportEvent::HashChanged { path: i.to_string() });
+	}
+	let body = events.take().full_text();
+	assert!(body.starts_with("HASH changed for file : 0 ! "));
+	assert!(body.ends_with("... and 5 more events, see the log\n"));
+	assert!(events.is_empty());
+   }
+   #[test]
+   fn test_progress_snapshot() {
+	use progress::Snapshot;
+	use std::time::Duration;
+	let mut snapshot = Snapshot {
+	    discovered_files: 10,
+	    discovered_bytes: 4000,
+	    counting_done: false,
+	    processed_files: 5,
+	    processed_bytes: 1000,
+	    bytes_hashed: 2048,
+	    elapsed: Duration::from_secs(2),
+	    dir: PathBuf::from("/photos/2020/holidays"),
+	};
+	// no ETA while the total is still growing
+	assert_eq!(snapshot.eta(), None);
+	assert_eq!(snapshot.to_string(), "5/10+ files, 2.0 KiB hashed, 1.0 KiB/s, in /photos/2020/holidays");
+	snapshot.counting_done = true;
+	assert_eq!(snapshot.eta(), Some(Duration::from_secs(6)));
+	assert_eq!(snapshot.to_string(), "5/10 files, 2.0 KiB hashed, 1.0 KiB/s, ETA 6s, in /photos/2020/holidays");
+	let short = snapshot.line(60);
+	assert_eq!(short.chars().count(), 60);
+	assert!(short.ends_with("in …/holidays"));
+   }
+   /// Minimal SMTP server for one connection. Recipients containing "reject" get a permanent error.
+   /// Returns the port and a receiver for the recipients and the DATA of each email.
+   fn smtp_sink() -> (u16, std::sync::mpsc::Receiver<(Vec<String>, String)>) {
+	use std::io::{BufRead, BufReader, Write};
+	let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+	let port = listener.local_addr().unwrap().port();
+	let (tx, rx) = std::sync::mpsc::channel();
+	std::thread::spawn(move || {
+	    let (stream, _) = listener.accept().unwrap();
+	    let mut reader = BufReader::new(stream.try_clone().unwrap());
+	    let mut out = stream;
+	    let mut recipients = vec![];
+	    out.write_all(b"220 sink ESMTP\r\n").unwrap();
+	    let mut line = String::new();
+	    while reader.read_line(&mut line).unwrap_or(0) > 0 {
+	        let command = line.trim_end().to_string();
+	        line.clear();
+	        let reply: &[u8] = if command.starts_with("EHLO") || command.starts_with("MAIL") {
+	            b"250 OK\r\n"
+	        } else if command.starts_with("RCPT") && command.contains("reject") {
+	            b"550 no such user\r\n"
+	        } else if command.starts_with("RCPT") {
+	            recipients.push(command);
+	            b"250 OK\r\n"
+	        } else if command == "DATA" {
+	            out.write_all(b"354 go ahead\r\n").unwrap();
+	            let mut data = String::new();
+	            while !data.ends_with("\r\n.\r\n") && reader.read_line(&mut data).unwrap_or(0) > 0 {}
+	            tx.send((std::mem::take(&mut recipients), data)).unwrap();
+	            b"250 queued\r\n"
+	        } else if command == "QUIT" {
+	            out.write_all(b"221 bye\r\n").unwrap();
+	            break;
+	        } else {
+	            b"250 OK\r\n"
+	        };
+	        out.write_all(reply).unwrap();
+	    }
+	});
+	(port, rx)
+   }
+   fn sink_settings(port: u16, to: &[&str]) -> Settings {
+	Settings {
+	    email_result_to: to.iter().map(|t| t.to_string()).collect(),
+	    email_from: Some(String::from("Duplicates <dup@example.com>")),
+	    email_hostname: Some(String::from("127.0.0.1")),
+	    email_port: Some(port),
+	    email_tls: Some(mailer::EmailTls::None),
+	    ..Default::default()
+	}
+   }
+   #[test]
+   fn test_email_sent_to_all_recipients() {
+	let (port, rx) = smtp_sink();
+	let settings = sink_settings(port, &["a@example.com", "b@example.com"]);
+	let body = ReportBody { events: vec![ReportEvent::HashChanged { path: String::from("/a") }], dropped: 0 };
+	mailer::send_report(&settings, &body, vec![]).unwrap();
+	let (recipients, data) = rx.recv_timeout(std::time::Duration::from_secs(5)).unwrap();
+	assert_eq!(recipients, vec!["RCPT TO:<a@example.com>", "RCPT TO:<b@example.com>"]);
+	assert!(data.contains("From: Duplicates <dup@example.com>"));
+	assert!(data.contains("HASH changed for file : /a"));
+   }
+   #[test]
+   fn test_email_skipped_or_failing() {
+	// nothing to report: no connection is made at all
+	let settings = sink_settings(1, &["a@example.com"]);
+	assert_eq!(mailer::send_report(&settings, &ReportBody::default(), vec![]), Ok(()));
+	// rejected recipients are not retried
+	let (port, _rx) = smtp_sink();
+	let settings = sink_settings(port, &["reject@example.com"]);
+	let start = std::time::Instant::now();
+	let body = ReportBody { events: vec![ReportEvent::HashChanged { path: String::from("/a") }], dropped: 0 };
+	assert!(mailer::send_report(&settings, &body, vec![]).is_err());
+	assert!(start.elapsed() < std::time::Duration::from_secs(5));
+	let settings = Settings { email_from: Some(String::from("not an address")), ..Default::default() };
+	assert!(mailer::validate(&settings).is_err());
+   }
+   #[test]
+   fn test_long_email_report_is_truncated() {
+	let duplicates = |i: usize| ReportEvent::Duplicates {
+	    delete: vec![format!("/download/<{}>.jpg", i)], keep: format!("/photos/{}.jpg", i), deleted: true,
+	};
+	let body = ReportBody { events: (0..5).map(duplicates).collect(), dropped: 0 };
+	assert!(body.summary().starts_with("Duplicates found: 5 (5 files deleted, 0 to delete)"));
+	let html = body.html(2);
+	assert!(html.contains("<tr><td>deleted</td><td>/download/&lt;1&gt;.jpg</td></tr>"));
+	assert!(!html.contains("/photos/2.jpg"));
+	assert!(html.contains("3 more events"));
+	let settings = Settings {
+	    email_result_to: vec![String::from("a@example.com")],
+	    email_max_events: Some(2),
+	    ..Default::default()
+	};
+	let email = String::from_utf8(mailer::build_email(&settings, &body, vec![]).unwrap().formatted()).unwrap();
+	assert!(email.contains("multipart/mixed"));
+	assert!(email.contains("multipart/alternative"));
+	assert!(email.contains("text/html"));
+	assert!(email.contains("filename=\"duplicates-events.txt\""));
+	let settings = Settings { email_max_events: Some(10), ..settings };
+	let email = String::from_utf8(mailer::build_email(&settings, &body, vec![]).unwrap().formatted()).unwrap();
+	assert!(!email.contains("multipart/mixed"));
+   }
+   #[test]
+   fn test_webhook_posts_json() {
+	use std::io::{BufRead, BufReader, Read, Write};
+	let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+	let url = format!("http://{}/hook", listener.local_addr().unwrap());
+	let server = std::thread::spawn(move || {
+	    let (stream, _) = listener.accept().unwrap();
+	    let mut reader = BufReader::new(stream.try_clone().unwrap());
+	    let mut head = String::new();
+	    let mut length = 0;
+	    loop {
+	        let mut line = String::new();
+	        reader.read_line(&mut line).unwrap();
+	        if let Some(l) = line.to_lowercase().strip_prefix("content-length:") {
+	            length = l.trim().parse().unwrap();
+	        }
+	        if line == "\r\n" {
+	            break;
+	        }
+	        head.push_str(&line);
+	    }
+	    let mut body = vec![0; length];
+	    reader.read_exact(&mut body).unwrap();
+	    let mut out = stream;
+	    out.write_all(b"HTTP/1.1 204 No Content\r\nContent-Length: 0\r\n\r\n").unwrap();
+	    (head, String::from_utf8(body).unwrap())
+	});
+	let settings = Settings { working_dir: String::from("/photos"), webhook_urls: vec![url], ..Default::default() };
+	let body = ReportBody {
+	    events: vec![ReportEvent::Duplicates { delete: vec![String::from("/photos/b")], keep: String::from("/photos/a"), deleted: false }],
+	    dropped: 0,
+	};
+	hooks::notify(&settings, &body);
+	let (head, payload) = server.join().unwrap();
+	assert!(head.starts_with("POST /hook HTTP/1.1"));
+	let json: serde_json::Value = serde_json::from_str(&payload).unwrap();
+	assert_eq!(json["working_dir"], "/photos");
+	assert_eq!(json["counts"]["files_to_delete"], 1);
+	assert_eq!(json["events"][0]["type"], "duplicates");
+	assert_eq!(json["events"][0]["keep"], "/photos/a");
+   }
+   #[test]
+   fn test_command_hooks_get_env_and_stdin() {
+	use hooks::{CommandHook, HookTrigger};
+	let dir = TempDir::new("hooks");
+	let out = dir.join("out");
+	let command = format!("echo \"$DUPLICATES_EVENT $DUPLICATES_KEEP $DUPLICATES_COUNT\" >> {0}; cat >> {0}; echo >> {0}", out.display());
+	let settings = Settings {
+	    command_hooks: vec![
+	        CommandHook { command: command.clone(), on: HookTrigger::DuplicateGroup },
+	        CommandHook { command, on: HookTrigger::Summary },
+	    ],
+	    ..Default::default()
+	};
+	let body = ReportBody {
+	    events: vec![
+	        ReportEvent::Duplicates { delete: vec![String::from("/b"), String::from("/c")], keep: String::from("/a"), deleted: true },
+	        ReportEvent::HashChanged { path: String::from("/d") },
+	    ],
+	    dropped: 0,
+	};
+	hooks::notify(&settings, &body);
+	let output = std::fs::read_to_string(&out).unwrap();
+	let lines: Vec<&str> = output.lines().collect();
+	assert_eq!(lines[0], "duplicate_group /a 3");
+	assert!(lines[1].starts_with(r#"{"type":"duplicates","delete":["/b","/c"],"keep":"/a","deleted":true}"#));
+	assert_eq!(lines[2], "summary  ");
+	assert!(lines[3].contains(r#""files_deleted":2"#));
+   }
+   #[test]
+   fn test_perceptual_hashes_of_resized_and_other_images() {
+	use image_hash::{hash_image, ImageHashKind};
+	let dir = TempDir::new("images");
+	let photo = image::RgbImage::from_fn(320, 240, |x, y| {
+	    let ring = if (x as i32 - 120).pow(2) + (y as i32 - 100).pow(2) < 60 * 60 { 200 } else { 30 };
+	    image::Rgb([(x * 255 / 320) as u8, ring, (y * 255 / 240) as u8])
+	});
+	let other = image::RgbImage::from_fn(320, 240, |x, y| {
+	    let stripe = if (x / 40 + y / 60) % 2 == 0 { 220 } else { 20 };
+	    image::Rgb([stripe, 255 - (y * 255 / 240) as u8, stripe])
+	});
+	photo.save(dir.join("photo.png")).unwrap();
+	image::imageops::resize(&photo, 160, 120, image::imageops::FilterType::Triangle).save(dir.join("small.jpg")).unwrap();
+	other.save(dir.join("other.png")).unwrap();
+	for kind in [ImageHashKind::Ahash, ImageHashKind::Dhash, ImageHashKind::Phash] {
+	    let hash = |name: &str| hash_image(&dir.join(name), kind).unwrap();
+	    let (photo, small, other) = (hash("photo.png"), hash("small.jpg"), hash("other.png"));
+	    assert!(bktree::hamming_distance(photo, small) <= 6, "{:?}: {:064b} {:064b}", kind, photo, small);
+	    assert!(bktree::hamming_distance(photo, other) > 16, "{:?}: {:064b} {:064b}", kind, photo, other);
+	}
+	assert!(hash_image(&dir.join("missing.jpg"), ImageHashKind::Dhash).is_err());
+   }
+   #[test]
+   fn test_bktree_finds_the_same_as_comparing_all() {
+	use bktree::{hamming_distance, BkTree};
+	let mut seed: u64 = 42;
+	let mut hashes = vec![];
+	for i in 0..500 {
+	    seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+	    // every tenth hash is a near copy of the one before
+	    hashes.push(if i % 10 == 9 { hashes[i - 1] ^ (seed & 0x0101) } else { seed });
+	}
+	let mut tree = BkTree::new();
+	for (i, hash) in hashes.iter().enumerate() {
+	    tree.insert(*hash, i);
+	}
+	for max_distance in [0, 2, 20] {
+	    for (i, hash) in hashes.iter().enumerate() {
+	        let mut found = tree.find(*hash, max_distance);
+	        found.sort();
+	        let expected: Vec<(usize, u32)> = hashes.iter().enumerate()
+	            .map(|(j, h)| (j, hamming_distance(*hash, *h)))
+	            .filter(|(_, d)| *d <= max_distance)
+	            .collect();
+	        assert_eq!(found, expected, "hash {} within {}", i, max_distance);
+	    }
+	}
+   }
+   #[test]
+   fn test_similar_images_are_clustered() {
+	let hashes = [0b0, u64::MAX, 0b11, 0b1, u64::MAX << 1, 0xf0f0];
+	assert_eq!(image_hash::cluster(&hashes, 1), vec![vec![0, 2, 3], vec![1, 4]]);
+	assert_eq!(image_hash::cluster(&hashes, 0), Vec::<Vec<usize>>::new());
+   }
+   /// Saves `image` as a JPEG with an APP1 segment holding `fields` (ASCII, primary IFD).
+   fn save_jpeg_with_exif(path: &Path, image: &image::RgbImage, fields: &[(exif::Tag, &str)]) {
+	let fields: Vec<exif::Field> = fields.iter().map(|(tag, value)| exif::Field {
+	    tag: *tag, ifd_num: exif::In::PRIMARY, value: exif::Value::Ascii(vec![value.as_bytes().to_vec()]),
+	}).collect();
+	let mut writer = exif::experimental::Writer::new();
+	for field in &fields {
+	    writer.push_field(field);
+	}
+	let mut tiff = std::io::Cursor::new(vec![]);
+	writer.write(&mut tiff, false).unwrap();
+	let tiff = tiff.into_inner();
+	let mut jpeg = std::io::Cursor::new(vec![]);
+	image.write_to(&mut jpeg, image::ImageOutputFormat::Jpeg(90)).unwrap();
+	let jpeg = jpeg.into_inner();
+	let mut out = jpeg[..2].to_vec();
+	out.extend([0xff, 0xe1]);
+	out.extend(((tiff.len() + 8) as u16).to_be_bytes());
+	out.extend(b"Exif\0\0");
+	out.extend(tiff);
+	out.extend(&jpeg[2..]);
+	std::fs::write(path, out).unwrap();
+   }
+   #[test]
+   fn test_exif_identity_and_survivor() {
+	use exif::Tag;
+	let dir = TempDir::new("exif");
+	let photo = image::RgbImage::from_fn(200, 150, |x, y| image::Rgb([x as u8, y as u8, 90]));
+	let taken = [(Tag::Make, "Canon"), (Tag::Model, "EOS 80D"), (Tag::DateTimeOriginal, "2023:07:01 12:00:00")];
+	let full = dir.join("full.jpg");
+	save_jpeg_with_exif(&full, &photo, &[taken[0], taken[1], taken[2], (Tag::Artist, "me"), (Tag::Copyright, "me")]);
+	let small = dir.join("small.jpg");
+	let resized = image::imageops::resize(&photo, 100, 75, image::imageops::FilterType::Triangle);
+	save_jpeg_with_exif(&small, &resized, &taken);
+	let edited = dir.join("edited.jpg");
+	save_jpeg_with_exif(&edited, &photo, &[taken[0], taken[1], taken[2], (Tag::ImageUniqueID, "00000000000000000000000000000000")]);
+	let unique = dir.join("unique.jpg");
+	save_jpeg_with_exif(&unique, &photo, &[taken[2], (Tag::ImageUniqueID, "a1b2")]);
+
+	let full_exif = photo::read_exif(&full);
+	assert_eq!(full_exif.identity.as_deref(), Some("taken:2023:07:01 12:00:00.|Canon EOS 80D"));
+	assert_eq!(photo::read_exif(&small).identity, full_exif.identity);
+	// an ID of zeros is no ID
+	assert_eq!(photo::read_exif(&edited).identity, full_exif.identity);
+	assert_eq!(photo::read_exif(&unique).identity.as_deref(), Some("id:a1b2"));
+	assert_eq!(photo::read_exif(&dir.join("missing.jpg")), photo::PhotoExif::default());
+	assert!(full_exif.fields > photo::read_exif(&small).fields);
+
+	let info = |path: &Path| FileInfo { full_path: path.to_str().unwrap().to_string(), size: 1, hash: String::new(), last_modified: 0, content_hash: None };
+	let dups = vec![info(&small), info(&full), info(&edited)];
+	// the full-size copy with the most EXIF fields is kept even if delete_score would keep another one
+	let settings = Settings { delete_score: vec![String::from("full")], exif_matching: true, ..Default::default() };
+	assert_eq!(get_duplicates_sorted_by_score(&dups, &settings).last(), Some(&info(&full).full_path));
+	let settings = Settings { delete_score: vec![String::from("full")], ..Default::default() };
+	assert_ne!(get_duplicates_sorted_by_score(&dups, &settings).last(), Some(&info(&full).full_path));
+   }
+   #[test]
+   fn test_burst_frames_are_only_reported() {
+	use exif::Tag;
+	let root = TempDir::new("burst");
+	let dir = root.join("photos");
+	std::fs::create_dir_all(&dir).unwrap();
+	let photo = image::RgbImage::from_fn(200, 150, |x, y| image::Rgb([x as u8, y as u8, 90]));
+	let mut moved = photo.clone();
+	for (x, y) in (0..30).flat_map(|x| (0..30).map(move |y| (x, y))) {
+	    moved.put_pixel(60 + x, 50 + y, image::Rgb([255, 255, 255]));
+	}
+	let small = image::imageops::resize(&photo, 100, 75, image::imageops::FilterType::Triangle);
+	let taken = [(Tag::Make, "Canon"), (Tag::Model, "EOS 80D"), (Tag::DateTimeOriginal, "2023:07:01 12:00:00")];
+	let subsec = [taken[0], taken[1], taken[2], (Tag::SubSecTimeOriginal, "12")];
+	save_jpeg_with_exif(&dir.join("full.jpg"), &photo, &taken);
+	save_jpeg_with_exif(&dir.join("small.jpg"), &small, &taken);
+	save_jpeg_with_exif(&dir.join("burst.jpg"), &moved, &taken);
+	save_jpeg_with_exif(&dir.join("sub_full.jpg"), &photo, &subsec);
+	save_jpeg_with_exif(&dir.join("sub_small.jpg"), &small, &subsec);
+	save_jpeg_with_exif(&dir.join("sub_burst.jpg"), &moved, &subsec);
+	// a black and white export keeps the unique ID of its original
+	let grey = image::DynamicImage::ImageRgb8(photo.clone()).grayscale().to_rgb8();
+	let id = [(Tag::ImageUniqueID, "a1b2")];
+	save_jpeg_with_exif(&dir.join("id_full.jpg"), &photo, &id);
+	save_jpeg_with_exif(&dir.join("id_small.jpg"), &small, &id);
+	save_jpeg_with_exif(&dir.join("id_grey.jpg"), &grey, &id);
+	let data_manager = DataStore::at(root.join("hashes.db").to_str().unwrap());
+	data_manager.create_tables().unwrap();
+	let settings = Settings {
+	    working_dir: dir.to_str().unwrap().to_string(),
+	    action: String::from("D"),
+	    exif_matching: true,
+	    // the perceptual hashes don't tell the frames apart
+	    exif_max_distance: Some(64),
+	    ..Default::default()
+	};
+	let mut events = ReportEvents::new();
+	process_path(&settings, false, &FileManager::new(), &data_manager, &mut events);
+
+	let files = |names: &[&str]| names.iter().map(|n| dir.join(n).to_str().unwrap().to_string()).collect::<Vec<_>>();
+	// only a unique ID or a time with subseconds, and the same pixels make a copy
+	let left: Vec<bool> = ["full.jpg", "small.jpg", "burst.jpg", "sub_full.jpg", "sub_small.jpg", "sub_burst.jpg", "id_full.jpg", "id_small.jpg", "id_grey.jpg"]
+	    .iter().map(|n| dir.join(n).exists()).collect();
+	assert_eq!(left, [true, true, true, true, false, true, true, false, true]);
+	let body = events.take();
+	let similar: Vec<&Vec<String>> = body.events.iter().filter_map(|e| match e {
+	    ReportEvent::SimilarImages { files } => Some(files),
+	    _ => None,
+	}).collect();
+	assert_eq!(similar, [&files(&["burst.jpg", "full.jpg", "small.jpg"]), &files(&["id_full.jpg", "id_grey.jpg"]), &files(&["sub_burst.jpg", "sub_full.jpg"])]);
+   }
+   #[test]
+   fn test_content_hash_ignores_metadata() {
+	use exif::Tag;
+	let dir = TempDir::new("content");
+	let picture = image::RgbImage::from_fn(64, 48, |x, y| image::Rgb([x as u8 * 4, y as u8 * 5, 60]));
+	save_jpeg_with_exif(&dir.join("a.jpg"), &picture, &[(Tag::DateTimeOriginal, "2023:07:01 12:00:00")]);
+	save_jpeg_with_exif(&dir.join("b.jpg"), &picture, &[(Tag::DateTimeOriginal, "2023:07:01 12:00:00"), (Tag::Artist, "someone else")]);
+	save_jpeg_with_exif(&dir.join("c.jpg"), &image::imageops::flip_horizontal(&picture), &[(Tag::DateTimeOriginal, "2023:07:01 12:00:00")]);
+	picture.save(dir.join("a.png")).unwrap();
+	// a text chunk after IHDR (the CRC is not checked)
+	let png = std::fs::read(dir.join("a.png")).unwrap();
+	let mut tagged = png[..33].to_vec();
+	tagged.extend(7u32.to_be_bytes());
+	tagged.extend(b"tEXtTitle\0x\0\0\0\0");
+	tagged.extend(&png[33..]);
+	std::fs::write(dir.join("b.png"), tagged).unwrap();
+	std::fs::write(dir.join("text.jpg"), "not an image").unwrap();
+	std::fs::write(dir.join("cut.jpg"), &std::fs::read(dir.join("a.jpg")).unwrap()[..100]).unwrap();
+
+	let hash = |name: &str| content_hash::content_hash(&dir.join(name));
+	let file_hash = |name: &str| calculate_hash_for_file(&mut File::open(dir.join(name)).unwrap());
+	assert_ne!(file_hash("a.jpg"), file_hash("b.jpg"));
+	assert!(hash("a.jpg").is_some());
+	assert_eq!(hash("a.jpg"), hash("b.jpg"));
+	assert_ne!(hash("a.jpg"), hash("c.jpg"));
+	assert_ne!(file_hash("a.png"), file_hash("b.png"));
+	assert!(hash("a.png").is_some());
+	assert_eq!(hash("a.png"), hash("b.png"));
+	assert_eq!(hash("text.jpg"), None);
+	assert_eq!(hash("cut.jpg"), None);
+   }
+   #[test]
+   fn test_content_hash_groups_report() {
+	use exif::Tag;
+	let root = TempDir::new("content-report");
+	let dir = root.join("photos");
+	std::fs::create_dir_all(&dir).unwrap();
+	let picture = image::RgbImage::from_fn(64, 48, |x, y| image::Rgb([x as u8 * 4, y as u8 * 5, 60]));
+	save_jpeg_with_exif(&dir.join("a.jpg"), &picture, &[(Tag::DateTimeOriginal, "2023:07:01 12:00:00")]);
+	save_jpeg_with_exif(&dir.join("b.jpg"), &picture, &[(Tag::DateTimeOriginal, "2023:07:01 12:00:00"), (Tag::Artist, "someone else")]);
+	std::fs::copy(dir.join("a.jpg"), dir.join("c.jpg")).unwrap();
+	std::fs::write(dir.join("text.jpg"), "not an image").unwrap();
+	std::fs::write(dir.join("notes.txt"), "notes").unwrap();
+	std::fs::write(dir.join("notes copy.txt"), "notes").unwrap();
+	let data_manager = DataStore::at(root.join("hashes.db").to_str().unwrap());
+	data_manager.create_tables().unwrap();
+	let settings = Settings { working_dir: dir.to_str().unwrap().to_string(), image_content_hash: true, ..Default::default() };
+	let mut events = ReportEvents::new();
+	process_path(&settings, false, &FileManager::new(), &data_manager, &mut events);
+
+	// a file without a content hash is not read for one again
+	let text = dir.join("text.jpg").to_str().unwrap().to_string();
+	assert_eq!(data_manager.get_entry_for_path(&text).unwrap().unwrap().content_hash.as_deref(), Some(content_hash::NONE));
+	let mut stats = ScanStats::default();
+	get_file_info(&text, &settings, &FileManager::new(), &data_manager, &mut stats);
+	assert_eq!(stats.bytes_read, 0);
+
+	let groups = report::duplicate_groups(&settings, &data_manager);
+	let files = |g: &report::DuplicateGroup| g.files.iter().map(|f| Path::new(&f.path).file_name().unwrap().to_str().unwrap().to_string()).collect::<Vec<_>>();
+	// the byte copies of a.jpg are listed with b.jpg only
+	assert_eq!(groups.len(), 2);
+	assert!(groups[0].hash.starts_with("content:"));
+	assert_eq!(files(&groups[0]), ["a.jpg", "b.jpg", "c.jpg"]);
+	assert_eq!(files(&groups[1]), ["notes copy.txt", "notes.txt"]);
+	let sizes: u64 = groups[0].files.iter().filter(|f| f.path != groups[0].survivor).map(|f| std::fs::metadata(&f.path).unwrap().len()).sum();
+	assert_eq!(groups[0].reclaimable_bytes, sizes);
+	// only the byte copies without image_content_hash
+	let settings = Settings { image_content_hash: false, ..settings };
+	let groups = report::duplicate_groups(&settings, &data_manager);
+	assert_eq!(groups.iter().map(files).collect::<Vec<_>>(), [vec!["a.jpg", "c.jpg"], vec!["notes copy.txt", "notes.txt"]]);
+   }
+   /// 16-bit PCM WAV, mono, with `extra` chunks before the data.
+   fn wav(rate: u32, samples: &[f32], extra: &[(&[u8; 4], &[u8])]) -> Vec<u8> {
+	let data: Vec<u8> = samples.iter().flat_map(|s| ((s.clamp(-1.0, 1.0) * 32767.0) as i16).to_le_bytes()).collect();
+	let mut chunks = vec![];
+	let mut chunk = |id: &[u8], content: &[u8]| {
+	    chunks.extend(id);
+	    chunks.extend((content.len() as u32).to_le_bytes());
+	    chunks.extend(content);
+	    if content.len() % 2 == 1 {
+	        chunks.push(0);
+	    }
+	};
+	let mut fmt = vec![1, 0, 1, 0];
+	fmt.extend(rate.to_le_bytes());
+	fmt.extend((rate * 2).to_le_bytes());
+	fmt.extend([2, 0, 16, 0]);
+	chunk(b"fmt ", &fmt);
+	for (id, content) in extra {
+	    chunk(*id, content);
+	}
+	chunk(b"data", &data);
+	let mut file = b"RIFF".to_vec();
+	file.extend((chunks.len() as u32 + 4).to_le_bytes());
+	file.extend(b"WAVE");
+	file.extend(chunks);
+	file
+   }
+   /// An Ogg page of `serial` with `packets`, CRC left at zero.
+   fn ogg_page(serial: u32, sequence: u32, packets: &[&[u8]]) -> Vec<u8> {
+	let mut lacing = vec![];
+	for packet in packets {
+	    lacing.extend(std::iter::repeat_n(255u8, packet.len() / 255));
+	    lacing.push((packet.len() % 255) as u8);
+	}
+	let mut page = b"OggS\0\0".to_vec();
+	page.extend([0; 8]);
+	page.extend(serial.to_le_bytes());
+	page.extend(sequence.to_le_bytes());
+	page.extend([0; 4]);
+	page.push(lacing.len() as u8);
+	page.extend(lacing);
+	for packet in packets {
+	    page.extend(*packet);
+	}
+	page
+   }
+   #[test]
+   fn test_audio_content_hash_ignores_tags() {
+	let dir = TempDir::new("audio-tags");
+	let write = |name: &str, parts: &[&[u8]]| std::fs::write(dir.join(name), parts.concat()).unwrap();
+	let frames: Vec<u8> = (0..2000u32).map(|i| if i % 400 == 0 { 0xff } else if i % 400 == 1 { 0xfb } else { (i * 7) as u8 }).collect();
+	let id3v2 = [b"ID3\x04\0\0\0\0\0\x14".as_slice(), b"TIT2\0\0\0\x06\0\0\0Song", &[0; 5]].concat();
+	let mut ape = b"APETAGEXitems".to_vec();
+	ape.extend(b"APETAGEX\xd0\x07\0\0");
+	ape.extend(45u32.to_le_bytes());
+	ape.extend([1, 0, 0, 0, 0, 0, 0, 0x80, 0, 0, 0, 0, 0, 0, 0, 0]);
+	let apeheader = [b"APETAGEX".as_slice(), &[0; 24]].concat();
+	let id3v1 = [b"TAG".as_slice(), &[b'x'; 125]].concat();
+	write("plain.mp3", &[&frames]);
+	write("tagged.mp3", &[&id3v2, &frames, &apeheader, &ape, &id3v1]);
+	write("other.mp3", &[&frames[..1600]]);
+
+	let streaminfo = [&[0u8, 0, 0, 34][..], &[7; 34]].concat();
+	let comment = |text: &[u8]| [&[0x84, 0, 0, text.len() as u8][..], text].concat();
+	write("plain.flac", &[b"fLaC", &streaminfo, &comment(b"a"), &frames]);
+	write("tagged.flac", &[b"fLaC", &streaminfo, &[1, 0, 0, 3, 0, 0, 0], &comment(b"artist=someone"), &frames]);
+
+	let ident: &[u8] = b"\x01vorbis ident";
+	let setup: &[u8] = b"\x05vorbis setup";
+	let long_comment = [b"\x03vorbis".as_slice(), &[b'c'; 300]].concat();
+	write("plain.ogg", &[&ogg_page(7, 0, &[ident]), &ogg_page(7, 1, &[b"\x03vorbis", setup]), &ogg_page(7, 2, &[&frames[..700], &frames[700..]])]);
+	write("tagged.ogg", &[&ogg_page(7, 0, &[ident]), &ogg_page(7, 1, &[&long_comment]), &ogg_page(7, 2, &[setup]),
+	    &ogg_page(7, 3, &[&frames[..700], &frames[700..]])]);
+
+	let samples: Vec<f32> = (0..800).map(|i| (i as f32 / 9.0).sin()).collect();
+	std::fs::write(dir.join("plain.wav"), wav(8000, &samples, &[])).unwrap();
+	std::fs::write(dir.join("tagged.wav"), wav(8000, &samples, &[(b"LIST", b"INFOISFT\x04\0\0\0abc\0")])).unwrap();
+	std::fs::write(dir.join("other.wav"), wav(8000, &samples[1..], &[])).unwrap();
+
+	let hash = |name: &str| content_hash::content_hash(&dir.join(name));
+	for format in ["mp3", "flac", "ogg", "wav"] {
+	    let (plain, tagged) = (format!("plain.{}", format), format!("tagged.{}", format));
+	    assert!(hash(&plain).is_some(), "{}", format);
+	    assert_eq!(hash(&plain), hash(&tagged), "{}", format);
+	}
+	assert_ne!(hash("plain.mp3"), hash("other.mp3"));
+	assert_ne!(hash("plain.wav"), hash("other.wav"));
+	assert_ne!(hash("plain.mp3"), hash("plain.flac"));
+	let settings = Settings { audio_content_hash: true, ..Default::default() };
+	assert!(content_hash::applies(Path::new("/music/a.FLAC"), &settings));
+	assert!(!content_hash::applies(Path::new("/music/a.jpg"), &settings));
+   }
+   #[test]
+   fn test_audio_fingerprints_match_the_same_recording() {
+	let dir = TempDir::new("audio-fp");
+	// a note every quarter second, with an overtone
+	let melody = |seed: u64, rate: u32, secs: f32| -> Vec<f32> {
+	    let notes: Vec<[f32; 2]> = (0..(secs * 4.0) as u64)
+	        .map(|n| [100.0 + ((n * 7919 + seed * 104729) % 400) as f32, 150.0 + ((n * 104729 + seed * 7919) % 500) as f32])
+	        .collect();
+	    let mut phases = [0.0f64; 2];
+	    (0..(rate as f32 * secs) as usize).map(|i| {
+	        let note = notes[i * 4 / rate as usize];
+	        (0..2).map(|v| {
+	            phases[v] = (phases[v] + note[v] as f64 / rate as f64) % 1.0;
+	            let t = (phases[v] * 2.0 * std::f64::consts::PI) as f32;
+	            (1..=12).map(|h| (h as f32 * t).sin() / h as f32).sum::<f32>() * 0.2
+	        }).sum::<f32>()
+	    }).collect()
+	};
+	std::fs::write(dir.join("a.wav"), wav(44100, &melody(1, 44100, 20.0), &[])).unwrap();
+	// quieter, a bit of noise, another sample rate and half a second of silence before it
+	let mut noise = 1u32;
+	let mut copy = vec![0.0; 11025];
+	copy.extend(melody(1, 22050, 20.0).iter().map(|s| {
+	    noise = noise.wrapping_mul(1664525).wrapping_add(1013904223);
+	    0.4 * s + ((noise >> 16) as f32 / 65536.0 - 0.5) * 0.02
+	}));
+	std::fs::write(dir.join("b.wav"), wav(22050, &copy, &[])).unwrap();
+	std::fs::write(dir.join("c.wav"), wav(44100, &melody(2, 44100, 20.0), &[])).unwrap();
+
+	let (a_ms, a) = audio::fingerprint_file(&dir.join("a.wav")).unwrap();
+	let (b_ms, b) = audio::fingerprint_file(&dir.join("b.wav")).unwrap();
+	let (_, c) = audio::fingerprint_file(&dir.join("c.wav")).unwrap();
+	assert_eq!((a_ms, b_ms), (20000, 20500));
+	assert!(a.len() > 200);
+	let same = audio::bit_error_rate(&a, &b);
+	let different = audio::bit_error_rate(&a, &c);
+	assert!(same < audio::DEFAULT_MAX_BIT_ERROR, "same recording: {}", same);
+	assert!(different > 0.35, "other recording: {}", different);
+	assert!(audio::fingerprint_file(&dir.join("missing.wav")).is_err());
+
+	// only reported by default
+	let db = TempDir::new("audio-fp-db");
+	let data_manager = DataStore::at(db.join("hashes.db").to_str().unwrap());
+	data_manager.create_tables().unwrap();
+	let settings = Settings { working_dir: dir.to_str().unwrap().to_string(), action: String::from("D"), audio_fingerprint: true, ..Default::default() };
+	let mut events = ReportEvents::new();
+	process_path(&settings, false, &FileManager::new(), &data_manager, &mut events);
+	let files = |name: &str| dir.join(name).to_str().unwrap().to_string();
+	let body = events.take();
+	assert!(body.events.contains(&ReportEvent::SimilarAudio { files: vec![files("a.wav"), files("b.wav")] }));
+	assert!(!body.events.iter().any(|e| matches!(e, ReportEvent::Duplicates { .. })));
+	assert!(dir.join("a.wav").exists() && dir.join("b.wav").exists());
+	let groups = audio::similar_groups(&settings, &data_manager);
+	assert_eq!(groups.len(), 1);
+	assert_eq!(groups[0].files.iter().map(|f| f.path.clone()).collect::<Vec<_>>(), vec![files("a.wav"), files("b.wav")]);
+
+	// deleted with the opt-in, the bigger copy is kept
+	let settings = Settings { audio_fingerprint_delete: true, ..settings };
+	process_path(&settings, false, &FileManager::new(), &data_manager, &mut events);
+	assert!(!events.take().events.iter().any(|e| matches!(e, ReportEvent::SimilarAudio { .. })));
+	assert!(dir.join("a.wav").exists() && !dir.join("b.wav").exists() && dir.join("c.wav").exists());
+   }
+   #[test]
+   fn test_similar_videos_are_clustered() {
+	use datastore::VideoHash;
+	let video = |duration_ms: u64, frames: [u64; 3]| VideoHash { path: String::new(), duration_ms, frames: frames.to_vec(), last_modified: 0 };
+	assert_eq!(video::frame_distance(&[0b1111, 0], &[0, 0b1]), Some(3));
+	assert_eq!(video::frame_distance(&[0], &[0, 0]), None);
+	let videos = [
+	    video(60_000, [0xff00, 0xf0f0, 0x0ff0]),
+	    // re-encoded, a bit shorter
+	    video(59_000, [0xff01, 0xf0f0, 0x0ff1]),
+	    // the same frames but twice as long
+	    video(120_000, [0xff00, 0xf0f0, 0x0ff0]),
+	    video(61_500, [u64::MAX, 0, u64::MAX]),
+	    // single colour, would match each other
+	    video(10_000, [0, 0, 0]),
+	    video(10_000, [0, 0, 0]),
+	];
+	assert_eq!(video::cluster(&videos, 1), vec![vec![0, 1]]);
+	assert_eq!(video::cluster(&videos, 0), Vec::<Vec<usize>>::new());
+   }
+   #[test]
+   fn test_video_frames_are_hashed_with_ffmpeg() {
+	use std::os::unix::fs::PermissionsExt;
+	let dir = TempDir::new("video");
+	// a 9x8 gray frame, brighter to the right
+	let frame: Vec<u8> = (0..72).map(|i| (i % 9) as u8 * 20).collect();
+	let octal: String = frame.iter().map(|b| format!("\\{:03o}", b)).collect();
+	let script = |name: &str, output: &str| {
+	    let path = dir.join(name);
+	    std::fs::write(&path, format!("#!/bin/sh\n[ \"$1\" = -version ] && exit 0\ncase \"$*\" in *file:/*) {} ;; *) exit 1 ;; esac\n", output)).unwrap();
+	    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+	    path.to_str().unwrap().to_string()
+	};
+	let ffmpeg = script("ffmpeg", &format!("printf '{}'", octal));
+	script("ffprobe", "echo 12.5");
+	let movie = dir.join("movie.mp4");
+	assert!(video::ffmpeg_available(&ffmpeg));
+	let (duration_ms, frames) = video::hash_video(&movie, &ffmpeg).unwrap();
+	assert_eq!(duration_ms, 12500);
+	assert_eq!(frames.len(), video::FRAME_POSITIONS.len());
+	let expected = image_hash::hash_pixels(&image::GrayImage::from_raw(9, 8, frame).unwrap(), image_hash::ImageHashKind::Dhash);
+	assert!(frames.iter().all(|f| *f == expected && *f != 0));
+
+	let missing = dir.join("bin").join("ffmpeg");
+	assert!(!video::ffmpeg_available(missing.to_str().unwrap()));
+	assert!(video::hash_video(&movie, missing.to_str().unwrap()).is_err());
+	// without ffmpeg videos are skipped, the database is not even asked
+	let settings = Settings { similar_videos: true, ffmpeg: Some(missing.to_str().unwrap().to_string()), ..Default::default() };
+	let info = FileInfo { full_path: movie.to_str().unwrap().to_string(), size: 1, hash: String::new(), last_modified: 0, content_hash: None };
+	video::update(&info, &settings, &MockDataManager::new());
+   }
+   /// A directory of its own below the system temp directory, removed with everything in it
+   /// when the test ends, whether it passes or not. Canonical, like the paths of a walk.
+   struct TempDir(PathBuf);
+   impl TempDir {
+	fn new(name: &str) -> Self {
+	    let path = std::env::temp_dir().join(format!("duplicates-{}-{}", name, std::process::id()));
+	    std::fs::create_dir_all(&path).unwrap();
+	    TempDir(std::fs::canonicalize(&path).unwrap())
+	}
+   }
+   impl std::ops::Deref for TempDir {
+	type Target = Path;
+	fn deref(&self) -> &Path { &self.0 }
+   }
+   impl AsRef<Path> for TempDir {
+	fn as_ref(&self) -> &Path { &self.0 }
+   }
+   impl Drop for TempDir {
+	fn drop(&mut self) {
+	    std::fs::remove_dir_all(&self.0).unwrap_or_default();
+	}
+   }
+   /// Writes the files (path below `root`, content) and returns their rows, with the content as hash.
+   fn directory_fixture(root: &Path, files: &[(&str, &str)]) -> Vec<FileInfo> {
+	files.iter().map(|(name, content)| {
+	    let path = root.join(name);
+	    std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+	    std::fs::write(&path, content).unwrap();
+	    FileInfo { full_path: path.to_str().unwrap().to_string(), size: 10, hash: content.to_string(), last_modified: 0, content_hash: None }
+	}).collect()
+   }
+   /// (delete, keep, subset) of every duplicate directory, paths relative to `root`.
+   fn relative_duplicates(root: &Path, found: Vec<directories::DuplicateDirectories>) -> Vec<(Vec<String>, String, bool)> {
+	let relative = |path: &str| path.strip_prefix(root.to_str().unwrap()).unwrap().to_string();
+	found.into_iter().map(|d| (d.delete.iter().map(|p| relative(p)).collect(), relative(&d.keep), d.subset)).collect()
+   }
+   #[test]
+   fn test_duplicate_directories() {
+	let root = TempDir::new("dirs");
+	let entries = directory_fixture(&root, &[
+	    ("album/a.jpg", "a"),
+	    ("album/b.jpg", "b"),
+	    ("album/sub/c.jpg", "c"),
+	    // renamed files, the same contents
+	    ("album copy/1.jpg", "a"),
+	    ("album copy/2.jpg", "b"),
+	    ("album copy/sub/3.jpg", "c"),
+	    ("partial/a.jpg", "a"),
+	    ("partial/c.jpg", "c"),
+	    ("mixed/b.jpg", "b"),
+	    ("other/b.jpg", "b"),
+	    ("other/d.jpg", "d"),
+	]);
+	// not in the database
+	std::fs::write(root.join("mixed/notes.txt"), "").unwrap();
+	std::fs::create_dir_all(root.join("other/empty/deeper")).unwrap();
+
+	let tree = directories::directory_tree(&root, &entries);
+	let node = |path: &str| &tree[&root.join(path)];
+	assert_eq!(node("album").hash, node("album copy").hash);
+	assert_eq!(node("album/sub").hash, node("album copy/sub").hash);
+	assert_ne!(node("album").hash, node("partial").hash);
+	assert_eq!((node("album").size, node("album").contents.len()), (30, 3));
+	assert!(node("album").complete && node("other").complete);
+	assert!(!node("mixed").complete);
+	assert!(!tree.contains_key(root.as_ref()));
+
+	let summary = relative_duplicates(&root, directories::find_duplicates(&tree, |path| path.contains("copy") as i32));
+	// mixed holds a file that was not compared, it is never deleted
+	assert_eq!(summary, vec![
+	    (vec![String::from("/album copy")], String::from("/album"), false),
+	    (vec![String::from("/partial")], String::from("/album"), true),
+	]);
+   }
+
+   #[test]
+   fn test_archive_members_are_hashed_and_never_deleted() {
+	use std::io::Write;
+	let dir = TempDir::new("archives");
+	let sha = |data: &[u8]| format!("{:x}", sha2::Sha512::digest(data));
+
+	let zip_path = dir.join("backup.zip");
+	let mut zip = zip::ZipWriter::new(std::fs::File::create(&zip_path).unwrap());
+	let options = zip::write::FileOptions::default().compression_method(zip::CompressionMethod::Deflated);
+	zip.add_directory("DCIM/", options).unwrap();
+	zip.start_file("DCIM/a.jpg", options).unwrap();
+	zip.write_all(b"first photo").unwrap();
+	zip.start_file("b.jpg", options).unwrap();
+	zip.write_all(b"second photo").unwrap();
+	zip.finish().unwrap();
+
+	let tar_path = dir.join("backup.tar.gz");
+	let mut tar = tar::Builder::new(flate2::write::GzEncoder::new(std::fs::File::create(&tar_path).unwrap(), flate2::Compression::default()));
+	let mut header = tar::Header::new_gnu();
+	header.set_size(11);
+	header.set_mode(0o644);
+	header.set_cksum();
+	tar.append_data(&mut header, "./photos/a.jpg", &b"first photo"[..]).unwrap();
+	tar.into_inner().unwrap().finish().unwrap();
+
+	let members = archive::read_members(&zip_path).unwrap();
+	assert_eq!(members, vec![
+	    archive::Member { name: String::from("DCIM/a.jpg"), size: 11, hash: sha(b"first photo") },
+	    archive::Member { name: String::from("b.jpg"), size: 12, hash: sha(b"second photo") },
+	]);
+	let members = archive::read_members(&tar_path).unwrap();
+	assert_eq!(members, vec![archive::Member { name: String::from("photos/a.jpg"), size: 11, hash: sha(b"first photo") }]);
+	assert!(archive::read_members(&dir.join("missing.zip")).is_err());
+
+	let member = format!("{}!/DCIM/a.jpg", zip_path.to_str().unwrap());
+	assert!(archive::is_member(&member) && !archive::is_member(zip_path.to_str().unwrap()));
+	assert_eq!(archive::container(&member), zip_path.to_str().unwrap());
+	let (from, to) = archive::member_range(zip_path.to_str().unwrap());
+	assert!(from.as_str() < member.as_str() && member.as_str() < to.as_str());
+	// another archive with the same name start is not in the range
+	let other = format!("{}.old", zip_path.to_str().unwrap());
+	assert!(!(from < other && other < to));
+
+	let entry = |path: &str| FileInfo { full_path: path.to_string(), size: 11, hash: sha(b"first photo"), last_modified: 0, content_hash: None };
+	let dups = vec![entry("/photos/download/a.jpg"), entry(&member), entry("/photos/a.jpg")];
+	let settings = Settings { delete_score: vec![String::from("download")], ..Default::default() };
+	assert_eq!(get_duplicates_sorted_by_score(&dups, &settings), vec!["/photos/download/a.jpg", "/photos/a.jpg"]);
+	let settings = Settings { archive_policy: Some(archive::ArchivePolicy::DeleteFiles), ..settings };
+	assert_eq!(get_duplicates_sorted_by_score(&dups, &settings), vec!["/photos/download/a.jpg", "/photos/a.jpg", member.as_str()]);
+   }
+
+   #[test]
+   fn test_empty_directories_cleanup() {
+	let root = TempDir::new("empty");
+	for dir in ["a/b/c", "a/keep", "x/y", "full", "ignored", "deep/er/est"] {
+	    std::fs::create_dir_all(root.join(dir)).unwrap();
+	}
+	std::fs::write(root.join("z.txt"), "").unwrap();
+	std::fs::write(root.join("full/f.jpg"), "data").unwrap();
+	let protected = ignore_rules::IgnoreRules::config_only(&root, &[String::from("keep"), String::from("/ignored")]).unwrap();
+	let kept = |p: &Path, d: bool| protected.is_ignored(p, d);
+	let file_manager = FileManager::new();
+
+	assert!(cleanup::is_empty_dir(&root.join("x"), &kept));
+	assert!(!cleanup::is_empty_dir(&root.join("a"), &kept) && !cleanup::is_empty_dir(&root.join("full"), &kept));
+	let relative = |paths: Vec<String>| -> Vec<String> { paths.iter().map(|p| p.strip_prefix(root.to_str().unwrap()).unwrap().to_string()).collect() };
+	let (files, directories) = cleanup::find_empty(&root, &file_manager::WalkOptions::default(), kept, &file_manager);
+	assert_eq!(relative(files), vec!["/z.txt"]);
+	assert_eq!(relative(directories), vec!["/a/b", "/deep", "/x"]);
+
+	// one level only: "er" stays
+	let removed = cleanup::remove_emptied(&[root.join("deep/er/est")].into(), &root, Some(1), kept, &file_manager);
+	assert_eq!(relative(removed), vec!["/deep/er/est"]);
+	// up to working_dir, apart from directories that hold protected ones
+	let deleted_from = [root.join("a/b/c"), root.join("deep/er"), root.join("full")].into();
+	let removed = cleanup::remove_emptied(&deleted_from, &root, None, kept, &file_manager);
+	assert_eq!(relative(removed), vec!["/deep/er", "/deep", "/a/b/c", "/a/b"]);
+	assert!(root.join("a/keep").exists() && root.join("full").exists() && root.exists());
+
+	// a file written to an empty directory after it was checked stays
+	std::fs::create_dir_all(root.join("late/empty")).unwrap();
+	std::fs::write(root.join("late/new.jpg"), "data").unwrap();
+	assert!(file_manager.remove_empty_dir(root.join("late").to_str().unwrap()).is_err());
+	assert!(root.join("late/new.jpg").exists() && !root.join("late/empty").exists());
+   }
+
+   #[test]
+   fn test_verify_finds_corrupted_files() {
+	let dir = TempDir::new("verify");
+	let entry = |name: &str, content: &str| {
+	    let path = dir.join(name);
+	    std::fs::write(&path, content).unwrap();
+	    let modified = std::fs::metadata(&path).unwrap().modified().unwrap();
+	    FileInfo { full_path: path.to_str().unwrap().to_string(), size: content.len() as u64, hash: format!("{:x}", Sha512::digest(content)),
+		last_modified: modified.duration_since(UNIX_EPOCH).unwrap().as_secs(), content_hash: None }
+	};
+	let rotten = entry("rotten.jpg", "photo one");
+	let copy = entry("copy.jpg", "photo one");
+	let lonely = entry("lonely.jpg", "photo two");
+	let fine = entry("fine.jpg", "photo six");
+	let edited = entry("edited.jpg", "photo ten");
+	// flipped bits, same size and mtime
+	for file in [&rotten, &lonely] {
+	    let modified = std::fs::metadata(&file.full_path).unwrap().modified().unwrap();
+	    std::fs::write(&file.full_path, "photo 0ne").unwrap();
+	    std::fs::File::options().write(true).open(&file.full_path).unwrap().set_modified(modified).unwrap();
+	}
+	std::fs::write(&edited.full_path, "photo ten, edited").unwrap();
+
+	let all = vec![rotten.clone(), copy.clone(), lonely.clone(), fine.clone(), edited.clone(), entry("gone.jpg", "x")];
+	std::fs::remove_file(dir.join("gone.jpg")).unwrap();
+	let mut d_mock = MockDataManager::new();
+	d_mock.expect_get_all_entries().returning(move || Ok(all.clone()));
+	let copies = vec![rotten.clone(), copy.clone()];
+	d_mock.expect_get_entries_by_hash().with(eq(rotten.hash.clone())).returning(move |_| Ok(copies.clone()));
+	d_mock.expect_get_entries_by_hash().with(eq(lonely.hash.clone())).returning(|_| Ok(vec![]));
+	// nothing is deleted, also with action D
+	let mut f_mock = MockHandleFiles::new();
+	f_mock.expect_copy_file().with(eq(copy.full_path.clone()), eq(rotten.full_path.clone())).times(1).returning(|_, _| Ok(()));
+
+	let settings = Settings { action: String::from("D"), verify_restore: true, ..Default::default() };
+	let mut events = ReportEvents::new();
+	let mut reported = HashSet::new();
+	let stats = verify::verify(&settings, &mut verify::Throttle::new(None), &mut reported, &f_mock, &d_mock, &mut events);
+	assert_eq!(stats, verify::VerifyStats { checked: 6, intact: 2, changed: 1, missing: 1, corrupted: 2, restored: 1, bytes_read: 36 });
+	assert_eq!(events.take().events, vec![
+	    ReportEvent::Corrupted { path: rotten.full_path.clone(), intact: Some(copy.full_path.clone()), restored: true },
+	    ReportEvent::Corrupted { path: lonely.full_path.clone(), intact: None, restored: false },
+	]);
+	// the next continuous pass finds them again, they were reported already
+	let settings = Settings { verify_restore: false, ..settings };
+	let stats = verify::verify(&settings, &mut verify::Throttle::new(None), &mut reported, &f_mock, &d_mock, &mut events);
+	assert_eq!(stats.corrupted, 2);
+	assert!(events.is_empty());
+
+	let started = std::time::Instant::now();
+	let mut throttle = verify::Throttle::new(Some(1000));
+	verify::hash_file(Path::new(&fine.full_path), &mut throttle).unwrap();
+	throttle.consume(191);
+	assert!(started.elapsed() >= std::time::Duration::from_millis(190));
+	// idle time is no credit
+	std::thread::sleep(std::time::Duration::from_millis(200));
+	throttle.restart();
+	let started = std::time::Instant::now();
+	throttle.consume(191);
+	assert!(started.elapsed() >= std::time::Duration::from_millis(190));
+   }
+
+   #[test]
+   fn test_directory_is_not_a_subset_of_deleted_copies() {
+	let root = TempDir::new("dirs-subset");
+	let entries = directory_fixture(&root, &[
+	    ("2019/Album/a.jpg", "a"),
+	    ("2019/Album/b.jpg", "b"),
+	    ("backup/Album/a.jpg", "a"),
+	    ("backup/Album/b.jpg", "b"),
+	    ("backup/g.jpg", "g"),
+	]);
+
+	let tree = directories::directory_tree(&root, &entries);
+	let summary = relative_duplicates(&root, directories::find_duplicates(&tree, |path| path.contains("backup") as i32));
+	// 2019 holds the only copies of a and b that are left, it is not in backup anymore
+	assert_eq!(summary, vec![(vec![String::from("/backup/Album")], String::from("/2019/Album"), false)]);
+   }
+
+   #[test]
+   fn test_archive_with_unhashed_files_is_kept() {
+	use std::io::Write;
+	let dir = TempDir::new("archives-kept");
+	let sha = |data: &[u8]| format!("{:x}", sha2::Sha512::digest(data));
+	let zip = |name: &str, files: &[(&str, &[u8])]| {
+	    let path = dir.join(name);
+	    let mut zip = zip::ZipWriter::new(std::fs::File::create(&path).unwrap());
+	    for (file, data) in files {
+		zip.start_file(*file, zip::write::FileOptions::default()).unwrap();
+		zip.write_all(data).unwrap();
+	    }
+	    zip.finish().unwrap();
+	    path.to_str().unwrap().to_string()
+	};
+	// notes.txt is left out by include_extensions, it is nowhere else
+	let mixed = zip("mixed.zip", &[("a.jpg", b"photo"), ("notes.txt", b"only here")]);
+	let photos = zip("photos.zip", &[("a.jpg", b"photo")]);
+	let photo = dir.join("a.jpg");
+	std::fs::write(&photo, "photo").unwrap();
+	let entry = |path: &str, hash: String| FileInfo { full_path: path.to_string(), size: 5, hash, last_modified: 0, content_hash: None };
+	let entries = vec![
+	    entry(photo.to_str().unwrap(), sha(b"photo")),
+	    entry(&mixed, String::from("m")),
+	    entry(&format!("{}!/a.jpg", mixed), sha(b"photo")),
+	    entry(&photos, String::from("p")),
+	    entry(&format!("{}!/a.jpg", photos), sha(b"photo")),
+	];
+	let mut d_mock = MockDataManager::new();
+	d_mock.expect_get_all_entries().return_once(move || Ok(entries));
+	d_mock.expect_delete_entry_for_path().with(eq(photos.clone())).times(1).returning(|_| Ok(()));
+	let mut f_mock = MockHandleFiles::new();
+	f_mock.expect_remove_file().with(eq(photos.clone())).times(1).returning(|_| Ok(()));
+
+	let settings = Settings { action: String::from("D"), scan_archives: true, include_extensions: vec![String::from("jpg")], ..Default::default() };
+	let mut events = ReportEvents::new();
+	process_archives(&settings, &f_mock, &d_mock, &mut events);
+	assert_eq!(events.take().events, vec![ReportEvent::RedundantArchive { path: photos, files: 1, deleted: true }]);
+   }
+
+   #[test]
+   fn test_replaced_archive_is_no_copy() {
+	use std::io::Write;
+	let root = TempDir::new("archives-replaced");
+	std::fs::create_dir_all(root.join("photos")).unwrap();
+	let zip = |files: &[(&str, &[u8])]| {
+	    let mut zip = zip::ZipWriter::new(std::fs::File::create(root.join("backup.zip")).unwrap());
+	    for (file, data) in files {
+		zip.start_file(*file, zip::write::FileOptions::default()).unwrap();
+		zip.write_all(data).unwrap();
+	    }
+	    zip.finish().unwrap();
+	};
+	zip(&[("a.jpg", b"photo")]);
+	let db = TempDir::new("archives-replaced-db");
+	let data_manager = DataStore::at(db.join("hashes.db").to_str().unwrap());
+	data_manager.create_tables().unwrap();
+	let settings = Settings { working_dir: root.to_str().unwrap().to_string(), action: String::from("D"), scan_archives: true,
+	    archive_policy: Some(archive::ArchivePolicy::DeleteFiles), ..Default::default() };
+	let mut events = ReportEvents::new();
+	process_path(&settings, false, &FileManager::new(), &data_manager, &mut events);
+	let member = format!("{}!/a.jpg", root.join("backup.zip").to_str().unwrap());
+	assert!(data_manager.get_entry_for_path(&member).unwrap().is_some());
+
+	// the archive as it was read holds the photo
+	std::fs::write(root.join("photos/copy.jpg"), "photo").unwrap();
+	process_file(root.join("photos/copy.jpg").to_str().unwrap(), &settings, &FileManager::new(), &data_manager, &mut events);
+	assert!(!root.join("photos/copy.jpg").exists());
+
+	// replaced by one without it, before the walk gets to the archive again
+	zip(&[("b.jpg", b"other")]);
+	let later = std::time::SystemTime::now() + Duration::from_secs(10);
+	std::fs::File::options().write(true).open(root.join("backup.zip")).unwrap().set_modified(later).unwrap();
+	std::fs::write(root.join("photos/a.jpg"), "photo").unwrap();
+	process_file(root.join("photos/a.jpg").to_str().unwrap(), &settings, &FileManager::new(), &data_manager, &mut events);
+	assert!(root.join("photos/a.jpg").exists());
+	assert!(data_manager.get_entry_for_path(&member).unwrap().is_none());
+   }
+
+   #[test]
+   fn test_archives_pass_the_file_filter() {
+	use std::io::Write;
+	let root = TempDir::new("archives-filtered");
+	let mut zip = zip::ZipWriter::new(std::fs::File::create(root.join("backup.zip")).unwrap());
+	for (file, data) in [("a.jpg", "photo"), ("notes.txt", "notes")] {
+	    zip.start_file(file, zip::write::FileOptions::default()).unwrap();
+	    zip.write_all(data.as_bytes()).unwrap();
+	}
+	zip.finish().unwrap();
+	let db = TempDir::new("archives-filtered-db");
+	let data_manager = DataStore::at(db.join("hashes.db").to_str().unwrap());
+	data_manager.create_tables().unwrap();
+	// the extension of the archive itself is not included, its members are filtered
+	let settings = Settings { working_dir: root.to_str().unwrap().to_string(), scan_archives: true,
+	    include_extensions: vec![String::from("jpg")], ..Default::default() };
+	let mut events = ReportEvents::new();
+	process_path(&settings, false, &FileManager::new(), &data_manager, &mut events);
+	let archive = root.join("backup.zip").to_str().unwrap().to_string();
+	assert!(data_manager.get_entry_for_path(&format!("{}!/a.jpg", archive)).unwrap().is_some());
+	assert!(data_manager.get_entry_for_path(&format!("{}!/notes.txt", archive)).unwrap().is_none());
+   }
+
+   /// The real file system, remembering which files were opened. Opening `slow` takes longer
+   /// than a checkpoint interval, opening `broken` panics.
+   struct RecordingFiles {
+	opened: std::cell::RefCell<Vec<String>>,
+	slow: Option<PathBuf>,
+	broken: Option<PathBuf>,
+   }
+   impl HandleFiles for RecordingFiles {
+	fn remove_file(&self, path: &str) -> io::Result<()> { FileManager::new().remove_file(path) }
+	fn remove_dir_all(&self, path: &str) -> io::Result<()> { FileManager::new().remove_dir_all(path) }
+	fn remove_empty_dir(&self, path: &str) -> io::Result<()> { FileManager::new().remove_empty_dir(path) }
+	fn copy_file(&self, from: &str, to: &str) -> io::Result<()> { FileManager::new().copy_file(from, to) }
+	fn get_full_path(&self, srcdir: &Path) -> io::Result<PathBuf> { FileManager::new().get_full_path(srcdir) }
+	fn walkdir(&self, srcdir: &str, options: &WalkOptions) -> Walker { FileManager::new().walkdir(srcdir, options) }
+	fn get_file(&self, path: &Path) -> io::Result<File> {
+	    if self.broken.as_deref() == Some(path) {
+		panic!("scan interrupted at {}", path.display());
+	    }
+	    if self.slow.as_deref() == Some(path) {
+		thread::sleep(CHECKPOINT_INTERVAL + Duration::from_millis(100));
+	    }
+	    self.opened.borrow_mut().push(path.file_name().unwrap().to_string_lossy().to_string());
+	    FileManager::new().get_file(path)
+	}
+   }
+
+   #[test]
+   fn test_interrupted_scan_is_resumed_from_checkpoint() {
+	let root = TempDir::new("resume");
+	// a copy of a/0.jpg before the interruption and one after it
+	for (name, content) in [("a/0.jpg", "zero"), ("a/00.jpg", "zero"), ("b/1.jpg", "one"), ("c/2.jpg", "two"), ("d/3.jpg", "zero")] {
+	    std::fs::create_dir_all(root.join(name).parent().unwrap()).unwrap();
+	    std::fs::write(root.join(name), content).unwrap();
+	}
+	let data_manager = DataStore::at(root.join("hashes.db").to_str().unwrap());
+	data_manager.create_tables().unwrap();
+	let settings = Settings { working_dir: root.to_str().unwrap().to_string(), action: String::from("T"),
+	    ignore_paths: vec![String::from("hashes.db*")], ..Default::default() };
+
+	// the checkpoint in b is written while b/1.jpg is hashed, the scan stops in c
+	let files = RecordingFiles { opened: Default::default(), slow: Some(root.join("b/1.jpg")), broken: Some(root.join("c/2.jpg")) };
+	let interrupted = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+	    process_path(&settings, false, &files, &data_manager, &mut ReportEvents::new());
+	}));
+	assert!(interrupted.is_err());
+	assert_eq!(files.opened.take(), vec!["0.jpg", "00.jpg", "1.jpg"]);
+	let run = data_manager.get_resumable_scan_run(&settings.working_dir).unwrap().unwrap();
+	assert_eq!(run.last_dir.as_deref(), root.join("b").to_str());
+
+	// a is done, b is the checkpoint and is walked again
+	let files = RecordingFiles { opened: Default::default(), slow: None, broken: None };
+	let mut events = ReportEvents::new();
+	process_path(&settings, true, &files, &data_manager, &mut events);
+	assert_eq!(files.opened.take(), vec!["1.jpg", "2.jpg", "3.jpg"]);
+	assert!(data_manager.get_resumable_scan_run(&settings.working_dir).unwrap().is_none());
+	assert_eq!(data_manager.get_all_entries().unwrap().len(), 5);
+	// the group seen in both parts is one group
+	let Some(ReportEvent::ScanFinished(summary)) = events.take().events.pop() else {
+	    panic!("no scan summary");
+	};
+	assert_eq!((summary.duplicate_groups, summary.redundant_copies, summary.bytes_reclaimable), (1, 2, 8));
+   }
+
+   #[test]
+   fn test_scan_without_findings_sends_nothing() {
+	use hooks::{CommandHook, HookTrigger};
+	let root = TempDir::new("quiet");
+	std::fs::create_dir_all(root.join("photos")).unwrap();
+	std::fs::write(root.join("photos/a.jpg"), "one").unwrap();
+	std::fs::write(root.join("photos/b.jpg"), "two").unwrap();
+	let data_manager = DataStore::at(root.join("hashes.db").to_str().unwrap());
+	data_manager.create_tables().unwrap();
+	let hooked = root.join("hooked");
+	let settings = Settings {
+	    working_dir: root.join("photos").to_str().unwrap().to_string(),
+	    action: String::from("T"),
+	    command_hooks: vec![CommandHook { command: format!("touch {}", hooked.display()), on: HookTrigger::Summary }],
+	    // no server listens there: an attempt to send fails
+	    email_retries: Some(0),
+	    ..sink_settings(1, &["a@example.com"])
+	};
+	let mut events = ReportEvents::new();
+	process_path(&settings, false, &FileManager::new(), &data_manager, &mut events);
+	assert!(events.is_empty());
+	let body = events.take();
+	assert!(matches!(body.events.as_slice(), [ReportEvent::ScanFinished(_)]));
+	assert!(body.is_empty());
+	assert_eq!(mailer::send_report(&settings, &body, vec![]), Ok(()));
+	hooks::notify(&settings, &body);
+	assert!(!hooked.exists());
+
+	// a copy is worth a report
+	std::fs::write(root.join("photos/c.jpg"), "one").unwrap();
+	process_path(&settings, false, &FileManager::new(), &data_manager, &mut events);
+	assert!(!events.is_empty());
+	let body = events.take();
+	assert!(mailer::send_report(&settings, &body, vec![]).is_err());
+	hooks::notify(&settings, &body);
+	assert!(hooked.exists());
+   }
+   #[test]
+   fn test_watch_limit_errors_make_directories_polled() {
+	let root = TempDir::new("watch-limit");
+	std::fs::create_dir_all(root.join("a")).unwrap();
+	let settings = Settings { watch_debounce_secs: Some(1), watch_poll_interval_secs: Some(1), ..Default::default() };
+	let (mut watcher, rx) = DirWatcher::new(&settings).unwrap();
+	watcher.watch_tree(&root, &file_manager::WalkOptions::default(), &FileManager::new(), |_| false);
+	assert!(watcher.is_watched(&root.join("a")) && !watcher.is_polled(&root.join("a")));
+
+	// as the OS reports it for a watch it couldn't add
+	watcher.handle_errors(vec![notify::Error::new(notify::ErrorKind::MaxFilesWatch).add_path(root.join("a"))]);
+	assert!(watcher.is_watched(&root.join("a")) && watcher.is_polled(&root.join("a")));
+	assert!(!watcher.is_polled(&root));
+	// directories created after that are polled right away
+	std::fs::create_dir(root.join("b")).unwrap();
+	watcher.watch_tree(&root.join("b"), &file_manager::WalkOptions::default(), &FileManager::new(), |_| false);
+	assert!(watcher.is_polled(&root.join("b")));
+
+	// and their changes come through
+	std::fs::write(root.join("b/new.jpg"), "new").unwrap();
+	let deadline = Instant::now() + Duration::from_secs(10);
+	let mut changed = vec![];
+	while !changed.contains(&root.join("b/new.jpg")) {
+	    let left = deadline.saturating_duration_since(Instant::now());
+	    match rx.recv_timeout(left) {
+	        Ok(Ok(events)) => changed.extend(watcher::changed_paths(events)),
+	        Ok(Err(errors)) => panic!("{:?}", errors),
+	        Err(e) => panic!("no change of b/new.jpg, only {:?}: {}", changed, e),
+	    }
+	}
+	drop(watcher);
+   }
+   #[test]
+   fn test_removed_dupignore_is_a_change() {
+	use notify::event::{AccessKind, CreateKind, EventKind, RemoveKind};
+	use notify_debouncer_full::DebouncedEvent;
+	let event = |kind: EventKind, path: &str| DebouncedEvent::new(notify::Event::new(kind).add_path(PathBuf::from(path)), Instant::now());
+	let changes = watcher::changed_paths(vec![
+	    event(EventKind::Create(CreateKind::File), "/photos/a.jpg"),
+	    event(EventKind::Access(AccessKind::Any), "/photos/b.jpg"),
+	    event(EventKind::Remove(RemoveKind::File), "/photos/c.jpg"),
+	    event(EventKind::Remove(RemoveKind::File), "/photos/2020/.dupignore"),
+	    event(EventKind::Create(CreateKind::File), "/photos/a.jpg"),
+	]);
+	assert_eq!(changes, [PathBuf::from("/photos/a.jpg"), PathBuf::from("/photos/2020/.dupignore")]);
+   }
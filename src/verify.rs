@@ -0,0 +1,189 @@
+use sha2::{Digest, Sha512};
+
+use std::collections::HashSet;
+use std::fmt;
+use std::fs::{self, File, Metadata};
+use std::io::{self, Read};
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+use crate::archive;
+use crate::daemon;
+use crate::datastore::{DataManager, FileInfo};
+use crate::file_manager::HandleFiles;
+use crate::report_events::{ReportEvent, ReportEvents};
+use crate::settings::Settings;
+
+/// What a verification pass found.
+#[derive(Debug, Default, PartialEq)]
+pub struct VerifyStats {
+    pub checked: u64,
+    /// hashed again, the content is what was hashed before
+    pub intact: u64,
+    /// size or mtime changed since the file was hashed, the next scan hashes it again
+    pub changed: u64,
+    pub missing: u64,
+    /// size and mtime are the same, the content is not
+    pub corrupted: u64,
+    /// corrupted files whose content was copied back from an intact copy (verify_restore)
+    pub restored: u64,
+    pub bytes_read: u64,
+}
+
+impl fmt::Display for VerifyStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "checked {} files, read {} bytes: {} intact, {} corrupted ({} restored), {} changed, {} missing",
+            self.checked, self.bytes_read, self.intact, self.corrupted, self.restored, self.changed, self.missing)
+    }
+}
+
+/// Keeps reading at no more than `bytes_per_sec` on average, counted from its creation or the
+/// last `restart`.
+pub struct Throttle {
+    bytes_per_sec: Option<u64>,
+    started: Instant,
+    bytes: u64,
+}
+
+impl Throttle {
+    /// None (or 0) doesn't limit anything.
+    pub fn new(bytes_per_sec: Option<u64>) -> Self {
+        Throttle { bytes_per_sec: bytes_per_sec.filter(|r| *r > 0), started: Instant::now(), bytes: 0 }
+    }
+
+    /// Counts from now on. Time spent not reading (between passes) is no credit for a burst later.
+    pub fn restart(&mut self) {
+        self.started = Instant::now();
+        self.bytes = 0;
+    }
+
+    /// Counts `bytes` as read and sleeps until reading them was allowed. Pings the systemd
+    /// watchdog, also while it sleeps, and returns early when a shutdown is requested.
+    pub fn consume(&mut self, bytes: u64) {
+        daemon::keepalive();
+        self.bytes += bytes;
+        let Some(rate) = self.bytes_per_sec else {
+            return;
+        };
+        let due = Duration::from_secs_f64(self.bytes as f64 / rate as f64);
+        while self.started.elapsed() < due && !daemon::shutdown_requested() {
+            thread::sleep(due.saturating_sub(self.started.elapsed()).min(daemon::tick()));
+            daemon::keepalive();
+        }
+    }
+}
+
+struct Throttled<'a, R> {
+    inner: R,
+    throttle: &'a mut Throttle,
+}
+
+impl<R: Read> Read for Throttled<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.throttle.consume(n as u64);
+        Ok(n)
+    }
+}
+
+/// sha512 (hex) of the file at `path` like scans calculate it, read as fast as `throttle` allows.
+pub fn hash_file(path: &Path, throttle: &mut Throttle) -> io::Result<String> {
+    let mut reader = Throttled { inner: File::open(path)?, throttle };
+    let mut hasher = Sha512::new();
+    io::copy(&mut reader, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+fn modified(meta: &Metadata) -> u64 {
+    meta.modified().ok()
+        .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Whether the file of `entry` has the size and mtime it had when it was hashed.
+fn unchanged(entry: &FileInfo) -> Option<bool> {
+    let meta = fs::metadata(&entry.full_path).ok()?;
+    Some(meta.len() == entry.size && modified(&meta) == entry.last_modified)
+}
+
+/// Another file on disk with the content `entry` had, checked by hashing it again.
+fn intact_copy(entry: &FileInfo, throttle: &mut Throttle, data_manager: &impl DataManager) -> Option<String> {
+    let copies = data_manager.get_entries_by_hash(&entry.hash).unwrap_or_default();
+    copies.into_iter()
+        .filter(|c| c.full_path != entry.full_path && !archive::is_member(&c.full_path) && unchanged(c) == Some(true))
+        .find(|c| hash_file(Path::new(&c.full_path), throttle).is_ok_and(|h| h == c.hash))
+        .map(|c| c.full_path)
+}
+
+/// Hashes every file in the database again whose size and mtime did not change since it was
+/// hashed, and reports the ones whose content changed anyway (bit rot, a failing disk) with an
+/// intact copy if there is one. Nothing is deleted: mtimes are whole seconds, an edit that kept
+/// the size looks the same. Only with verify_restore the intact copy is copied over the corrupted
+/// file. The rows stay as they are. Files inside archives are left out. Stops early on shutdown.
+/// Corruptions in `reported` (path and stored hash) were reported by an earlier pass, they are
+/// counted but not reported again.
+pub fn verify(settings: &Settings, throttle: &mut Throttle, reported: &mut HashSet<(String, String)>, file_manager: &impl HandleFiles,
+    data_manager: &impl DataManager, events: &mut ReportEvents) -> VerifyStats {
+    throttle.restart();
+    let mut stats = VerifyStats::default();
+    let entries = data_manager.get_all_entries().expect("Unable to read hash database");
+    for entry in entries.iter().filter(|e| !archive::is_member(&e.full_path)) {
+        if daemon::shutdown_requested() {
+            break;
+        }
+        daemon::keepalive();
+        stats.checked += 1;
+        match unchanged(entry) {
+            None => {
+                stats.missing += 1;
+                continue;
+            }
+            Some(false) => {
+                stats.changed += 1;
+                continue;
+            }
+            Some(true) => (),
+        }
+        let hash = match hash_file(Path::new(&entry.full_path), throttle) {
+            Ok(hash) => hash,
+            Err(e) => {
+                log::warn!(path = entry.full_path.as_str(); "Unable to read {}: {}", entry.full_path, e);
+                stats.missing += 1;
+                continue;
+            }
+        };
+        stats.bytes_read += entry.size;
+        let corruption = (entry.full_path.clone(), entry.hash.clone());
+        if hash == entry.hash {
+            reported.remove(&corruption);
+            stats.intact += 1;
+            continue;
+        }
+        // written to while it was read
+        if unchanged(entry) != Some(true) {
+            stats.changed += 1;
+            continue;
+        }
+        stats.corrupted += 1;
+        let intact = intact_copy(entry, throttle, data_manager);
+        log::error!(path = entry.full_path.as_str(); "Content of {} changed, but its size and mtime did not. Intact copy: {}",
+            entry.full_path, intact.as_deref().unwrap_or("none"));
+        let mut restored = false;
+        if let Some(intact) = intact.as_deref().filter(|_| settings.verify_restore) {
+            match file_manager.copy_file(intact, &entry.full_path) {
+                Ok(()) => {
+                    log::info!(path = entry.full_path.as_str(), action = "restore"; "RESTORED {} from {}", entry.full_path, intact);
+                    stats.restored += 1;
+                    restored = true;
+                }
+                Err(e) => log::error!(path = entry.full_path.as_str(); "Unable to restore from {}: {}", intact, e),
+            }
+        }
+        if reported.insert(corruption) {
+            events.push(ReportEvent::Corrupted { path: entry.full_path.clone(), intact, restored });
+        }
+    }
+    stats
+}
@@ -0,0 +1,157 @@
+use image::GrayImage;
+
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::Mutex;
+
+use crate::datastore::{DataManager, FileInfo, VideoHash};
+use crate::image_hash::{group_pairs, hash_pixels, retain_existing, sort_groups, ImageHashKind, SimilarFile, SimilarGroup};
+use crate::settings::Settings;
+
+/// Files with these extensions get frame hashes when similar_videos is on.
+pub const EXTENSIONS: [&str; 12] = ["mp4", "m4v", "mov", "mkv", "webm", "avi", "wmv", "flv", "3gp", "mpg", "mpeg", "mts"];
+/// where frames are taken, relative to the length; start and end are left out, intros and
+/// credits are often cut when a video is shared
+pub const FRAME_POSITIONS: [f64; 9] = [0.1, 0.2, 0.3, 0.4, 0.5, 0.6, 0.7, 0.8, 0.9];
+pub const DEFAULT_MAX_DISTANCE: u32 = 8;
+const DEFAULT_FFMPEG: &str = "ffmpeg";
+/// videos whose lengths differ by more than this (or 2% of the length) are not compared
+const DURATION_TOLERANCE_MS: u64 = 2000;
+
+pub fn is_video(path: &Path) -> bool {
+    path.extension().is_some_and(|e| EXTENSIONS.contains(&e.to_string_lossy().to_lowercase().as_str()))
+}
+
+fn ffprobe(ffmpeg: &str) -> PathBuf {
+    Path::new(ffmpeg).with_file_name("ffprobe")
+}
+
+/// Whether `ffmpeg` and the ffprobe next to it can be run. Checked once per binary, a warning
+/// is logged if they can't.
+pub fn ffmpeg_available(ffmpeg: &str) -> bool {
+    static CHECKED: Mutex<Vec<(String, bool)>> = Mutex::new(vec![]);
+    let mut checked = CHECKED.lock().unwrap();
+    if let Some((_, available)) = checked.iter().find(|(binary, _)| binary == ffmpeg) {
+        return *available;
+    }
+    let available = [PathBuf::from(ffmpeg), ffprobe(ffmpeg)].iter().all(|binary| {
+        Command::new(binary).arg("-version").stdin(Stdio::null()).stdout(Stdio::null()).stderr(Stdio::null())
+            .status().is_ok_and(|s| s.success())
+    });
+    if !available {
+        log::warn!("{} or ffprobe not found, videos are not compared", ffmpeg);
+    }
+    checked.push((ffmpeg.to_string(), available));
+    available
+}
+
+/// Runs `binary` and returns what it wrote to stdout, or its error output if it failed.
+fn run(binary: &Path, args: &[&str]) -> Result<Vec<u8>, String> {
+    let output = Command::new(binary).args(args).stdin(Stdio::null()).output()
+        .map_err(|e| format!("{}: {}", binary.display(), e))?;
+    if !output.status.success() {
+        return Err(String::from_utf8_lossy(&output.stderr).trim().to_string());
+    }
+    Ok(output.stdout)
+}
+
+/// Length of the video at `path` in milliseconds and the dHash of a frame at each of
+/// FRAME_POSITIONS.
+pub fn hash_video(path: &Path, ffmpeg: &str) -> Result<(u64, Vec<u64>), String> {
+    // "file:" so that names with a colon or starting with a dash are not taken for something else
+    let input = format!("file:{}", path.to_string_lossy());
+    let duration = run(&ffprobe(ffmpeg), &["-v", "error", "-show_entries", "format=duration", "-of", "default=noprint_wrappers=1:nokey=1", &input])?;
+    let secs: f64 = String::from_utf8_lossy(&duration).trim().parse()
+        .map_err(|_| format!("unknown length: {}", String::from_utf8_lossy(&duration).trim()))?;
+    let frames = FRAME_POSITIONS.iter().map(|position| {
+        // seeking before the input is fast, it starts decoding at the keyframe before
+        let at = format!("{:.3}", secs * position);
+        let pixels = run(Path::new(ffmpeg), &["-v", "error", "-nostdin", "-ss", &at, "-i", &input, "-frames:v", "1",
+            "-vf", "scale=9:8:flags=area", "-pix_fmt", "gray", "-f", "rawvideo", "-"])?;
+        let frame = GrayImage::from_raw(9, 8, pixels).ok_or(format!("no frame at {} s", at))?;
+        Ok(hash_pixels(&frame, ImageHashKind::Dhash))
+    }).collect::<Result<Vec<u64>, String>>()?;
+    Ok(((secs * 1000.0) as u64, frames))
+}
+
+/// Stores the frame hashes of `info` if it is a video whose hashes are missing or older than the
+/// file. Nothing happens if ffmpeg is not installed.
+pub fn update(info: &FileInfo, settings: &Settings, data_manager: &impl DataManager) {
+    let path = Path::new(&info.full_path);
+    let ffmpeg = settings.ffmpeg.as_deref().unwrap_or(DEFAULT_FFMPEG);
+    if !is_video(path) || !ffmpeg_available(ffmpeg) {
+        return;
+    }
+    match data_manager.get_video_hash(&info.full_path) {
+        Ok(Some(existing)) if existing.last_modified >= info.last_modified => return,
+        Ok(_) => (),
+        Err(e) => {
+            log::error!("Unable to read video hashes of {}: {:?}", info.full_path, e);
+            return;
+        }
+    }
+    match hash_video(path, ffmpeg) {
+        Ok((duration_ms, frames)) => {
+            let entry = VideoHash { path: info.full_path.clone(), duration_ms, frames, last_modified: info.last_modified };
+            data_manager.add_video_hash(&entry).unwrap_or_else(|e| log::error!("Unable to add video hashes of {}: {:?}", info.full_path, e));
+        }
+        Err(e) => log::warn!(path = info.full_path.as_str(); "Unable to read video: {}", e),
+    }
+}
+
+/// Bits in which the frame hashes of two videos differ on average, rounded. None if they
+/// were not sampled at the same positions.
+pub fn frame_distance(a: &[u64], b: &[u64]) -> Option<u32> {
+    if a.is_empty() || a.len() != b.len() {
+        return None;
+    }
+    let total: u32 = a.iter().zip(b).map(|(x, y)| (x ^ y).count_ones()).sum();
+    Some((total + a.len() as u32 / 2) / a.len() as u32)
+}
+
+/// Indexes of `videos` grouped so that every video of a group is about as long as and at most
+/// `max_distance` from at least one other video of the group. Videos of a single colour (all
+/// hashes 0) are left out, they would match each other. Only groups of two or more, each sorted.
+pub fn cluster(videos: &[VideoHash], max_distance: u32) -> Vec<Vec<usize>> {
+    let mut by_duration: Vec<usize> = (0..videos.len()).filter(|i| videos[*i].frames.iter().any(|f| *f != 0)).collect();
+    by_duration.sort_by_key(|i| videos[*i].duration_ms);
+    let mut pairs = vec![];
+    for (n, &i) in by_duration.iter().enumerate() {
+        let tolerance = DURATION_TOLERANCE_MS.max(videos[i].duration_ms / 50);
+        for &j in by_duration[n + 1..].iter().take_while(|j| videos[**j].duration_ms - videos[i].duration_ms <= tolerance) {
+            if frame_distance(&videos[i].frames, &videos[j].frames).is_some_and(|d| d <= max_distance) {
+                pairs.push((i, j));
+            }
+        }
+    }
+    group_pairs(videos.len(), pairs)
+}
+
+/// Groups of similar videos among the hashes in the database, biggest groups first. Groups of
+/// bit-identical files are left out, those are plain duplicates. Rows of files that are gone
+/// are removed on the way.
+pub fn similar_groups(settings: &Settings, data_manager: &impl DataManager) -> Vec<SimilarGroup> {
+    let max_distance = settings.video_max_distance.unwrap_or(DEFAULT_MAX_DISTANCE);
+    let mut videos = data_manager.get_video_hashes().expect("Unable to read video hashes");
+    retain_existing(&mut videos, |v| &v.path, data_manager);
+    videos.sort_by(|a, b| a.path.cmp(&b.path));
+    let mut groups: Vec<SimilarGroup> = cluster(&videos, max_distance).into_iter().filter_map(|members| {
+        let contents: HashSet<String> = members.iter()
+            .filter_map(|i| data_manager.get_entry_for_path(&videos[*i].path).ok().flatten())
+            .map(|e| e.hash)
+            .collect();
+        if contents.len() <= 1 {
+            return None;
+        }
+        let first = &videos[members[0]].frames;
+        Some(SimilarGroup {
+            files: members.iter().map(|i| SimilarFile {
+                path: videos[*i].path.clone(),
+                distance: frame_distance(first, &videos[*i].frames).unwrap_or_default(),
+            }).collect(),
+        })
+    }).collect();
+    sort_groups(&mut groups);
+    groups
+}
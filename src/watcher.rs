@@ -0,0 +1,172 @@
+use notify::{Config, ErrorKind, EventKind, PollWatcher, RecommendedWatcher, RecursiveMode};
+use notify_debouncer_full::{new_debouncer_opt, DebounceEventResult, DebouncedEvent, Debouncer, NoCache};
+
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::time::Duration;
+
+use crate::file_manager::{HandleFiles, WalkOptions};
+use crate::ignore_rules::DUPIGNORE;
+use crate::settings::Settings;
+
+/// Watches a directory tree for changes.
+///
+/// Every directory gets its own non-recursive OS watch, so that running out of
+/// inotify watches (`fs.inotify.max_user_watches`) can be detected per directory.
+/// Directories that could not get an OS watch are polled periodically instead.
+pub struct DirWatcher {
+    watcher: Debouncer<RecommendedWatcher, NoCache>,
+    poller: Option<Debouncer<PollWatcher, NoCache>>,
+    tx: Sender<DebounceEventResult>,
+    debounce: Duration,
+    poll_interval: Duration,
+    watched: HashSet<PathBuf>,
+    polled: HashSet<PathBuf>,
+    limit_reached: bool,
+}
+
+impl DirWatcher {
+    pub fn new(settings: &Settings) -> notify::Result<(DirWatcher, Receiver<DebounceEventResult>)> {
+        let (tx, rx) = channel();
+        let debounce = Duration::from_secs(settings.watch_debounce_secs.unwrap_or(2));
+        let poll_interval = Duration::from_secs(settings.watch_poll_interval_secs.unwrap_or(60));
+        let watcher = new_debouncer_opt::<_, RecommendedWatcher, _>(debounce, None, tx.clone(), NoCache, Config::default())?;
+        Ok((DirWatcher {
+            watcher,
+            poller: None,
+            tx,
+            debounce,
+            poll_interval,
+            watched: HashSet::new(),
+            polled: HashSet::new(),
+            limit_reached: false,
+        }, rx))
+    }
+
+    /// Adds watches for `root` and every directory below it that is not ignored.
+    pub fn watch_tree(&mut self, root: &Path, options: &WalkOptions, file_manager: &impl HandleFiles, ignore: impl Fn(&Path) -> bool) {
+        let root = match root.to_str() {
+            Some(r) => r,
+            None => return,
+        };
+        let mut walker = file_manager.walkdir(root, options);
+        while let Some(entry) = walker.next() {
+            let entry = match entry {
+                Ok(e) => e,
+                Err(_) => continue,
+            };
+            if !entry.file_type().is_dir() {
+                continue;
+            }
+            if ignore(entry.path()) {
+                walker.skip_current_dir();
+            } else {
+                self.watch_dir(entry.path());
+            }
+        }
+    }
+
+    /// Returns true if `path` is a directory this watcher already knows about.
+    pub fn is_watched(&self, path: &Path) -> bool {
+        self.watched.contains(path) || self.polled.contains(path)
+    }
+
+    #[cfg(test)]
+    pub fn is_polled(&self, path: &Path) -> bool {
+        self.polled.contains(path)
+    }
+
+    /// Handles asynchronous watcher errors. Watch limit errors make the affected paths polled,
+    /// the rest is printed.
+    pub fn handle_errors(&mut self, errors: Vec<notify::Error>) {
+        for error in errors {
+            if let ErrorKind::MaxFilesWatch = error.kind {
+                self.warn_limit_reached();
+                for path in &error.paths {
+                    self.watched.remove(path);
+                    self.poll_dir(path);
+                }
+            } else {
+                log::error!("watch error: {:?}", error);
+            }
+        }
+    }
+
+    fn watch_dir(&mut self, dir: &Path) {
+        if self.is_watched(dir) {
+            return;
+        }
+        if self.limit_reached {
+            self.poll_dir(dir);
+            return;
+        }
+        match self.watcher.watch(dir, RecursiveMode::NonRecursive) {
+            Ok(()) => {
+                self.watched.insert(dir.to_path_buf());
+            }
+            Err(e) => {
+                if let ErrorKind::MaxFilesWatch = e.kind {
+                    self.warn_limit_reached();
+                    self.poll_dir(dir);
+                } else {
+                    log::error!("Unable to watch {}: {:?}", dir.display(), e);
+                }
+            }
+        }
+    }
+
+    fn poll_dir(&mut self, dir: &Path) {
+        if self.polled.contains(dir) {
+            return;
+        }
+        if self.poller.is_none() {
+            let config = Config::default().with_poll_interval(self.poll_interval);
+            match new_debouncer_opt::<_, PollWatcher, _>(self.debounce, None, self.tx.clone(), NoCache, config) {
+                Ok(p) => self.poller = Some(p),
+                Err(e) => {
+                    log::error!("Unable to start polling watcher, {} will not be watched: {:?}", dir.display(), e);
+                    return;
+                }
+            }
+        }
+        let poller = self.poller.as_mut().unwrap();
+        match poller.watch(dir, RecursiveMode::NonRecursive) {
+            Ok(()) => {
+                self.polled.insert(dir.to_path_buf());
+            }
+            Err(e) => log::error!("Unable to poll {}: {:?}", dir.display(), e),
+        }
+    }
+
+    fn warn_limit_reached(&mut self) {
+        if !self.limit_reached {
+            self.limit_reached = true;
+            log::warn!("OS file watch limit reached (see fs.inotify.max_user_watches). \
+                      Remaining directories will be polled every {} s.", self.poll_interval.as_secs());
+        }
+    }
+}
+
+/// Paths that were created or modified, in the order of the events.
+/// Access events (including our own reads while hashing) and removals are skipped, but for
+/// removed .dupignore files: their rules have to be forgotten.
+pub fn changed_paths(events: Vec<DebouncedEvent>) -> Vec<PathBuf> {
+    let mut paths: Vec<PathBuf> = vec![];
+    for event in events {
+        let removed = match event.kind {
+            EventKind::Access(_) => continue,
+            EventKind::Remove(_) => true,
+            _ => false,
+        };
+        for path in &event.paths {
+            if removed && path.file_name().is_none_or(|n| n != DUPIGNORE) {
+                continue;
+            }
+            if !paths.contains(path) {
+                paths.push(path.clone());
+            }
+        }
+    }
+    paths
+}